@@ -1,16 +1,27 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
+use std::fmt;
 use std::fs;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{mpsc, Arc, Mutex};
+use std::process::{self, ExitCode};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, LazyLock, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{ProgressBar, ProgressStyle};
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use notify::Watcher;
 use rayon::prelude::*;
+use regex::Regex;
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
 
 // ============================================================================
 // Constants
@@ -41,588 +52,7666 @@ const GENERATED_EXTENSIONS: &[&str] = &[".map", ".d", ".timestamp", ".min.js", "
 
 const ALWAYS_IGNORE_FILES: &[&str] = &[
     ".DS_Store", "package-lock.json", "yarn.lock", "pnpm-lock.yaml",
-    "Cargo.lock", ".eslintrc", ".prettierrc", ".npmignore", ".gitignore",
+    "Cargo.lock", ".eslintrc", ".prettierrc", ".npmignore", ".gitignore", ".vdbignore", ".vdb-index.toml",
 ];
 
 const ALLOWED_NO_EXTENSION: &[&str] = &["Makefile", "Dockerfile", "Gemfile", "Rakefile", "Podfile", "Containerfile"];
 
+/// File name fragments and directory names that mark test files, which are
+/// skipped unless --include-tests is passed.
+const TEST_FILE_PATTERNS: &[&str] = &[".test.", ".spec."];
+const TEST_DIRS: &[&str] = &["__tests__"];
+
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// A force-indexed file chunking into more than this many pieces gets a warning.
+const FORCED_CHUNK_WARNING: usize = 1000;
+
+const COLLECTION_LIST_RETRIES: u32 = 5;
+
+/// Chunks with fewer non-whitespace characters than this carry no meaning and
+/// tend to produce degenerate embeddings.
+const MIN_EMBEDDABLE_CHARS: usize = 3;
+const DEFAULT_CHUNK_SIZE: usize = 3000;
+const DEFAULT_CHUNK_OVERLAP: usize = 500;
+const DEFAULT_BATCH_SIZE: usize = 128;
+
+/// Files shorter than this don't get a summary chunk; their one or two regular
+/// chunks already describe them.
+const SUMMARY_MIN_LINES: usize = 30;
+
+/// Caps on what goes into a summary chunk.
+const SUMMARY_MAX_DOC_LINES: usize = 10;
+const SUMMARY_MAX_SYMBOLS: usize = 50;
+
+/// Authors kept in a chunk's `author_lines` metadata.
+const BLAME_MAX_AUTHORS: usize = 5;
+/// What `git blame` reports for lines changed in the working tree.
+const UNCOMMITTED_AUTHOR: &str = "Not Committed Yet";
+
+/// Collection metadata key recording the prefix documents were embedded with.
+const EMBED_PREFIX_KEY: &str = "embed_prefix";
+/// Collection metadata key recording the model that produced the vectors.
+const EMBEDDING_MODEL_KEY: &str = "embedding_model";
+
+const LAST_FULL_INDEX_KEY: &str = "last_full_index_ts";
+
+/// Version of the chunk id scheme and metadata layout, stored on every chunk.
+/// Bump it when either changes, so a branch indexed by an older indexer is
+/// re-indexed from scratch rather than mixing old and new chunks.
+const SCHEMA_VERSION: u64 = 2;
+
+/// Collection metadata key naming the branch/version/commit an interrupted run
+/// left half-uploaded, so the next run doesn't take it as already indexed.
+/// Holds one space-separated entry per repository a multi-root run left behind.
+const INCOMPLETE_INDEX_KEY: &str = "incomplete_index";
+
+/// How often `watch` checks for Ctrl-C while no files change.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Files whose mtime is within this many seconds of the last full index are
+/// treated as modified, since filesystem timestamps can be coarse.
+const MTIME_SLACK_SECS: u64 = 2;
+
 // ============================================================================
 // File Utilities
 // ============================================================================
 
-fn should_index_file(path: &Path) -> bool {
-    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-    let file_name_lower = file_name.to_lowercase();
+/// Why a path is left out of the index.
+#[derive(Debug)]
+pub enum SkipReason {
+    IgnoredDir(String),
+    Gitignore { pattern: String, source: Option<PathBuf> },
+    Vdbignore { pattern: String, source: Option<PathBuf> },
+    IgnoredFile(String),
+    BinaryExtension(String),
+    GeneratedPattern(&'static str),
+    TestFile(String),
+    NoExtension,
+    /// File size and the limit it exceeds
+    TooLarge(u64, u64),
+    TooDeep(usize),
+    Excluded(String),
+    NotIncluded,
+}
 
-    if ALWAYS_IGNORE_FILES.iter().any(|f| file_name_lower == f.to_lowercase()) {
-        return false;
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IgnoredDir(dir) => write!(f, "inside always-ignored directory '{}'", dir),
+            Self::Gitignore { pattern, source: Some(source) } => write!(f, "matched gitignore rule '{}' in {}", pattern, source.display()),
+            Self::Gitignore { pattern, source: None } => write!(f, "matched gitignore rule '{}'", pattern),
+            Self::Vdbignore { pattern, source: Some(source) } => write!(f, "matched .vdbignore rule '{}' in {}", pattern, source.display()),
+            Self::Vdbignore { pattern, source: None } => write!(f, "matched .vdbignore rule '{}'", pattern),
+            Self::IgnoredFile(name) => write!(f, "'{}' is an always-ignored file", name),
+            Self::BinaryExtension(ext) => write!(f, "binary extension '{}'", ext),
+            Self::GeneratedPattern(pattern) => write!(f, "generated file pattern '{}'", pattern),
+            Self::TestFile(pattern) => write!(f, "matched test file pattern '{}' (pass --include-tests to index tests)", pattern),
+            Self::NoExtension => write!(f, "no extension and not in the allowed list"),
+            Self::TooLarge(size, limit) => write!(f, "{} bytes exceeds the --max-file-size limit of {} bytes", size, limit),
+            Self::TooDeep(depth) => write!(f, "{} directories deep, beyond --max-depth", depth),
+            Self::Excluded(pattern) => write!(f, "matched --exclude '{}'", pattern),
+            Self::NotIncluded => write!(f, "matched no --include glob"),
+        }
     }
+}
 
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        let ext_with_dot = format!(".{}", ext.to_lowercase());
-        if BINARY_EXTENSIONS.contains(&ext_with_dot.as_str()) {
-            return false;
+/// Project config file looked for at the root of the indexed directory.
+const PROJECT_CONFIG_FILE: &str = ".vdb-index.toml";
+
+/// `.vdb-index.toml`: project changes to the built-in filter lists. Each list
+/// is added to its built-in one; an entry starting with `!` removes a built-in
+/// entry instead, e.g. `ignore_dirs = ["!docs"]`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    ignore_dirs: Option<Vec<String>>,
+    ignore_files: Option<Vec<String>>,
+    binary_extensions: Option<Vec<String>>,
+    #[serde(alias = "extra_no_extension_files")]
+    allowed_no_extension: Option<Vec<String>>,
+    test_file_patterns: Option<Vec<String>>,
+    test_dirs: Option<Vec<String>>,
+}
+
+impl ProjectConfig {
+    /// Reads `path`. A missing file is `None` unless `required`; a file that
+    /// isn't valid TOML or has unknown keys is an error.
+    pub fn load(path: &Path, required: bool) -> Result<Option<Self>> {
+        if !required && !path.exists() {
+            return Ok(None);
         }
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read config {}", path.display()))?;
+        let config = toml::from_str(&text).with_context(|| format!("Invalid config {}", path.display()))?;
+        Ok(Some(config))
     }
+}
+
+/// The directory, file and extension lists the file checks use.
+#[derive(Debug, Clone)]
+struct FilterLists {
+    ignore_dirs: Vec<String>,
+    /// Lowercase
+    ignore_files: Vec<String>,
+    /// Lowercase, with the leading dot
+    binary_extensions: Vec<String>,
+    allowed_no_extension: Vec<String>,
+    /// Matched against file names
+    test_file_patterns: Vec<String>,
+    test_dirs: Vec<String>,
+}
 
-    for pattern in GENERATED_EXTENSIONS {
-        if file_name_lower.ends_with(pattern) {
-            return false;
+impl Default for FilterLists {
+    fn default() -> Self {
+        let owned = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+        Self {
+            ignore_dirs: owned(ALWAYS_IGNORE_DIRS),
+            ignore_files: ALWAYS_IGNORE_FILES.iter().map(|f| f.to_lowercase()).collect(),
+            binary_extensions: owned(BINARY_EXTENSIONS),
+            allowed_no_extension: owned(ALLOWED_NO_EXTENSION),
+            test_file_patterns: owned(TEST_FILE_PATTERNS),
+            test_dirs: owned(TEST_DIRS),
         }
     }
+}
 
-    if file_name.contains(".test.") || file_name.contains(".spec.") {
-        return false;
+/// Merges config `entries` into `list` (or replaces it), normalising each entry
+/// with `normalize`. `!entry` removes an entry.
+fn merge_list(list: &mut Vec<String>, entries: Option<Vec<String>>, replace: bool, normalize: fn(&str) -> String) {
+    let Some(entries) = entries else { return };
+    if replace {
+        list.clear();
+    }
+    for entry in entries {
+        match entry.strip_prefix('!') {
+            Some(removed) => {
+                let removed = normalize(removed);
+                list.retain(|e| *e != removed);
+            }
+            None => {
+                let added = normalize(&entry);
+                if !list.contains(&added) {
+                    list.push(added);
+                }
+            }
+        }
     }
+}
 
-    if path.components().any(|c| c.as_os_str() == "__tests__") {
-        return false;
+/// User-configurable parts of the file checks, layered over the built-in lists.
+#[derive(Debug, Clone, Default)]
+pub struct FileFilter {
+    lists: FilterLists,
+    extra_no_extension: Vec<String>,
+    force_index: GlobSet,
+    max_depth: Option<usize>,
+    /// In bytes
+    max_file_size: u64,
+    /// Print each file skipped for size after the scan
+    list_skipped: bool,
+    follow_symlinks: bool,
+    include_tests: bool,
+    /// `--include` globs; empty to include everything
+    include: GlobSet,
+    exclude: GlobSet,
+    exclude_patterns: Vec<String>,
+}
+
+impl FileFilter {
+    /// `extra_no_extension` names extensionless files to index on top of `ALLOWED_NO_EXTENSION`.
+    /// Files matching a `force_index` glob (relative to the indexed directory)
+    /// skip the size limit.
+    pub fn new(extra_no_extension: Vec<String>, force_index: &[String]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in force_index {
+            builder.add(Glob::new(pattern).with_context(|| format!("Invalid --force-index glob '{}'", pattern))?);
+        }
+        Ok(Self {
+            lists: FilterLists::default(),
+            extra_no_extension,
+            force_index: builder.build()?,
+            max_file_size: MAX_FILE_SIZE,
+            ..Default::default()
+        })
     }
 
-    if path.extension().is_none() && !ALLOWED_NO_EXTENSION.contains(&file_name) {
-        return false;
+    /// Limits indexing to paths (relative to the indexed directory) matching an
+    /// `include` glob, if any are given, and drops any matching an `exclude` glob.
+    pub fn with_scope(mut self, include: &[String], exclude: &[String]) -> Result<Self> {
+        let build = |flag: &str, patterns: &[String]| -> Result<GlobSet> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern).with_context(|| format!("Invalid {} glob '{}'", flag, pattern))?);
+            }
+            Ok(builder.build()?)
+        };
+        self.include = build("--include", include)?;
+        self.exclude = build("--exclude", exclude)?;
+        self.exclude_patterns = exclude.to_vec();
+        Ok(self)
     }
 
-    true
-}
+    /// `--include`/`--exclude` check for a path relative to the indexed directory.
+    /// Exclude wins over include.
+    fn scope_skip_reason(&self, relative: &Path) -> Option<SkipReason> {
+        if let Some(&i) = self.exclude.matches(relative).first() {
+            return Some(SkipReason::Excluded(self.exclude_patterns[i].clone()));
+        }
+        if !self.include.is_empty() && !self.include.is_match(relative) {
+            return Some(SkipReason::NotIncluded);
+        }
+        None
+    }
+
+    /// Applies a project config to the built-in lists. With `replace`, each
+    /// list the config sets replaces the built-in one instead of adding to it.
+    pub fn with_config(mut self, config: ProjectConfig, replace: bool) -> Self {
+        merge_list(&mut self.lists.ignore_dirs, config.ignore_dirs, replace, |d| d.trim_matches('/').to_string());
+        merge_list(&mut self.lists.ignore_files, config.ignore_files, replace, str::to_lowercase);
+        merge_list(&mut self.lists.binary_extensions, config.binary_extensions, replace, |e| {
+            format!(".{}", e.trim_start_matches('.').to_lowercase())
+        });
+        merge_list(&mut self.lists.allowed_no_extension, config.allowed_no_extension, replace, str::to_string);
+        merge_list(&mut self.lists.test_file_patterns, config.test_file_patterns, replace, str::to_string);
+        merge_list(&mut self.lists.test_dirs, config.test_dirs, replace, |d| d.trim_matches('/').to_string());
+        self
+    }
 
-fn load_gitignore(directory: &Path) -> Option<Gitignore> {
-    let gitignore_path = directory.join(".gitignore");
-    if gitignore_path.exists() {
-        let mut builder = GitignoreBuilder::new(directory);
-        if builder.add(&gitignore_path).is_none() {
-            return builder.build().ok();
+    /// Whether a directory with this name is never descended into.
+    fn ignores_dir(&self, name: &str) -> bool {
+        self.lists.ignore_dirs.iter().any(|d| d == name)
+    }
+
+    /// Only index files at most `max_depth` directories below the root (0 = top level only).
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Skip files over `max_file_size_mb` megabytes unless force-indexed.
+    pub fn with_max_file_size(mut self, max_file_size_mb: Option<u64>) -> Self {
+        if let Some(mb) = max_file_size_mb {
+            self.max_file_size = mb.saturating_mul(1024 * 1024);
         }
+        self
+    }
+
+    /// List the paths of oversized files after a scan, not just their count.
+    pub fn with_list_skipped(mut self, list_skipped: bool) -> Self {
+        self.list_skipped = list_skipped;
+        self
+    }
+
+    /// Descend into symlinked directories and index symlinked files.
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Index test files instead of skipping them.
+    pub fn with_include_tests(mut self, include_tests: bool) -> Self {
+        self.include_tests = include_tests;
+        self
+    }
+
+    /// The test pattern `path` matches, unless tests are included.
+    fn test_file_pattern(&self, path: &Path, file_name: &str) -> Option<String> {
+        if self.include_tests {
+            return None;
+        }
+        if let Some(pattern) = self.lists.test_file_patterns.iter().find(|p| file_name.contains(p.as_str())) {
+            return Some(pattern.clone());
+        }
+        path.components()
+            .filter_map(|c| c.as_os_str().to_str())
+            .find_map(|name| self.lists.test_dirs.iter().find(|d| *d == name))
+            .map(|dir| format!("{}/", dir))
+    }
+
+    /// Whether the walker should stop at a directory `depth` levels below the root.
+    fn beyond_max_depth(&self, depth: usize) -> bool {
+        self.max_depth.is_some_and(|max| depth > max)
+    }
+
+    /// Whether `relative` was singled out with `--force-index`.
+    pub fn is_forced(&self, relative: &Path) -> bool {
+        self.force_index.is_match(relative)
+    }
+
+    /// The size of a file over the limit, unless it's force-indexed.
+    fn too_large(&self, directory: &Path, path: &Path) -> Option<u64> {
+        let size = path.metadata().ok()?.len();
+        let forced = path.strip_prefix(directory).is_ok_and(|relative| self.is_forced(relative));
+        (size > self.max_file_size && !forced).then_some(size)
+    }
+
+    fn allows_no_extension(&self, file_name: &str) -> bool {
+        self.lists.allowed_no_extension.iter().any(|f| f == file_name) || self.extra_no_extension.iter().any(|f| f == file_name)
+    }
+
+    fn should_index_file(&self, path: &Path) -> bool {
+        self.file_skip_reason(path).is_none()
+    }
+
+    fn file_skip_reason(&self, path: &Path) -> Option<SkipReason> {
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let file_name_lower = file_name.to_lowercase();
+
+        if self.lists.ignore_files.contains(&file_name_lower) {
+            return Some(SkipReason::IgnoredFile(file_name.to_string()));
+        }
+
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            let ext_with_dot = format!(".{}", ext.to_lowercase());
+            if self.lists.binary_extensions.contains(&ext_with_dot) {
+                return Some(SkipReason::BinaryExtension(ext_with_dot));
+            }
+        }
+
+        for pattern in GENERATED_EXTENSIONS {
+            if file_name_lower.ends_with(pattern) {
+                return Some(SkipReason::GeneratedPattern(pattern));
+            }
+        }
+
+        if let Some(pattern) = self.test_file_pattern(path, file_name) {
+            return Some(SkipReason::TestFile(pattern));
+        }
+
+        if path.extension().is_none() && !self.allows_no_extension(file_name) {
+            return Some(SkipReason::NoExtension);
+        }
+
+        None
+    }
+
+    /// Walks the same checks as `scan_directory` for a single path and returns the
+    /// first one that excludes it, or `None` if it would be indexed.
+    pub fn explain_path(&self, directory: &Path, path: &Path) -> Result<Option<SkipReason>> {
+        if !path.starts_with(directory) {
+            anyhow::bail!("{} is not inside {}", path.display(), directory.display());
+        }
+        if !path.is_file() {
+            anyhow::bail!("{} is not a file", path.display());
+        }
+        Ok(self.path_skip_reason(directory, &GitignoreTree::new(directory), path))
+    }
+
+    /// Path-based checks for a file under `directory`; the size check only applies
+    /// when the file still exists.
+    fn path_skip_reason(&self, directory: &Path, gitignore: &GitignoreTree, path: &Path) -> Option<SkipReason> {
+        let relative = path.strip_prefix(directory).ok()?;
+        let depth = relative.components().count().saturating_sub(1);
+        if self.beyond_max_depth(depth) {
+            return Some(SkipReason::TooDeep(depth));
+        }
+        let mut current = directory.to_path_buf();
+        if let Some(parent) = relative.parent() {
+            for component in parent.components() {
+                current.push(component);
+                let name = component.as_os_str().to_str().unwrap_or("");
+                if self.ignores_dir(name) {
+                    return Some(SkipReason::IgnoredDir(name.to_string()));
+                }
+                if let Some(reason) = gitignore.skip_reason(&current, true) {
+                    return Some(reason);
+                }
+            }
+        }
+
+        if let Some(reason) = gitignore.skip_reason(path, false) {
+            return Some(reason);
+        }
+        if let Some(reason) = self.scope_skip_reason(relative) {
+            return Some(reason);
+        }
+        if let Some(reason) = self.file_skip_reason(path) {
+            return Some(reason);
+        }
+
+        if let Some(size) = self.too_large(directory, path) {
+            return Some(SkipReason::TooLarge(size, self.max_file_size));
+        }
+
+        None
     }
-    None
 }
 
-// ============================================================================
-// Embedding Client
-// ============================================================================
+/// Header markers of generated files, matched case-insensitively.
+const GENERATED_MARKERS: &[&str] = &["@generated", "autogenerated", "auto-generated", "do not edit", "code generated by"];
 
-pub struct EmbeddingClient {
-    client: Client,
-    base_url: String,
+/// How many leading lines are searched for a generated-file marker.
+const GENERATED_MARKER_LINES: usize = 10;
+
+/// The first of `markers` (lowercase) found in the first lines of `content`.
+fn generated_marker<'a>(content: &str, markers: &'a [String]) -> Option<&'a str> {
+    if markers.is_empty() {
+        return None;
+    }
+    let head = content.lines().take(GENERATED_MARKER_LINES).collect::<Vec<_>>().join("\n").to_lowercase();
+    markers.iter().find(|marker| head.contains(marker.as_str())).map(String::as_str)
 }
 
-#[derive(Serialize)]
-struct EmbedRequest {
-    inputs: Vec<String>,
+/// How many leading bytes `looks_binary` inspects.
+const BINARY_SNIFF_BYTES: usize = 8192;
+
+/// Share of control bytes in the sniffed prefix above which a file is binary.
+const BINARY_CONTROL_RATIO: f64 = 0.3;
+
+/// Whether the start of a file looks like binary data: it has a NUL byte, or
+/// too many control characters other than whitespace and escape. Bytes above
+/// ASCII are left alone so UTF-8 and legacy encodings still count as text.
+fn looks_binary(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(BINARY_SNIFF_BYTES)];
+    if head.is_empty() {
+        return false;
+    }
+    if head.contains(&0) {
+        return true;
+    }
+    let control = head
+        .iter()
+        .filter(|&&b| (b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b)) || b == 0x7f)
+        .count();
+    control as f64 / head.len() as f64 > BINARY_CONTROL_RATIO
 }
 
-impl EmbeddingClient {
-    pub fn new(url: &str) -> Result<Self> {
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(300))
-            .build()?;
+/// What `read_text_file` found in a file.
+enum FileContent {
+    /// Decoded text, with the detected encoding when it wasn't UTF-8
+    Text(String, Option<&'static str>),
+    /// The content looks like binary data
+    Binary,
+    /// Not valid UTF-8, and no other encoding decoded it cleanly
+    Undecodable,
+}
+
+/// Reads a file as text. Content that `looks_binary` is rejected first.
+/// Non-UTF-8 files are skipped unless `detect_encoding` is set, in which case
+/// the encoding is guessed and its name returned with the decoded text. Only
+/// I/O failures are errors.
+fn read_text_file(path: &Path, detect_encoding: bool) -> std::io::Result<FileContent> {
+    let bytes = fs::read(path)?;
+    if looks_binary(&bytes) {
+        return Ok(FileContent::Binary);
+    }
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) => return Ok(FileContent::Text(text, None)),
+        Err(err) if detect_encoding => err.into_bytes(),
+        Err(_) => return Ok(FileContent::Undecodable),
+    };
+
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(&bytes, true);
+    let encoding = detector.guess(None, true);
+    let (text, _, had_errors) = encoding.decode(&bytes);
+    if had_errors {
+        return Ok(FileContent::Undecodable);
+    }
 
-        let health_url = format!("{}/health", url);
-        for _ in 0..30 {
-            if let Ok(resp) = client.get(&health_url).send() {
-                if resp.status().is_success() {
-                    return Ok(Self { client, base_url: url.to_string() });
+    Ok(FileContent::Text(text.into_owned(), Some(encoding.name())))
+}
+
+/// Indexing-only ignore rules, in gitignore syntax, that take precedence over
+/// `.gitignore`.
+const VDBIGNORE_FILE: &str = ".vdbignore";
+
+/// The `.vdbignore` and `.gitignore` files at and below an indexed directory,
+/// loaded as paths are checked. A deeper file overrides a shallower one, as in
+/// git, and any `.vdbignore` rule overrides every `.gitignore`.
+pub struct GitignoreTree {
+    root: PathBuf,
+    /// Parsed rules by ignore file path
+    loaded: Mutex<HashMap<PathBuf, Option<Arc<Gitignore>>>>,
+}
+
+impl GitignoreTree {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf(), loaded: Mutex::new(HashMap::new()) }
+    }
+
+    fn load(&self, dir: &Path, file_name: &'static str) -> Option<Arc<Gitignore>> {
+        let ignore_path = dir.join(file_name);
+        let mut loaded = self.loaded.lock().unwrap();
+        loaded
+            .entry(ignore_path.clone())
+            .or_insert_with(|| {
+                if !ignore_path.is_file() {
+                    return None;
+                }
+                let mut builder = GitignoreBuilder::new(dir);
+                if builder.add(&ignore_path).is_some() {
+                    return None;
+                }
+                builder.build().ok().map(Arc::new)
+            })
+            .clone()
+    }
+
+    /// The rule that ignores `path`, checking every `.vdbignore` before any
+    /// `.gitignore`, and the closest file of each first.
+    fn skip_reason(&self, path: &Path, is_dir: bool) -> Option<SkipReason> {
+        for file_name in [VDBIGNORE_FILE, ".gitignore"] {
+            for dir in path.ancestors().skip(1) {
+                if !dir.starts_with(&self.root) {
+                    break;
+                }
+                let Some(rules) = self.load(dir, file_name) else { continue };
+                match rules.matched(path, is_dir) {
+                    ignore::Match::Ignore(glob) => {
+                        let pattern = glob.original().to_string();
+                        let source = glob.from().map(Path::to_path_buf);
+                        return Some(match file_name {
+                            VDBIGNORE_FILE => SkipReason::Vdbignore { pattern, source },
+                            _ => SkipReason::Gitignore { pattern, source },
+                        });
+                    }
+                    ignore::Match::Whitelist(_) => return None,
+                    ignore::Match::None => {}
                 }
             }
-            std::thread::sleep(std::time::Duration::from_secs(2));
         }
-        anyhow::bail!("Embedding service not available at {}", url)
+        None
     }
+}
 
-    pub fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
-        if texts.is_empty() {
-            return Ok(Vec::new());
-        }
+// ============================================================================
+// Language Detection
+// ============================================================================
 
-        let request = EmbedRequest {
-            inputs: texts.iter().map(|s| s.to_string()).collect(),
-        };
+const EXTENSION_LANGUAGES: &[(&str, &str)] = &[
+    ("rs", "rust"), ("py", "python"), ("pyi", "python"), ("js", "javascript"), ("mjs", "javascript"),
+    ("cjs", "javascript"), ("jsx", "javascript"), ("ts", "typescript"), ("tsx", "typescript"),
+    ("go", "go"), ("java", "java"), ("kt", "kotlin"), ("kts", "kotlin"), ("swift", "swift"),
+    ("c", "c"), ("cc", "cpp"), ("cpp", "cpp"), ("cxx", "cpp"), ("hpp", "cpp"), ("hh", "cpp"),
+    ("cs", "csharp"), ("rb", "ruby"), ("php", "php"), ("scala", "scala"), ("dart", "dart"),
+    ("lua", "lua"), ("sh", "shell"), ("bash", "shell"), ("zsh", "shell"), ("ps1", "powershell"),
+    ("html", "html"), ("css", "css"), ("scss", "scss"), ("vue", "vue"), ("svelte", "svelte"),
+    ("json", "json"), ("yaml", "yaml"), ("yml", "yaml"), ("toml", "toml"), ("xml", "xml"),
+    ("md", "markdown"), ("mdx", "markdown"), ("graphql", "graphql"), ("proto", "protobuf"),
+    ("ex", "elixir"), ("exs", "elixir"), ("erl", "erlang"), ("hs", "haskell"), ("ml", "ocaml"),
+    ("clj", "clojure"), ("zig", "zig"), ("nim", "nim"), ("gd", "gdscript"), ("tf", "terraform"),
+];
 
-        let response = self.client
-            .post(&format!("{}/embed", self.base_url))
-            .json(&request)
-            .send()
-            .context("Failed to send embedding request")?;
+const FILENAME_LANGUAGES: &[(&str, &str)] = &[
+    ("Makefile", "make"), ("Dockerfile", "dockerfile"), ("Containerfile", "dockerfile"),
+    ("Gemfile", "ruby"), ("Rakefile", "ruby"), ("Podfile", "ruby"),
+];
 
-        if !response.status().is_success() {
-            anyhow::bail!("Embedding request failed: {}", response.status());
+const SHEBANG_LANGUAGES: &[(&str, &str)] = &[
+    ("python", "python"), ("node", "javascript"), ("deno", "typescript"), ("bash", "shell"),
+    ("zsh", "shell"), ("sh", "shell"), ("ruby", "ruby"), ("perl", "perl"), ("php", "php"),
+];
+
+const OBJC_MARKERS: &[&str] = &["@interface", "@implementation", "@protocol", "#import"];
+
+const CPP_MARKERS: &[&str] = &["namespace ", "template<", "template <", "class ", "std::", "public:", "private:"];
+
+/// Languages for extensions several languages share, told apart by content:
+/// `.h` is C unless it has Objective-C or C++ constructs, and `.m` is only
+/// recognised as Objective-C.
+fn content_language(ext: &str, content: &str) -> Option<&'static str> {
+    let has = |markers: &[&str]| markers.iter().any(|m| content.contains(m));
+    match ext {
+        "h" if has(OBJC_MARKERS) => Some("objective-c"),
+        "h" if has(CPP_MARKERS) => Some("cpp"),
+        "h" => Some("c"),
+        "m" if has(OBJC_MARKERS) => Some("objective-c"),
+        _ => None,
+    }
+}
+
+/// Identifies a file's language from its name, extension, content or shebang.
+/// Returns `None` for unknown or ambiguous extensions such as `.r`.
+fn detect_language(path: &Path, content: &str) -> Option<&'static str> {
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    if let Some((_, lang)) = FILENAME_LANGUAGES.iter().find(|(name, _)| *name == file_name) {
+        return Some(lang);
+    }
+
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        let ext = ext.to_lowercase();
+        if let Some((_, lang)) = EXTENSION_LANGUAGES.iter().find(|(e, _)| *e == ext) {
+            return Some(lang);
+        }
+        if let Some(lang) = content_language(&ext, content) {
+            return Some(lang);
         }
+    }
 
-        Ok(response.json()?)
+    let shebang = content.lines().next()?.strip_prefix("#!")?;
+    let mut words = shebang.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|w| !w.starts_with('-'))?;
     }
+    SHEBANG_LANGUAGES
+        .iter()
+        .find(|(name, _)| interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') == *name)
+        .map(|(_, lang)| *lang)
+}
+
+/// The `language` stored on a file's chunks: the detected language, or the
+/// lowercased extension when there is none.
+fn language_tag(path: &Path, content: &str) -> Option<String> {
+    detect_language(path, content)
+        .map(str::to_string)
+        .or_else(|| path.extension().and_then(|e| e.to_str()).map(str::to_lowercase))
 }
 
 // ============================================================================
-// Chunking
+// Embedding Client
 // ============================================================================
 
-#[derive(Debug, Clone, Serialize)]
-pub struct ChunkMetadata {
-    pub file_path: String,
-    pub start_line: usize,
-    pub end_line: usize,
-    pub file_type: String,
-    pub git_commit: String,
-    pub git_branch: String,
+/// Turns texts into vectors. One embedding per input, in input order.
+pub trait Embedder: Send + Sync {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>>;
+
+    /// Model the vectors come from, when the backend knows it.
+    fn model_id(&self) -> Option<&str> {
+        None
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct Chunk {
-    pub id: String,
-    pub text: String,
-    pub metadata: ChunkMetadata,
+/// Backend for `--embed-backend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EmbedBackend {
+    /// Text Embeddings Inference at TEI_URL
+    Tei,
+    /// Ollama at OLLAMA_URL, using --embed-model
+    Ollama,
 }
 
-pub struct CodeChunker {
-    git_commit: String,
-    git_branch: String,
+impl EmbedBackend {
+    /// Environment variable holding the service URL, and its default.
+    pub fn url_var(self) -> (&'static str, &'static str) {
+        match self {
+            EmbedBackend::Tei => ("TEI_URL", "http://localhost:8081"),
+            EmbedBackend::Ollama => ("OLLAMA_URL", "http://localhost:11434"),
+        }
+    }
 }
 
-impl CodeChunker {
-    pub fn new(git_commit: String, git_branch: String) -> Self {
-        Self { git_commit, git_branch }
+/// Connects to the embedding service for `backend`, waiting up to
+/// `startup_timeout` for it to come up. Each request may take up to
+/// `request_timeout`.
+pub fn connect_embedder(
+    backend: EmbedBackend,
+    url: &str,
+    model: Option<&str>,
+    startup_timeout: Duration,
+    request_timeout: Duration,
+    max_retries: usize,
+    max_batch: usize,
+) -> Result<Box<dyn Embedder>> {
+    Ok(match backend {
+        EmbedBackend::Tei => Box::new(EmbeddingClient::new(url, startup_timeout, request_timeout)?.with_max_retries(max_retries).with_max_batch(max_batch)),
+        EmbedBackend::Ollama => {
+            let model = model.context("--embed-backend ollama needs --embed-model")?;
+            Box::new(OllamaEmbedder::new(url, model, startup_timeout, request_timeout)?.with_max_retries(max_retries).with_max_batch(max_batch))
+        }
+    })
+}
+
+/// The model id vectors are recorded and checked under: `--embedding-model`
+/// if given, otherwise whatever the embedding service reports.
+fn embedding_model_id(configured: Option<&str>, embedder: &dyn Embedder) -> Option<String> {
+    configured.or(embedder.model_id()).map(str::to_string)
+}
+
+/// Vectors from different models aren't comparable, so a collection is only
+/// written or searched with the model it was built with. Collections from
+/// before the model was recorded pass.
+fn check_embedding_model(collection: &str, stored: Option<&str>, model: Option<&str>) -> Result<()> {
+    if let (Some(stored), Some(model)) = (stored, model) {
+        if stored != model {
+            anyhow::bail!(
+                "{} was built with embedding model {}, but this run uses {}; index into another --collection or re-embed it with `migrate`",
+                collection, stored, model
+            );
+        }
     }
+    Ok(())
+}
 
-    pub fn chunk_code(&self, content: &str, file_path: &str) -> Vec<Chunk> {
-        let chunk_size = 3000;
-        let overlap = 500;
-        let lines: Vec<&str> = content.lines().collect();
-        let mut chunks = Vec::new();
-        let mut current_chunk: Vec<&str> = Vec::new();
-        let mut current_size = 0usize;
-        let mut start_line = 1usize;
+/// Default for `--embed-startup-timeout-secs`.
+const DEFAULT_EMBED_STARTUP_TIMEOUT_SECS: u64 = 60;
+/// Default for `--embed-timeout-secs`; a large batch on a busy server is slow.
+const DEFAULT_EMBED_TIMEOUT_SECS: u64 = 300;
+/// Default for `--store-timeout-secs`; store calls are small, so a long wait
+/// means the service is down.
+const DEFAULT_STORE_TIMEOUT_SECS: u64 = 60;
+/// Connecting gives up after this long even when the request timeout is
+/// longer, so a wrong host fails fast.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
-        for (i, line) in lines.iter().enumerate() {
-            let line_size = line.len() + 1;
+/// An HTTP client that gives up on connecting after `CONNECT_TIMEOUT` and on
+/// a whole request after `timeout`.
+fn http_client(timeout: Duration) -> Result<Client> {
+    http_client_with_headers(timeout, reqwest::header::HeaderMap::new())
+}
 
-            if current_size + line_size > chunk_size && !current_chunk.is_empty() {
-                chunks.push(self.create_chunk(file_path, &current_chunk, start_line));
+/// `http_client` that sends `headers` with every request.
+fn http_client_with_headers(timeout: Duration, headers: reqwest::header::HeaderMap) -> Result<Client> {
+    Ok(Client::builder().connect_timeout(CONNECT_TIMEOUT.min(timeout)).timeout(timeout).default_headers(headers).build()?)
+}
+const EMBED_STARTUP_POLL: Duration = Duration::from_secs(2);
 
-                let overlap_lines = self.get_overlap_lines(&current_chunk, overlap);
-                let overlap_count = overlap_lines.len();
-                current_chunk = overlap_lines;
-                current_size = current_chunk.iter().map(|l| l.len() + 1).sum();
-                start_line = i + 1 - overlap_count;
+/// Polls `probe_url` until it answers with a success status or `timeout`
+/// runs out. Always tries at least once. On timeout, the error says how long
+/// it waited, to tell a slow model load from a wrong URL.
+fn wait_for_service(client: &Client, probe_url: &str, timeout: Duration, name: &str, url: &str) -> Result<reqwest::blocking::Response> {
+    let started = Instant::now();
+    loop {
+        if let Ok(resp) = client.get(probe_url).send() {
+            if resp.status().is_success() {
+                return Ok(resp);
             }
-
-            current_chunk.push(line);
-            current_size += line_size;
         }
-
-        if !current_chunk.is_empty() {
-            chunks.push(self.create_chunk(file_path, &current_chunk, start_line));
+        let remaining = timeout.saturating_sub(started.elapsed());
+        if remaining.is_zero() {
+            anyhow::bail!("{} not available at {} after waiting {:.0}s", name, url, started.elapsed().as_secs_f32());
         }
+        std::thread::sleep(EMBED_STARTUP_POLL.min(remaining));
+    }
+}
 
-        chunks
+pub struct EmbeddingClient {
+    client: Client,
+    base_url: String,
+    model_id: Option<String>,
+    max_retries: usize,
+    max_batch: usize,
+}
+
+/// Statuses worth retrying an embedding request on: overload and gateway errors.
+const RETRYABLE_EMBED_STATUSES: &[u16] = &[429, 500, 502, 503, 504];
+const EMBED_BACKOFF_BASE_MS: u64 = 500;
+const EMBED_BACKOFF_MAX_MS: u64 = 30_000;
+
+/// Delay before retry number `attempt` (1-based): exponential from
+/// `EMBED_BACKOFF_BASE_MS`, capped, with up to half of it added as jitter so
+/// parallel workers don't retry in lockstep.
+fn embed_backoff(attempt: usize) -> Duration {
+    let base = EMBED_BACKOFF_BASE_MS.saturating_mul(1 << (attempt - 1).min(16)).min(EMBED_BACKOFF_MAX_MS);
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos() as u64);
+    Duration::from_millis(base + nanos % (base / 2 + 1))
+}
+
+#[derive(Deserialize)]
+struct EmbedInfo {
+    model_id: String,
+}
+
+#[derive(Serialize)]
+struct EmbedRequest {
+    inputs: Vec<String>,
+}
+
+impl EmbeddingClient {
+    pub fn new(url: &str, startup_timeout: Duration, request_timeout: Duration) -> Result<Self> {
+        let client = http_client(request_timeout)?;
+
+        wait_for_service(&client, &format!("{}/health", url), startup_timeout, "Embedding service", url)?;
+        let model_id = client
+            .get(format!("{}/info", url))
+            .send()
+            .ok()
+            .filter(|r| r.status().is_success())
+            .and_then(|r| r.json::<EmbedInfo>().ok())
+            .map(|info| info.model_id);
+        Ok(Self { client, base_url: url.to_string(), model_id, max_retries: 0, max_batch: usize::MAX })
     }
 
-    fn create_chunk(&self, file_path: &str, lines: &[&str], start_line: usize) -> Chunk {
-        let end_line = start_line + lines.len() - 1;
-        let chunk_text = lines.join("\n");
+    /// Retries an `encode` up to `n` times on overload statuses, connection
+    /// errors and timeouts. Other errors fail straight away.
+    pub fn with_max_retries(mut self, n: usize) -> Self {
+        self.max_retries = n;
+        self
+    }
 
-        let file_type = Path::new(file_path)
-            .extension()
-            .and_then(|e| e.to_str())
-            .map(|e| format!(".{}", e))
-            .unwrap_or_default();
+    /// Sends at most `n` inputs per request, splitting larger `encode` calls.
+    pub fn with_max_batch(mut self, n: usize) -> Self {
+        self.max_batch = n.max(1);
+        self
+    }
 
-        let commit_prefix = if self.git_commit.len() >= 8 { &self.git_commit[..8] } else { &self.git_commit };
-        let id = format!("{}_{}_{}_{}_{}",
-            self.git_branch, commit_prefix,
-            file_path.replace('/', "_").replace('.', "_"),
-            start_line, end_line
-        );
+    /// Model reported by the service's `/info` endpoint, if it has one.
+    pub fn model_id(&self) -> Option<&str> {
+        self.model_id.as_deref()
+    }
 
-        Chunk {
-            id,
-            text: chunk_text,
-            metadata: ChunkMetadata {
-                file_path: file_path.to_string(),
-                start_line,
-                end_line,
-                file_type,
-                git_commit: self.git_commit.clone(),
-                git_branch: self.git_branch.clone(),
-            },
+    pub fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for group in texts.chunks(self.max_batch) {
+            embeddings.extend(self.encode_batch(group)?);
+        }
+        Ok(embeddings)
+    }
+
+    fn encode_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let request = EmbedRequest {
+            inputs: texts.iter().map(|s| s.to_string()).collect(),
+        };
+
+        let url = format!("{}/embed", self.base_url);
+        post_with_retries(&self.client, &url, &request, self.max_retries)?
+            .json()
+            .context("Invalid embedding response")
+    }
+}
+
+impl Embedder for EmbeddingClient {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        EmbeddingClient::encode(self, texts)
+    }
+
+    fn model_id(&self) -> Option<&str> {
+        EmbeddingClient::model_id(self)
+    }
+}
+
+/// POSTs `body` as JSON, retrying up to `max_retries` times on overload
+/// statuses, connection errors and timeouts. Other errors fail straight away.
+fn post_with_retries<T: Serialize>(client: &Client, url: &str, body: &T, max_retries: usize) -> Result<reqwest::blocking::Response> {
+    let mut attempt = 0;
+    loop {
+        let result = client.post(url).json(body).send().and_then(|r| r.error_for_status());
+        let err = match result {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        let retryable = err.is_connect()
+            || err.is_timeout()
+            || err.status().is_some_and(|status| RETRYABLE_EMBED_STATUSES.contains(&status.as_u16()));
+        if !retryable || attempt >= max_retries {
+            let message = match attempt {
+                0 => "Embedding request failed".to_string(),
+                n => format!("Embedding request failed after {} retries", n),
+            };
+            return Err(anyhow::Error::new(err).context(message));
+        }
+        attempt += 1;
+        let delay = embed_backoff(attempt);
+        warn!("embedding request failed ({}); retry {}/{} in {:.1}s", err, attempt, max_retries, delay.as_secs_f32());
+        std::thread::sleep(delay);
+    }
+}
+
+/// Embeds through a local Ollama server. Uses the batch `/api/embed` endpoint
+/// and falls back to one-text-per-request `/api/embeddings` on servers that
+/// predate it.
+pub struct OllamaEmbedder {
+    client: Client,
+    base_url: String,
+    model: String,
+    max_retries: usize,
+    max_batch: usize,
+    legacy: AtomicBool,
+}
+
+#[derive(Deserialize)]
+struct OllamaTags {
+    #[serde(default)]
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct OllamaEmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [&'a str],
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+#[derive(Serialize)]
+struct OllamaLegacyRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+}
+
+#[derive(Deserialize)]
+struct OllamaLegacyResponse {
+    embedding: Vec<f32>,
+}
+
+impl OllamaEmbedder {
+    pub fn new(url: &str, model: &str, startup_timeout: Duration, request_timeout: Duration) -> Result<Self> {
+        let client = http_client(request_timeout)?;
+
+        let resp = wait_for_service(&client, &format!("{}/api/tags", url), startup_timeout, "Ollama", url)?;
+        let tags = resp.json::<OllamaTags>().unwrap_or(OllamaTags { models: Vec::new() });
+        let tagged = format!("{}:latest", model);
+        if !tags.models.iter().any(|m| m.name == model || m.name == tagged) {
+            warn!("Ollama at {} has no model {}; run `ollama pull {}`", url, model, model);
+        }
+        Ok(Self {
+            client,
+            base_url: url.to_string(),
+            model: model.to_string(),
+            max_retries: 0,
+            max_batch: usize::MAX,
+            legacy: AtomicBool::new(false),
+        })
+    }
+
+    /// Retries a request up to `n` times on overload statuses, connection
+    /// errors and timeouts.
+    pub fn with_max_retries(mut self, n: usize) -> Self {
+        self.max_retries = n;
+        self
+    }
+
+    /// Sends at most `n` inputs per `/api/embed` request.
+    pub fn with_max_batch(mut self, n: usize) -> Self {
+        self.max_batch = n.max(1);
+        self
+    }
+
+    fn encode_batch(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let url = format!("{}/api/embed", self.base_url);
+        let request = OllamaEmbedRequest { model: &self.model, input: texts };
+        match post_with_retries(&self.client, &url, &request, self.max_retries) {
+            Ok(response) => {
+                let embeddings = response.json::<OllamaEmbedResponse>().context("Invalid Ollama embedding response")?.embeddings;
+                if embeddings.len() != texts.len() {
+                    anyhow::bail!("Ollama returned {} embeddings for {} inputs", embeddings.len(), texts.len());
+                }
+                Ok(embeddings)
+            }
+            Err(err) if is_not_found(&err) => {
+                warn!("Ollama at {} has no /api/embed; embedding one text per request", self.base_url);
+                self.legacy.store(true, Ordering::Relaxed);
+                self.encode_each(texts)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn encode_each(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        let url = format!("{}/api/embeddings", self.base_url);
+        texts
+            .iter()
+            .map(|text| {
+                let request = OllamaLegacyRequest { model: &self.model, prompt: text };
+                let response: OllamaLegacyResponse = post_with_retries(&self.client, &url, &request, self.max_retries)?
+                    .json()
+                    .context("Invalid Ollama embedding response")?;
+                Ok(response.embedding)
+            })
+            .collect()
+    }
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+        .is_some_and(|status| status == reqwest::StatusCode::NOT_FOUND)
+}
+
+impl Embedder for OllamaEmbedder {
+    fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<f32>>> {
+        if self.legacy.load(Ordering::Relaxed) {
+            return self.encode_each(texts);
+        }
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for group in texts.chunks(self.max_batch) {
+            embeddings.extend(self.encode_batch(group)?);
+        }
+        Ok(embeddings)
+    }
+
+    fn model_id(&self) -> Option<&str> {
+        Some(&self.model)
+    }
+}
+
+/// What to do when the embedding service returns a vector that can't be right.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BadEmbeddingPolicy {
+    /// Abort the run on the first bad vector
+    Fail,
+    /// Drop the offending chunk, warn, and keep going
+    Skip,
+}
+
+/// Returns why an embedding is unusable, or `None` if it looks sane.
+///
+/// Only vectors with no non-zero component at all are treated as all-zero, so
+/// sparse embeddings with a handful of tiny values still pass.
+fn embedding_problem(embedding: &[f32], expected_dim: Option<usize>) -> Option<String> {
+    if embedding.is_empty() {
+        return Some("zero-length vector".to_string());
+    }
+    if let Some(dim) = expected_dim {
+        if embedding.len() != dim {
+            return Some(format!("dimension {} (expected {})", embedding.len(), dim));
+        }
+    }
+    if embedding.iter().any(|x| !x.is_finite()) {
+        return Some("NaN or infinite component".to_string());
+    }
+    if embedding.iter().all(|&x| x == 0.0) {
+        return Some("all-zero vector".to_string());
+    }
+    None
+}
+
+/// Whether a chunk has enough real content to be worth embedding.
+fn is_embeddable(text: &str) -> bool {
+    text.chars().any(char::is_alphanumeric)
+        && text.chars().filter(|c| !c.is_whitespace()).nth(MIN_EMBEDDABLE_CHARS - 1).is_some()
+}
+
+/// Number of distinct whitespace-delimited tokens in a chunk's text.
+fn distinct_tokens(text: &str) -> usize {
+    text.split_whitespace().collect::<HashSet<_>>().len()
+}
+
+/// Drops chunks with fewer than `min_tokens` distinct tokens (`--min-distinct-tokens`),
+/// counting them in `stats`.
+fn drop_low_information(chunks: &mut Vec<Chunk>, min_tokens: Option<usize>, stats: &RunStats) {
+    let Some(min_tokens) = min_tokens else { return };
+    let before = chunks.len();
+    chunks.retain(|c| distinct_tokens(&c.text) >= min_tokens);
+    stats.low_information_chunks.fetch_add(before - chunks.len(), Ordering::Relaxed);
+}
+
+fn l2_norm(embedding: &[f32]) -> f32 {
+    embedding.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+/// Checks a batch of embeddings against its chunks, dropping or rejecting bad
+/// vectors per `policy`. The first well-formed vector fixes the run's dimension.
+fn validate_embeddings(
+    chunks: &[Chunk],
+    embeddings: Vec<Vec<f32>>,
+    expected_dim: &mut Option<usize>,
+    policy: BadEmbeddingPolicy,
+) -> Result<(Vec<Chunk>, Vec<Vec<f32>>, usize)> {
+    if embeddings.len() != chunks.len() {
+        anyhow::bail!("Embedding service returned {} vectors for {} inputs", embeddings.len(), chunks.len());
+    }
+
+    let mut kept_chunks = Vec::with_capacity(chunks.len());
+    let mut kept_embeddings = Vec::with_capacity(embeddings.len());
+    let mut skipped = 0usize;
+
+    for (chunk, embedding) in chunks.iter().zip(embeddings) {
+        if let Some(problem) = embedding_problem(&embedding, *expected_dim) {
+            let location = format!("{}:{}-{}", chunk.metadata.file_path, chunk.metadata.start_line, chunk.metadata.end_line);
+            match policy {
+                BadEmbeddingPolicy::Fail => anyhow::bail!("Bad embedding for {}: {}", location, problem),
+                BadEmbeddingPolicy::Skip => {
+                    warn!(path = %location, problem = %problem, "skipping bad embedding");
+                    skipped += 1;
+                    continue;
+                }
+            }
+        }
+
+        expected_dim.get_or_insert(embedding.len());
+        kept_chunks.push(chunk.clone());
+        kept_embeddings.push(embedding);
+    }
+
+    Ok((kept_chunks, kept_embeddings, skipped))
+}
+
+// ============================================================================
+// Embedding Cache
+// ============================================================================
+
+/// Bumped whenever cached vectors stop being valid for the same model and
+/// content (say, a change to how embedding inputs are built). A cache with an
+/// older version is emptied on open.
+const EMBEDDING_CACHE_VERSION: i64 = 1;
+
+/// File name of the embedding cache inside `--cache-dir`
+const EMBEDDING_CACHE_FILE: &str = "embeddings.sqlite";
+
+/// Local SQLite store of vectors keyed by model and content digest, so content
+/// that hasn't changed between runs is never re-embedded. Keying on the model
+/// means switching models simply misses.
+pub struct EmbeddingCache {
+    conn: Mutex<rusqlite::Connection>,
+    model: String,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl EmbeddingCache {
+    pub fn open(path: &Path, model: &str) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)
+            .with_context(|| format!("Failed to open embedding cache {}", path.display()))?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != EMBEDDING_CACHE_VERSION {
+            if version != 0 {
+                info!("Embedding cache {} is from version {}; starting it afresh", path.display(), version);
+            }
+            conn.execute_batch(&format!("DROP TABLE IF EXISTS embeddings; PRAGMA user_version = {}", EMBEDDING_CACHE_VERSION))?;
+        }
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS embeddings (
+                model TEXT NOT NULL,
+                digest TEXT NOT NULL,
+                vector BLOB NOT NULL,
+                PRIMARY KEY (model, digest)
+            )",
+        )?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            model: model.to_string(),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn get_many(&self, digests: &[String]) -> Result<Vec<Option<Vec<f32>>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached("SELECT vector FROM embeddings WHERE model = ?1 AND digest = ?2")?;
+        let mut found = Vec::with_capacity(digests.len());
+        for digest in digests {
+            let vector: Option<Vec<u8>> = stmt
+                .query_row(rusqlite::params![self.model, digest], |row| row.get(0))
+                .map(Some)
+                .or_else(|e| if e == rusqlite::Error::QueryReturnedNoRows { Ok(None) } else { Err(e) })?;
+            let vector = vector.map(|bytes| {
+                bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+            });
+            let counter = if vector.is_some() { &self.hits } else { &self.misses };
+            counter.fetch_add(1, Ordering::Relaxed);
+            found.push(vector);
+        }
+        Ok(found)
+    }
+
+    pub fn put_many(&self, entries: &[(String, &[f32])]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached("INSERT OR REPLACE INTO embeddings (model, digest, vector) VALUES (?1, ?2, ?3)")?;
+            for (digest, vector) in entries {
+                let bytes: Vec<u8> = vector.iter().flat_map(|x| x.to_le_bytes()).collect();
+                stmt.execute(rusqlite::params![self.model, digest, bytes])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn report(&self) {
+        info!(
+            "Embedding cache: {} hits, {} misses",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed)
+        );
+    }
+}
+
+// ============================================================================
+// Chunking
+// ============================================================================
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChunkMetadata {
+    pub file_path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub file_type: String,
+    /// Language from the file's name, extension, shebang or content,
+    /// falling back to the bare extension
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub git_commit: String,
+    pub git_branch: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_encoding: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding_norm: Option<f32>,
+    /// Lines preceding the chunk, stored for display only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_before: Option<String>,
+    /// Lines following the chunk, stored for display only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_after: Option<String>,
+    /// `file` for whole-file summary chunks; absent on regular chunks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub granularity: Option<String>,
+    /// Author of the most lines in the chunk, with `--with-blame`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub primary_author: Option<String>,
+    /// JSON object of author to line count, with `--with-blame`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author_lines: Option<String>,
+    /// Set on chunks of files with uncommitted changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dirty: Option<bool>,
+    /// `false` on chunks written with `--archive-old-commits`, `true` once a
+    /// later commit archives them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archived: Option<bool>,
+    /// JSON array of every file holding this chunk's text, with `--dedup-chunks`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_files: Option<String>,
+    /// Unix time the chunk was chunked for upload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub indexed_at: Option<u64>,
+    /// `SCHEMA_VERSION` of the indexer that wrote the chunk
+    pub schema_version: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub id: String,
+    /// Stored document text
+    pub text: String,
+    /// Text sent to the embedder when transforms make it differ from `text`
+    pub embed_text: Option<String>,
+    pub metadata: ChunkMetadata,
+}
+
+impl Chunk {
+    pub fn embedding_input(&self) -> &str {
+        self.embed_text.as_deref().unwrap_or(&self.text)
+    }
+}
+
+/// How chunk ids are derived.
+///
+/// `lines` keys ids on the line range, so any change to chunk boundaries
+/// changes every id. `content` keys them on a hash of the chunk text, so
+/// re-chunking leaves ids for unchanged content intact. `stable` also drops
+/// the commit, so unchanged content keeps its id from one commit to the next
+/// and a re-index only replaces the chunks that changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IdMode {
+    Lines,
+    Content,
+    Stable,
+}
+
+fn content_digest(text: &str) -> String {
+    Sha256::digest(text.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn content_hash(text: &str) -> String {
+    content_digest(text)[..16].to_string()
+}
+
+/// A definition line in most mainstream languages; group 1 is the name.
+static DEFINITION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"^\s*(?:export\s+)?(?:pub(?:\([^)]*\))?\s+)?(?:async\s+)?(?:fn|struct|enum|trait|class|def|function|interface|func|module|type)\s+([A-Za-z_][A-Za-z0-9_]*)",
+    )
+    .unwrap()
+});
+
+/// The comment block at the top of a file (after any shebang), with comment
+/// markers stripped.
+fn leading_comment(content: &str) -> Vec<&str> {
+    let mut doc = Vec::new();
+    for line in content.lines().skip_while(|l| l.starts_with("#!") || l.trim().is_empty()) {
+        let trimmed = line.trim();
+        let Some(text) = ["///", "//!", "//", "/**", "/*", "*/", "*", "# ", "--", ";;"]
+            .iter()
+            .find_map(|marker| trimmed.strip_prefix(marker))
+        else {
+            break;
+        };
+        let text = text.trim().trim_end_matches("*/").trim();
+        if !text.is_empty() {
+            doc.push(text);
+        }
+        if doc.len() == SUMMARY_MAX_DOC_LINES { break; }
+    }
+    doc
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LineEnding {
+    /// Join chunk lines with `\n` regardless of the source
+    Lf,
+    /// Join with the file's dominant line ending so documents match the bytes on disk
+    Preserve,
+}
+
+impl LineEnding {
+    /// The separator to join `content`'s lines with.
+    fn separator(self, content: &str) -> &'static str {
+        if self == Self::Preserve {
+            let crlf = content.matches("\r\n").count();
+            if crlf > content.matches('\n').count() - crlf {
+                return "\r\n";
+            }
+        }
+        "\n"
+    }
+}
+
+/// Lines that are always noise under `--strip-noise`: banners of repeated punctuation.
+static BANNER_LINE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*(?://+|/\*+)?\s*(?:-{20,}|={20,}|\*{20,}|#{20,}|~{20,}|_{20,}|\+{20,})\s*(?:\*+/)?\s*$").unwrap());
+
+/// Minimum length for a line to count as an embedded base64 blob.
+const BASE64_MIN_LINE: usize = 60;
+
+/// Drops noise lines (banners, base64 blobs, user patterns) before chunking.
+#[derive(Debug, Clone)]
+pub struct NoiseFilter {
+    patterns: Vec<Regex>,
+    /// Store the original lines as the document and only embed the cleaned text
+    keep_in_document: bool,
+}
+
+impl NoiseFilter {
+    pub fn new(patterns: &[String], keep_in_document: bool) -> Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|p| Regex::new(p).with_context(|| format!("Invalid --noise-pattern '{}'", p)))
+            .collect::<Result<_>>()?;
+        Ok(Self { patterns, keep_in_document })
+    }
+
+    fn is_noise(&self, line: &str) -> bool {
+        BANNER_LINE.is_match(line) || is_base64_blob(line) || self.patterns.iter().any(|p| p.is_match(line))
+    }
+}
+
+/// A long line made only of base64 characters, allowing for surrounding quotes
+/// and a trailing comma as in a string literal.
+fn is_base64_blob(line: &str) -> bool {
+    let body = line.trim().trim_end_matches([',', ';', '+']).trim_matches(['"', '\'', '`']);
+    body.len() >= BASE64_MIN_LINE && body.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+}
+
+/// How files are cut into chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChunkStrategy {
+    /// Fill chunks line by line up to --chunk-size
+    Lines,
+    /// Cut between top-level declarations in supported languages, falling back to lines
+    Syntactic,
+}
+
+/// What `--chunk-size` and `--overlap` count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SizeUnit {
+    Bytes,
+    /// Tokens of the model's tokenizer, loaded from --tokenizer
+    Tokens,
+}
+
+impl SizeUnit {
+    fn name(self) -> &'static str {
+        match self {
+            SizeUnit::Bytes => "bytes",
+            SizeUnit::Tokens => "tokens",
+        }
+    }
+}
+
+/// Loads the tokenizer for `--size-unit tokens`. Without one, chunks are
+/// sized in bytes as before, so indexing still works offline.
+fn load_tokenizer(unit: SizeUnit, path: Option<&Path>) -> Option<Arc<tokenizers::Tokenizer>> {
+    if unit == SizeUnit::Bytes {
+        return None;
+    }
+    let Some(path) = path else {
+        warn!("--size-unit tokens needs --tokenizer <tokenizer.json>; sizing chunks in bytes");
+        return None;
+    };
+    match tokenizers::Tokenizer::from_file(path) {
+        Ok(tokenizer) => Some(Arc::new(tokenizer)),
+        Err(err) => {
+            warn!("failed to load tokenizer {}: {}; sizing chunks in bytes", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Set once a tokenizer fails to encode, so the fallback to bytes is reported once.
+static TOKENIZER_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Grammars that failed to load this run. Each is warned about once and then
+/// skipped, so later files of that language go straight to line chunking.
+static FAILED_GRAMMARS: LazyLock<Mutex<HashSet<&'static str>>> = LazyLock::new(Default::default);
+
+/// Records that the grammar for `name` can't be used. Returns whether this is
+/// the first failure reported for it.
+fn grammar_failed(name: &'static str, err: &dyn fmt::Display) -> bool {
+    let first = FAILED_GRAMMARS.lock().unwrap().insert(name);
+    if first {
+        warn!("tree-sitter grammar for {} failed to load ({}); chunking its files by lines", name, err);
+    }
+    first
+}
+
+/// Tree-sitter grammar, and its name, for the languages syntactic chunking
+/// supports. `None` for other files and for grammars that failed to load.
+fn syntax_language(file_path: &str) -> Option<(&'static str, tree_sitter::Language)> {
+    let (name, language) = match Path::new(file_path).extension()?.to_str()? {
+        "rs" => ("rust", tree_sitter_rust::LANGUAGE),
+        "ts" => ("typescript", tree_sitter_typescript::LANGUAGE_TYPESCRIPT),
+        "tsx" => ("tsx", tree_sitter_typescript::LANGUAGE_TSX),
+        "js" | "jsx" | "mjs" | "cjs" => ("javascript", tree_sitter_javascript::LANGUAGE),
+        "py" => ("python", tree_sitter_python::LANGUAGE),
+        "go" => ("go", tree_sitter_go::LANGUAGE),
+        _ => return None,
+    };
+    if FAILED_GRAMMARS.lock().unwrap().contains(name) {
+        return None;
+    }
+    Some((name, language.into()))
+}
+
+/// First line (1-based) of each top-level declaration. Comments and attributes
+/// stay with the declaration after them, and a node starting on the line
+/// another ends on (e.g. a trailing comment) stays with that one. `None` if
+/// the grammar won't load or the file can't be parsed.
+fn declaration_starts(content: &str, name: &'static str, language: &tree_sitter::Language) -> Option<Vec<usize>> {
+    let mut parser = tree_sitter::Parser::new();
+    if let Err(err) = parser.set_language(language) {
+        grammar_failed(name, &err);
+        return None;
+    }
+    let tree = parser.parse(content, None)?;
+    let root = tree.root_node();
+
+    let mut starts = Vec::new();
+    let mut attached = false;
+    let mut previous_end = None;
+    let mut cursor = root.walk();
+    for node in root.children(&mut cursor) {
+        let row = node.start_position().row;
+        if !attached && previous_end.is_none_or(|end| row > end) {
+            starts.push(row + 1);
+        }
+        let kind = node.kind();
+        attached = kind.contains("comment") || kind == "attribute_item" || kind == "decorator";
+        previous_end = Some(node.end_position().row);
+    }
+    Some(starts)
+}
+
+#[derive(Clone)]
+pub struct CodeChunker {
+    git_commit: String,
+    git_branch: String,
+    version: Option<String>,
+    id_mode: IdMode,
+    context_lines: usize,
+    line_ending: LineEnding,
+    noise: Option<NoiseFilter>,
+    chunk_size: usize,
+    overlap: usize,
+    strategy: ChunkStrategy,
+    /// Sizes chunks in tokens instead of bytes when set
+    tokenizer: Option<Arc<tokenizers::Tokenizer>>,
+}
+
+impl CodeChunker {
+    pub fn new(git_commit: String, git_branch: String, id_mode: IdMode) -> Self {
+        Self {
+            git_commit,
+            git_branch,
+            version: None,
+            id_mode,
+            context_lines: 0,
+            line_ending: LineEnding::Lf,
+            noise: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            overlap: DEFAULT_CHUNK_OVERLAP,
+            strategy: ChunkStrategy::Lines,
+            tokenizer: None,
+        }
+    }
+
+    /// Measures `chunk_size` and `overlap` in this tokenizer's tokens.
+    pub fn with_tokenizer(mut self, tokenizer: Option<Arc<tokenizers::Tokenizer>>) -> Self {
+        self.tokenizer = tokenizer;
+        self
+    }
+
+    /// A line's share of a chunk's size, counting its newline: bytes, or
+    /// tokens with a tokenizer. Lines are tokenized on their own, which is
+    /// close to how they tokenize inside a chunk. `chunk_code` sizes each
+    /// line once and hands the sizes down.
+    fn line_size(&self, line: &str) -> usize {
+        match &self.tokenizer {
+            Some(tokenizer) => match tokenizer.encode(line, false) {
+                Ok(encoding) => encoding.len() + 1,
+                Err(err) => {
+                    if !TOKENIZER_FAILED.swap(true, Ordering::Relaxed) {
+                        warn!("tokenizer failed ({}); sizing lines it can't encode in bytes", err);
+                    }
+                    line.len() + 1
+                }
+            },
+            None => line.len() + 1,
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: ChunkStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Target chunk size and the overlap carried into the next chunk, in bytes
+    /// or, with a tokenizer, tokens.
+    pub fn with_sizes(mut self, chunk_size: usize, overlap: usize) -> Result<Self> {
+        if chunk_size == 0 {
+            anyhow::bail!("--chunk-size must be greater than 0");
+        }
+        if overlap >= chunk_size {
+            anyhow::bail!("--overlap ({}) must be less than --chunk-size ({})", overlap, chunk_size);
+        }
+        self.chunk_size = chunk_size;
+        self.overlap = overlap;
+        Ok(self)
+    }
+
+    /// Leaves lines `noise` flags out of chunks before they're sized.
+    pub fn with_noise_filter(mut self, noise: Option<NoiseFilter>) -> Self {
+        self.noise = noise;
+        self
+    }
+
+    pub fn with_line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    /// Stores up to `n` surrounding lines on each side of a chunk in its metadata.
+    pub fn with_context_lines(mut self, n: usize) -> Self {
+        self.context_lines = n;
+        self
+    }
+
+    /// Tags chunks with a release label so several versions can share a collection.
+    pub fn with_version(mut self, version: Option<String>) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// A copy that tags chunks with `root`'s commit and branch.
+    pub fn for_root(&self, root: &SourceRoot) -> Self {
+        Self { git_commit: root.git_commit.clone(), git_branch: root.git_branch.clone(), ..self.clone() }
+    }
+
+    pub fn chunk_code(&self, content: &str, file_path: &str) -> Vec<Chunk> {
+        let newline = self.line_ending.separator(content);
+        let lines: Vec<&str> = content.lines().collect();
+        // Source line numbers (1-based) of the lines that make it into chunks;
+        // noise lines are left out but ranges still refer to the original file
+        let kept: Vec<usize> = (1..=lines.len())
+            .filter(|&n| !self.noise.as_ref().is_some_and(|noise| noise.is_noise(lines[n - 1])))
+            .collect();
+        // Sizes by line, so a line is tokenized once however often it's measured
+        let mut sizes = vec![0; lines.len()];
+        for &n in &kept {
+            sizes[n - 1] = self.line_size(lines[n - 1]);
+        }
+        let starts = match self.strategy {
+            ChunkStrategy::Syntactic => syntax_language(file_path).and_then(|(name, language)| declaration_starts(content, name, &language)),
+            ChunkStrategy::Lines => None,
+        };
+        let mut chunks = match starts {
+            Some(starts) => self.chunk_declarations(file_path, &lines, &sizes, &kept, &starts, newline),
+            None => self.chunk_lines(file_path, &lines, &sizes, &kept, newline),
+        };
+
+        let language = language_tag(Path::new(file_path), content);
+        for chunk in &mut chunks {
+            chunk.metadata.language = language.clone();
+        }
+
+        // Store the untouched source range, embedding the cleaned text
+        if self.noise.as_ref().is_some_and(|noise| noise.keep_in_document) {
+            for chunk in &mut chunks {
+                let original = lines[chunk.metadata.start_line - 1..chunk.metadata.end_line].join(newline);
+                if original != chunk.text {
+                    chunk.embed_text = Some(std::mem::replace(&mut chunk.text, original));
+                }
+            }
+        }
+
+        if self.context_lines > 0 {
+            for chunk in &mut chunks {
+                let before_start = (chunk.metadata.start_line - 1).saturating_sub(self.context_lines);
+                let before = &lines[before_start..chunk.metadata.start_line - 1];
+                let after_end = (chunk.metadata.end_line + self.context_lines).min(lines.len());
+                let after = &lines[chunk.metadata.end_line..after_end];
+                chunk.metadata.context_before = (!before.is_empty()).then(|| before.join(newline));
+                chunk.metadata.context_after = (!after.is_empty()).then(|| after.join(newline));
+            }
+        }
+
+        // Repeated content within a file would otherwise share an id
+        if self.id_mode != IdMode::Lines {
+            let mut seen: HashMap<String, usize> = HashMap::new();
+            for chunk in &mut chunks {
+                let count = seen.entry(chunk.id.clone()).or_insert(0);
+                *count += 1;
+                if *count > 1 {
+                    chunk.id = format!("{}_{}", chunk.id, count);
+                }
+            }
+        }
+
+        chunks
+    }
+
+    /// Packs whole top-level declarations (starting at the 1-based `starts`)
+    /// into chunks up to `chunk_size`. A declaration too big for one chunk is
+    /// split by `chunk_lines` on its own.
+    fn chunk_declarations(&self, file_path: &str, lines: &[&str], sizes: &[usize], kept: &[usize], starts: &[usize], newline: &str) -> Vec<Chunk> {
+        // Declaration boundaries as indexes into `kept`
+        let mut bounds = vec![0];
+        for &start in starts {
+            let bound = kept.partition_point(|&n| n < start);
+            if bound > *bounds.last().unwrap() && bound < kept.len() {
+                bounds.push(bound);
+            }
+        }
+        bounds.push(kept.len());
+        let size = |range: &[usize]| range.iter().map(|&n| sizes[n - 1]).sum::<usize>();
+
+        let mut chunks = Vec::new();
+        let mut group_start = 0;
+        let mut group_size = 0;
+        for unit in bounds.windows(2) {
+            let (from, to) = (unit[0], unit[1]);
+            let unit_size = size(&kept[from..to]);
+            if group_size + unit_size > self.chunk_size && group_start < from {
+                chunks.extend(self.chunk_lines(file_path, lines, sizes, &kept[group_start..from], newline));
+                group_start = from;
+                group_size = 0;
+            }
+            if unit_size > self.chunk_size {
+                chunks.extend(self.chunk_lines(file_path, lines, sizes, &kept[from..to], newline));
+                group_start = to;
+                continue;
+            }
+            group_size += unit_size;
+        }
+        if group_start < kept.len() {
+            chunks.extend(self.chunk_lines(file_path, lines, sizes, &kept[group_start..], newline));
+        }
+        chunks
+    }
+
+    /// Fills chunks line by line up to `chunk_size`, carrying `overlap` worth
+    /// of trailing lines into the next. `kept` holds the 1-based source line
+    /// numbers to use; `sizes` holds every line's `line_size`.
+    fn chunk_lines(&self, file_path: &str, lines: &[&str], sizes: &[usize], kept: &[usize], newline: &str) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let text = |range: &[usize]| range.iter().map(|&n| lines[n - 1]).collect::<Vec<&str>>();
+        let mut current_size = 0usize;
+        let mut start = 0usize;
+
+        for (i, &line_no) in kept.iter().enumerate() {
+            let line_size = sizes[line_no - 1];
+
+            if current_size + line_size > self.chunk_size && start < i {
+                let current_chunk = &kept[start..i];
+                chunks.push(self.create_chunk(file_path, &text(current_chunk), kept[start], kept[i - 1], newline));
+
+                start += self.overlap_start(current_chunk, sizes);
+                current_size = kept[start..i].iter().map(|&n| sizes[n - 1]).sum();
+            }
+
+            current_size += line_size;
+        }
+
+        if start < kept.len() {
+            chunks.push(self.create_chunk(file_path, &text(&kept[start..]), kept[start], kept[kept.len() - 1], newline));
+        }
+
+        chunks
+    }
+
+    /// One synthetic chunk describing the whole file: path, language, leading
+    /// doc comment and defined names. `None` for files under `SUMMARY_MIN_LINES`.
+    pub fn summary_chunk(&self, content: &str, file_path: &str, language: Option<&str>) -> Option<Chunk> {
+        let line_count = content.lines().count();
+        if line_count < SUMMARY_MIN_LINES {
+            return None;
+        }
+
+        let mut summary = vec![format!("File: {}", file_path)];
+        if let Some(language) = language {
+            summary.push(format!("Language: {}", language));
+        }
+        summary.push(format!("Lines: {}", line_count));
+
+        let doc = leading_comment(content);
+        if !doc.is_empty() {
+            summary.push(format!("Summary: {}", doc.join(" ")));
+        }
+
+        let mut symbols: Vec<&str> = Vec::new();
+        for captures in content.lines().filter_map(|line| DEFINITION.captures(line)) {
+            let name = captures.get(1).map_or("", |m| m.as_str());
+            if !symbols.contains(&name) {
+                symbols.push(name);
+            }
+            if symbols.len() == SUMMARY_MAX_SYMBOLS { break; }
+        }
+        if !symbols.is_empty() {
+            summary.push(format!("Defines: {}", symbols.join(", ")));
+        }
+
+        let lines: Vec<&str> = summary.iter().map(String::as_str).collect();
+        let mut chunk = self.create_chunk(file_path, &lines, 1, line_count, "\n");
+        chunk.id = format!("{}_summary", self.id_prefix(file_path));
+        chunk.metadata.language = language_tag(Path::new(file_path), content);
+        chunk.metadata.granularity = Some("file".to_string());
+        Some(chunk)
+    }
+
+    /// Scope, commit and path parts shared by every chunk id of a file.
+    /// Stable ids leave out the commit.
+    fn id_prefix(&self, file_path: &str) -> String {
+        let commit_prefix = if self.git_commit.len() >= 8 { &self.git_commit[..8] } else { &self.git_commit };
+        let path_key = file_path.replace(['/', '.'], "_");
+        let scope = match &self.version {
+            Some(version) => format!("{}_{}", version, self.git_branch),
+            None => self.git_branch.clone(),
+        };
+        match self.id_mode {
+            IdMode::Stable => format!("{}_{}", scope, path_key),
+            IdMode::Lines | IdMode::Content => format!("{}_{}_{}", scope, commit_prefix, path_key),
+        }
+    }
+
+    fn create_chunk(&self, file_path: &str, lines: &[&str], start_line: usize, end_line: usize, newline: &str) -> Chunk {
+        let chunk_text = lines.join(newline);
+
+        let file_type = Path::new(file_path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| format!(".{}", e))
+            .unwrap_or_default();
+
+        let prefix = self.id_prefix(file_path);
+        let id = match self.id_mode {
+            IdMode::Lines => format!("{}_{}_{}", prefix, start_line, end_line),
+            IdMode::Content | IdMode::Stable => format!("{}_{}", prefix, content_hash(&chunk_text)),
+        };
+
+        Chunk {
+            id,
+            text: chunk_text,
+            embed_text: None,
+            metadata: ChunkMetadata {
+                file_path: file_path.to_string(),
+                start_line,
+                end_line,
+                file_type,
+                language: None,
+                git_commit: self.git_commit.clone(),
+                git_branch: self.git_branch.clone(),
+                version: self.version.clone(),
+                source_encoding: None,
+                embedding_norm: None,
+                context_before: None,
+                context_after: None,
+                granularity: None,
+                primary_author: None,
+                author_lines: None,
+                dirty: None,
+                archived: None,
+                source_files: None,
+                indexed_at: None,
+                schema_version: SCHEMA_VERSION,
+            },
+        }
+    }
+
+    /// Where the trailing lines of `current_chunk` (1-based line numbers)
+    /// totalling at most `overlap` begin, as an index into it. The first line
+    /// is never carried, so each chunk starts after the previous one;
+    /// otherwise a short chunk followed by a long line would be repeated whole
+    /// inside the next chunk.
+    fn overlap_start(&self, current_chunk: &[usize], sizes: &[usize]) -> usize {
+        let mut start = current_chunk.len();
+        let mut overlap_size = 0usize;
+        while start > 1 {
+            let line_size = sizes[current_chunk[start - 1] - 1];
+            if overlap_size + line_size > self.overlap { break; }
+            overlap_size += line_size;
+            start -= 1;
+        }
+        start
+    }
+}
+
+// ============================================================================
+// Transforms
+// ============================================================================
+
+/// A rewrite applied to chunk text before it is embedded or stored.
+pub trait ChunkTransform: Send + Sync {
+    fn apply(&self, chunk: &Chunk, text: &mut String);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TransformKind {
+    /// Replace likely secrets (keys, tokens, passwords, private keys) with [REDACTED]
+    Redact,
+    /// Drop whole-line comments; trailing comments are left alone
+    StripComments,
+    /// Trim trailing whitespace and collapse runs of blank lines
+    NormalizeWhitespace,
+    /// Prefix the text with the file path and line range
+    PrependContext,
+}
+
+impl TransformKind {
+    fn build(self) -> Box<dyn ChunkTransform> {
+        match self {
+            Self::Redact => Box::new(Redact),
+            Self::StripComments => Box::new(StripComments),
+            Self::NormalizeWhitespace => Box::new(NormalizeWhitespace),
+            Self::PrependContext => Box::new(PrependContext),
+        }
+    }
+}
+
+static SECRET_ASSIGNMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)\b((?:api[_-]?key|secret|token|passw(?:or)?d|access[_-]?key|private[_-]?key)\w*)(\s*[:=]\s*)(["']?)[^\s"',;]{4,}"#).unwrap()
+});
+static SECRET_TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b(?:AKIA[0-9A-Z]{16}|gh[pousr]_[A-Za-z0-9]{36,}|sk-[A-Za-z0-9_-]{20,}|xox[abpr]-[A-Za-z0-9-]{10,})\b").unwrap()
+});
+static PRIVATE_KEY_BLOCK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?s)-----BEGIN [A-Z ]*PRIVATE KEY-----.*?(?:-----END [A-Z ]*PRIVATE KEY-----|$)").unwrap()
+});
+
+struct Redact;
+
+impl ChunkTransform for Redact {
+    fn apply(&self, _chunk: &Chunk, text: &mut String) {
+        let redacted = PRIVATE_KEY_BLOCK.replace_all(text, "[REDACTED PRIVATE KEY]");
+        let redacted = SECRET_ASSIGNMENT.replace_all(&redacted, "${1}${2}${3}[REDACTED]");
+        let redacted = SECRET_TOKEN.replace_all(&redacted, "[REDACTED]");
+        *text = redacted.into_owned();
+    }
+}
+
+struct StripComments;
+
+impl ChunkTransform for StripComments {
+    fn apply(&self, chunk: &Chunk, text: &mut String) {
+        let language = detect_language(Path::new(&chunk.metadata.file_path), text);
+        let (slash, hash) = match language {
+            Some("python" | "shell" | "ruby" | "yaml" | "toml" | "perl" | "make" | "dockerfile" | "elixir" | "terraform" | "gdscript") => (false, true),
+            Some("css" | "scss") => (true, false),
+            Some("markdown" | "html" | "xml" | "json") | None => return,
+            Some(_) => (true, false),
+        };
+
+        let mut in_block = false;
+        let mut kept = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let trimmed = line.trim_start();
+            if in_block {
+                in_block = !trimmed.contains("*/");
+                continue;
+            }
+            if slash && trimmed.starts_with("/*") {
+                in_block = !trimmed.contains("*/");
+                continue;
+            }
+            if slash && trimmed.starts_with("//") {
+                continue;
+            }
+            if hash && trimmed.starts_with('#') && !(i == 0 && trimmed.starts_with("#!")) {
+                continue;
+            }
+            kept.push(line);
+        }
+        *text = kept.join("\n");
+    }
+}
+
+struct NormalizeWhitespace;
+
+impl ChunkTransform for NormalizeWhitespace {
+    fn apply(&self, _chunk: &Chunk, text: &mut String) {
+        let mut normalized = Vec::new();
+        let mut blank_run = false;
+        for line in text.lines().map(str::trim_end) {
+            if line.is_empty() {
+                if blank_run { continue; }
+                blank_run = true;
+            } else {
+                blank_run = false;
+            }
+            normalized.push(line);
+        }
+        *text = normalized.join("\n").trim().to_string();
+    }
+}
+
+struct PrependContext;
+
+impl ChunkTransform for PrependContext {
+    fn apply(&self, chunk: &Chunk, text: &mut String) {
+        let meta = &chunk.metadata;
+        *text = format!("File: {} (lines {}-{})\n\n{}", meta.file_path, meta.start_line, meta.end_line, text);
+    }
+}
+
+/// Ordered transforms for the embedded text and, separately, for the stored
+/// document. Both start from the raw chunk text.
+#[derive(Default)]
+pub struct TransformPipeline {
+    embed: Vec<Box<dyn ChunkTransform>>,
+    document: Vec<Box<dyn ChunkTransform>>,
+}
+
+impl TransformPipeline {
+    pub fn new(embed: &[TransformKind], document: &[TransformKind]) -> Self {
+        Self {
+            embed: embed.iter().map(|k| k.build()).collect(),
+            document: document.iter().map(|k| k.build()).collect(),
+        }
+    }
+
+    pub fn apply(&self, chunk: &mut Chunk) {
+        if self.embed.is_empty() && self.document.is_empty() {
+            return;
+        }
+
+        let mut document = chunk.text.clone();
+        for transform in &self.document {
+            transform.apply(chunk, &mut document);
+        }
+        let mut embed = chunk.embedding_input().to_string();
+        for transform in &self.embed {
+            transform.apply(chunk, &mut embed);
+        }
+
+        chunk.embed_text = (embed != document).then_some(embed);
+        chunk.text = document;
+    }
+}
+
+// ============================================================================
+// ChromaDB Client
+// ============================================================================
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChromaCollection {
+    id: String,
+    name: String,
+    #[serde(default)]
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default)]
+    configuration_json: Option<serde_json::Value>,
+}
+
+impl ChromaCollection {
+    /// The distance metric, from the metadata or, on newer servers that
+    /// drop HNSW keys from metadata, the collection configuration.
+    fn space(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .and_then(|m| m.get("hnsw:space"))
+            .or_else(|| self.configuration_json.as_ref()?.get("hnsw")?.get("space"))
+            .and_then(|v| v.as_str())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChromaUpsertRequest {
+    ids: Vec<String>,
+    embeddings: Vec<Vec<f32>>,
+    documents: Vec<String>,
+    metadatas: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChromaQueryRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r#where: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<usize>,
+    include: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChromaQueryResponse {
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChromaNearestResponse {
+    ids: Vec<Vec<String>>,
+    #[serde(default)]
+    documents: Option<Vec<Vec<Option<String>>>>,
+    #[serde(default)]
+    metadatas: Option<Vec<Vec<Option<serde_json::Value>>>>,
+    #[serde(default)]
+    distances: Option<Vec<Vec<Option<f32>>>>,
+}
+
+/// A page of stored records, as returned by `get` with documents and metadatas.
+#[derive(Debug, Deserialize)]
+pub struct ChromaRecords {
+    pub ids: Vec<String>,
+    #[serde(default)]
+    pub documents: Option<Vec<Option<String>>>,
+    #[serde(default)]
+    pub metadatas: Option<Vec<Option<serde_json::Value>>>,
+    #[serde(default)]
+    pub embeddings: Option<Vec<Vec<f32>>>,
+}
+
+/// Records read per request when listing indexed files.
+const INDEXED_FILES_PAGE_SIZE: usize = 1000;
+
+/// Records deleted or archived per request when cleaning up old commits.
+const CLEANUP_PAGE_SIZE: usize = 1000;
+
+/// A condition every chunk meets, for filters scoped only by branch/version.
+fn all_files_condition() -> serde_json::Value {
+    serde_json::json!({"file_path": {"$ne": ""}})
+}
+
+/// Metadata flag `--archive-old-commits` sets on chunks instead of deleting them.
+const ARCHIVED_KEY: &str = "archived";
+
+/// Unarchived chunks of `git_commit`.
+fn live_commit_condition(git_commit: &str) -> serde_json::Value {
+    serde_json::json!({"$and": [{"git_commit": {"$eq": git_commit}}, {ARCHIVED_KEY: {"$ne": true}}]})
+}
+
+/// Unarchived chunks of every commit but `live_commits` and their dirty variants.
+fn old_commits_condition(live_commits: &[&str]) -> serde_json::Value {
+    let live: Vec<String> = live_commits.iter().flat_map(|commit| [commit.to_string(), dirty_commit(commit)]).collect();
+    serde_json::json!({"$and": [
+        {"git_commit": {"$nin": live}},
+        {ARCHIVED_KEY: {"$ne": true}},
+    ]})
+}
+
+fn archived_condition() -> serde_json::Value {
+    serde_json::json!({ARCHIVED_KEY: {"$eq": true}})
+}
+
+/// Restricts `condition` to one branch and, when set, one version label, so
+/// cleanup for one version never touches another sharing the branch name.
+fn scope_filter(git_branch: &str, version: Option<&str>, condition: serde_json::Value) -> serde_json::Value {
+    let mut conditions = vec![serde_json::json!({"git_branch": {"$eq": git_branch}}), condition];
+    if let Some(version) = version {
+        conditions.push(serde_json::json!({"version": {"$eq": version}}));
+    }
+    serde_json::json!({ "$and": conditions })
+}
+
+/// API path of the database the indexer uses when a URL doesn't name one.
+const DEFAULT_TENANT: &str = "default_tenant";
+const DEFAULT_DATABASE: &str = "default_database";
+
+/// Checks a tenant or database name before it goes into a URL path, so a typo
+/// fails here rather than as a 404 from ChromaDB.
+fn validate_chroma_name(source: &str, name: &str) -> Result<()> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        anyhow::bail!("{} must be a non-empty name of letters, digits, '_', '-' and '.', got {:?}", source, name);
+    }
+    Ok(())
+}
+
+/// Scheme for `--store-scheme`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum UrlScheme {
+    Http,
+    Https,
+}
+
+impl UrlScheme {
+    fn name(self) -> &'static str {
+        match self {
+            UrlScheme::Http => "http",
+            UrlScheme::Https => "https",
+        }
+    }
+}
+
+/// Resolves the database base URL: `chroma_url` when given, otherwise built
+/// from `scheme`, `host` and `port`. A `chroma_url` without a path, and the
+/// host/port form, use `tenant` and `database` (or the defaults); a
+/// `chroma_url` with a path must be a full
+/// `/api/v2/tenants/<tenant>/databases/<database>` path and names its own.
+pub fn chroma_base_url(chroma_url: Option<&str>, scheme: UrlScheme, host: &str, port: &str, tenant: Option<&str>, database: Option<&str>) -> Result<String> {
+    let database_path = || -> Result<String> {
+        let tenant = tenant.unwrap_or(DEFAULT_TENANT);
+        let database = database.unwrap_or(DEFAULT_DATABASE);
+        validate_chroma_name("--chroma-tenant", tenant)?;
+        validate_chroma_name("--chroma-database", database)?;
+        Ok(format!("/api/v2/tenants/{}/databases/{}", tenant, database))
+    };
+    let Some(raw) = chroma_url else {
+        return Ok(format!("{}://{}:{}{}", scheme.name(), host, port, database_path()?));
+    };
+
+    let mut url = parse_service_url("--chroma-url", raw)?;
+    if url.path() == "/" {
+        url.set_path(&database_path()?);
+    } else if tenant.is_some() || database.is_some() {
+        anyhow::bail!("--chroma-url already names a tenant and database; drop --chroma-tenant and --chroma-database, or the URL's path");
+    }
+    let segments: Vec<&str> = url.path().trim_matches('/').split('/').collect();
+    let ["api", "v2", "tenants", tenant, "databases", database] = segments.as_slice() else {
+        anyhow::bail!("--chroma-url path must be /api/v2/tenants/<tenant>/databases/<database>, got {}", url.path());
+    };
+    validate_chroma_name("The --chroma-url tenant", tenant)?;
+    validate_chroma_name("The --chroma-url database", database)?;
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+/// Parses an http(s) service URL, naming `source` in the error.
+pub fn parse_service_url(source: &str, raw: &str) -> Result<reqwest::Url> {
+    let url = reqwest::Url::parse(raw).with_context(|| format!("{} is not a valid URL: {}", source, raw))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("{} must use http or https, got {}", source, raw);
+    }
+    if url.host_str().is_none() {
+        anyhow::bail!("{} has no host: {}", source, raw);
+    }
+    Ok(url)
+}
+
+/// Where and how to reach ChromaDB.
+#[derive(Clone)]
+pub struct ChromaEndpoint {
+    /// Database base URL, as returned by `chroma_base_url`
+    pub url: String,
+    pub timeout: Duration,
+    /// Sent as a bearer token with every request
+    pub token: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ChromaClient {
+    client: Client,
+    base_url: String,
+    collection_id: Option<String>,
+    collection_name: String,
+    /// Gzip upsert bodies
+    compress_uploads: bool,
+}
+
+impl ChromaClient {
+    /// Opens `collection_name`, creating it if needed. `creation_metadata` is
+    /// merged into the new collection's metadata and ignored when it already exists.
+    pub fn new(endpoint: &ChromaEndpoint, collection_name: &str, creation_metadata: &serde_json::Map<String, serde_json::Value>) -> Result<Self> {
+        let mut chroma = Self::connect(endpoint)?;
+        chroma.collection_name = collection_name.to_string();
+        chroma.get_or_create_collection(creation_metadata)?;
+        Ok(chroma)
+    }
+
+    /// Opens an existing collection without creating it.
+    pub fn open(endpoint: &ChromaEndpoint, collection_name: &str) -> Result<Self> {
+        let mut chroma = Self::connect(endpoint)?;
+        let collection = chroma
+            .fetch_collections()?
+            .into_iter()
+            .find(|c| c.name == collection_name)
+            .with_context(|| format!("Collection {} does not exist", collection_name))?;
+        chroma.collection_id = Some(collection.id);
+        chroma.collection_name = collection.name;
+        Ok(chroma)
+    }
+
+    /// A client for `name` on the same server, created if needed.
+    pub fn sibling(&self, name: &str, creation_metadata: &serde_json::Map<String, serde_json::Value>) -> Result<Self> {
+        let mut chroma = Self { collection_id: None, collection_name: name.to_string(), ..self.clone() };
+        chroma.get_or_create_collection(creation_metadata)?;
+        Ok(chroma)
+    }
+
+    /// A client for database-level calls that aren't tied to one collection.
+    pub fn connect(endpoint: &ChromaEndpoint) -> Result<Self> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(token) = &endpoint.token {
+            let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+                .context("The ChromaDB token isn't a valid header value")?;
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
+        let client = http_client_with_headers(endpoint.timeout, headers)?;
+        let base_url = endpoint.url.trim_end_matches('/').to_string();
+
+        Ok(Self {
+            client,
+            base_url,
+            collection_id: None,
+            collection_name: String::new(),
+            compress_uploads: false,
+        })
+    }
+
+    pub fn list_collections(&self) -> Result<Vec<String>> {
+        Ok(self.fetch_collections()?.into_iter().map(|c| c.name).collect())
+    }
+
+    /// Raw collection record, including its metadata and dimension.
+    pub fn collection_info(&self, name: &str) -> Result<serde_json::Value> {
+        let url = format!("{}/collections/{}", self.base_url, name);
+        let response = self.client.get(&url).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Collection {} not found: {}", name, response.status());
+        }
+        Ok(response.json()?)
+    }
+
+    /// Merges `updates` into this collection's metadata. HNSW keys are left out
+    /// of the update because Chroma refuses to change them after creation.
+    pub fn update_metadata(&self, updates: serde_json::Map<String, serde_json::Value>) -> Result<()> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let info = self.collection_info(&self.collection_name)?;
+
+        let mut metadata: serde_json::Map<String, serde_json::Value> = info
+            .get("metadata")
+            .and_then(|m| m.as_object())
+            .cloned()
+            .unwrap_or_default();
+        metadata.retain(|key, _| !key.starts_with("hnsw:"));
+        metadata.extend(updates);
+
+        let url = format!("{}/collections/{}", self.base_url, collection_id);
+        let response = self.client.put(&url).json(&serde_json::json!({ "new_metadata": metadata })).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to update collection metadata: {}", response.text().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// A single metadata value stored on this collection.
+    pub fn metadata_value(&self, key: &str) -> Option<serde_json::Value> {
+        let info = self.collection_info(&self.collection_name).ok()?;
+        info.get("metadata")?.get(key).cloned()
+    }
+
+    /// Length of the stored vectors; ChromaDB fixes it on the first upload.
+    pub fn dimension(&self) -> Result<Option<usize>> {
+        let info = self.collection_info(&self.collection_name)?;
+        Ok(info.get("dimension").and_then(|d| d.as_u64()).map(|d| d as usize))
+    }
+
+    pub fn delete_collection(&self, name: &str) -> Result<()> {
+        let url = format!("{}/collections/{}", self.base_url, name);
+        let response = self.client.delete(&url).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to delete collection {}: {}", name, response.text().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// Lists collections, retrying transient failures so an unreachable server
+    /// is never mistaken for a missing collection.
+    fn fetch_collections(&self) -> Result<Vec<ChromaCollection>> {
+        let url = format!("{}/collections", self.base_url);
+        let mut last_error = anyhow::anyhow!("no attempts made");
+
+        for attempt in 0..COLLECTION_LIST_RETRIES {
+            if attempt > 0 {
+                std::thread::sleep(std::time::Duration::from_secs(1 << (attempt - 1)));
+            }
+            match self.client.get(&url).send() {
+                Ok(resp) if resp.status().is_success() => {
+                    return resp.json().context("Invalid collection list from ChromaDB");
+                }
+                Ok(resp) if resp.status().is_server_error() => {
+                    last_error = resp.error_for_status().err().map_or_else(|| anyhow::anyhow!("server error"), Into::into)
+                }
+                Ok(resp) if resp.status() == reqwest::StatusCode::NOT_FOUND => {
+                    anyhow::bail!("Failed to list collections: {} (does the tenant and database in {} exist?)", resp.status(), self.base_url)
+                }
+                Ok(resp) => anyhow::bail!("Failed to list collections: {}", resp.status()),
+                Err(err) => last_error = err.into(),
+            }
+        }
+
+        Err(last_error.context(format!("Could not list collections after {} attempts", COLLECTION_LIST_RETRIES)))
+    }
+
+    fn get_or_create_collection(&mut self, creation_metadata: &serde_json::Map<String, serde_json::Value>) -> Result<()> {
+        let url = format!("{}/collections", self.base_url);
+
+        for collection in self.fetch_collections()? {
+            if collection.name == self.collection_name {
+                self.collection_id = Some(collection.id.clone());
+                let stored = serde_json::Value::Object(collection.metadata.clone().unwrap_or_default());
+                info!("Using existing collection: {} (metadata: {})", self.collection_name, stored);
+                let requested = creation_metadata.get("hnsw:space").and_then(|v| v.as_str());
+                if let (Some(requested), Some(existing)) = (requested, collection.space()) {
+                    if requested != existing {
+                        warn!(
+                            "{} already uses {} distance; ignoring the requested {} (it can't be changed after creation)",
+                            self.collection_name, existing, requested
+                        );
+                    }
+                }
+                return Ok(());
+            }
+        }
+
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("hnsw:space".to_string(), "cosine".into());
+        metadata.extend(creation_metadata.clone());
+
+        let body = serde_json::json!({
+            "name": self.collection_name,
+            "metadata": metadata
+        });
+
+        let response = self.client.post(&url).json(&body).send()?;
+        if response.status().is_success() {
+            let collection: ChromaCollection = response.json()?;
+            self.collection_id = Some(collection.id);
+            info!("Created new collection: {}", self.collection_name);
+        } else {
+            anyhow::bail!("Failed to create collection");
+        }
+
+        Ok(())
+    }
+
+    /// Writes `chunks`, replacing the document, embedding and metadata of any
+    /// chunk whose id is already stored.
+    pub fn add_chunks(&self, chunks: &[Chunk], embeddings: Vec<Vec<f32>>) -> Result<()> {
+        self.upsert_records(ChromaUpsertRequest {
+            ids: chunks.iter().map(|c| c.id.clone()).collect(),
+            embeddings,
+            documents: chunks.iter().map(|c| c.text.clone()).collect(),
+            metadatas: chunks.iter().map(|c| serde_json::to_value(&c.metadata).unwrap()).collect(),
+        })
+    }
+
+    /// Gzips upsert bodies, which are mostly chunk text and compress well, to
+    /// stay under request size limits.
+    pub fn with_compressed_uploads(mut self, compress_uploads: bool) -> Self {
+        self.compress_uploads = compress_uploads;
+        self
+    }
+
+    fn upsert_records(&self, request: ChromaUpsertRequest) -> Result<()> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/upsert", self.base_url, collection_id);
+
+        let body = serde_json::to_vec(&request)?;
+        let builder = self.client.post(&url).header(reqwest::header::CONTENT_TYPE, "application/json");
+        let response = if self.compress_uploads {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(&body)?;
+            let compressed = encoder.finish()?;
+            debug!(collection = %self.collection_name, bytes = body.len(), compressed = compressed.len(), "compressed upload");
+            builder.header(reqwest::header::CONTENT_ENCODING, "gzip").body(compressed).send()?
+        } else {
+            builder.body(body).send()?
+        };
+        if !response.status().is_success() {
+            let error_text = response.text().unwrap_or_default();
+            anyhow::bail!("Failed to upsert chunks: {}", error_text);
+        }
+
+        Ok(())
+    }
+
+    /// One page of stored records with the fields named in `include`
+    /// (`documents`, `metadatas`, `embeddings`).
+    pub fn get_records(&self, offset: usize, limit: usize, include: &[&str]) -> Result<ChromaRecords> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/get", self.base_url, collection_id);
+
+        let request = ChromaQueryRequest {
+            r#where: None,
+            limit: Some(limit),
+            offset: Some(offset),
+            include: include.iter().map(|field| field.to_string()).collect(),
+        };
+
+        let response = self.client.post(&url).json(&request).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to read records: {}", response.text().unwrap_or_default());
+        }
+        Ok(response.json()?)
+    }
+
+    /// The `n_results` nearest stored chunks to `embedding`, closest first.
+    pub fn nearest(&self, embedding: &[f32], n_results: usize) -> Result<Vec<SearchHit>> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/query", self.base_url, collection_id);
+
+        let body = serde_json::json!({
+            "query_embeddings": [embedding],
+            "n_results": n_results,
+            "include": ["documents", "metadatas", "distances"],
+        });
+
+        let response = self.client.post(&url).json(&body).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Query on {} failed: {}", self.collection_name, response.text().unwrap_or_default());
+        }
+        let result: ChromaNearestResponse = response.json()?;
+
+        let ids = result.ids.into_iter().next().unwrap_or_default();
+        let documents = result.documents.and_then(|d| d.into_iter().next()).unwrap_or_default();
+        let metadatas = result.metadatas.and_then(|m| m.into_iter().next()).unwrap_or_default();
+        let distances = result.distances.and_then(|d| d.into_iter().next()).unwrap_or_default();
+
+        Ok(ids
+            .into_iter()
+            .enumerate()
+            .map(|(i, id)| {
+                let metadata = metadatas.get(i).cloned().flatten().unwrap_or_default();
+                let line = |key: &str| metadata.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+                SearchHit {
+                    collection: self.collection_name.clone(),
+                    id,
+                    file_path: metadata.get("file_path").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+                    start_line: line("start_line"),
+                    end_line: line("end_line"),
+                    document: documents.get(i).cloned().flatten().unwrap_or_default(),
+                    distance: distances.get(i).copied().flatten().unwrap_or(f32::INFINITY),
+                    score: 0.0,
+                }
+            })
+            .collect())
+    }
+
+    pub fn is_commit_indexed(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> bool {
+        let Some(collection_id) = &self.collection_id else { return false };
+        let url = format!("{}/collections/{}/get", self.base_url, collection_id);
+
+        let request = ChromaQueryRequest {
+            r#where: Some(scope_filter(git_branch, version, live_commit_condition(git_commit))),
+            limit: Some(1),
+            offset: None,
+            include: vec![],
+        };
+
+        if let Ok(resp) = self.client.post(&url).json(&request).send() {
+            if let Ok(result) = resp.json::<ChromaQueryResponse>() {
+                return !result.ids.is_empty();
+            }
+        }
+        false
+    }
+
+    pub fn branch_schema(&self, git_branch: &str, version: Option<&str>) -> Result<Option<u64>> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/get", self.base_url, collection_id);
+        let request = ChromaQueryRequest {
+            r#where: Some(scope_filter(git_branch, version, all_files_condition())),
+            limit: Some(1),
+            offset: None,
+            include: vec!["metadatas".to_string()],
+        };
+        let response = self.client.post(&url).json(&request).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to read a chunk of {}: {}", git_branch, response.text().unwrap_or_default());
+        }
+        let page: ChromaRecords = response.json()?;
+        let metadata = page.metadatas.unwrap_or_default().into_iter().flatten().next();
+        Ok(metadata.map(|m| schema_version_of(&m)))
+    }
+
+    pub fn delete_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize> {
+        self.delete_matching(scope_filter(git_branch, version, old_commits_condition(live_commits)))
+    }
+
+    /// Deletes the chunks `--archive-old-commits` kept on this branch/version.
+    pub fn purge_archived(&self, git_branch: &str, version: Option<&str>) -> Result<usize> {
+        self.delete_matching(scope_filter(git_branch, version, archived_condition()))
+    }
+
+    /// Deletes every record matching `filter`, returning how many.
+    fn delete_matching(&self, filter: serde_json::Value) -> Result<usize> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let delete_url = format!("{}/collections/{}/delete", self.base_url, collection_id);
+        let mut deleted = 0;
+        let mut seen = HashSet::new();
+        loop {
+            // Deleted records stop matching, so each page starts from the top
+            let page = self.cleanup_page(&filter, vec![], &mut seen)?;
+            if page.ids.is_empty() {
+                return Ok(deleted);
+            }
+            let response = self.client.post(&delete_url).json(&serde_json::json!({ "ids": page.ids })).send()?;
+            if !response.status().is_success() {
+                anyhow::bail!("Failed to delete old chunks: {}", response.text().unwrap_or_default());
+            }
+            deleted += page.ids.len();
+        }
+    }
+
+    /// The first `CLEANUP_PAGE_SIZE` records matching `filter`. Fails if one
+    /// was already in `seen`, which means the last delete or update didn't
+    /// take and another pass would loop forever.
+    fn cleanup_page(&self, filter: &serde_json::Value, include: Vec<String>, seen: &mut HashSet<String>) -> Result<ChromaRecords> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/get", self.base_url, collection_id);
+        let request = ChromaQueryRequest {
+            r#where: Some(filter.clone()),
+            limit: Some(CLEANUP_PAGE_SIZE),
+            offset: None,
+            include,
+        };
+        let response = self.client.post(&url).json(&request).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to find old chunks: {}", response.text().unwrap_or_default());
+        }
+        let page: ChromaRecords = response.json()?;
+        for id in &page.ids {
+            if !seen.insert(id.clone()) {
+                anyhow::bail!("Chunk {} still matches after cleanup; ChromaDB didn't apply the change", id);
+            }
+        }
+        Ok(page)
+    }
+
+    /// Tags chunks from every commit but `live_commits` as archived rather
+    /// than deleting them. The full metadata is sent back, so nothing depends
+    /// on whether the server merges metadata on update.
+    pub fn archive_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let update_url = format!("{}/collections/{}/update", self.base_url, collection_id);
+        let filter = scope_filter(git_branch, version, old_commits_condition(live_commits));
+        let mut archived = 0;
+        let mut seen = HashSet::new();
+        loop {
+            // Archived records stop matching, so each page starts from the top
+            let page = self.cleanup_page(&filter, vec!["metadatas".to_string()], &mut seen)?;
+            if page.ids.is_empty() {
+                return Ok(archived);
+            }
+            let mut metadatas: Vec<serde_json::Value> = page.metadatas.unwrap_or_default().into_iter().map(Option::unwrap_or_default).collect();
+            for metadata in &mut metadatas {
+                metadata[ARCHIVED_KEY] = true.into();
+            }
+            let body = serde_json::json!({ "ids": page.ids, "metadatas": metadatas });
+            let response = self.client.post(&update_url).json(&body).send()?;
+            if !response.status().is_success() {
+                anyhow::bail!("Failed to archive old chunks: {}", response.text().unwrap_or_default());
+            }
+            archived += page.ids.len();
+        }
+    }
+
+    /// Every `file_path` with chunks on this branch/version.
+    pub fn indexed_files(&self, git_branch: &str, version: Option<&str>) -> Result<HashSet<String>> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/get", self.base_url, collection_id);
+        let mut files = HashSet::new();
+        let mut offset = 0;
+        loop {
+            let request = ChromaQueryRequest {
+                r#where: Some(scope_filter(git_branch, version, all_files_condition())),
+                limit: Some(INDEXED_FILES_PAGE_SIZE),
+                offset: Some(offset),
+                include: vec!["metadatas".to_string()],
+            };
+            let response = self.client.post(&url).json(&request).send()?;
+            if !response.status().is_success() {
+                anyhow::bail!("Failed to list indexed files: {}", response.text().unwrap_or_default());
+            }
+            let page: ChromaRecords = response.json()?;
+            if page.ids.is_empty() {
+                break;
+            }
+            offset += page.ids.len();
+            files.extend(page.metadatas.unwrap_or_default().iter().flatten().filter_map(|m| m["file_path"].as_str()).map(str::to_string));
+        }
+        Ok(files)
+    }
+
+    pub fn delete_file(&self, git_branch: &str, version: Option<&str>, file_path: &str) -> Result<()> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/delete", self.base_url, collection_id);
+        let body = serde_json::json!({
+            "where": scope_filter(git_branch, version, serde_json::json!({"file_path": {"$eq": file_path}}))
+        });
+
+        let response = self.client.post(&url).json(&body).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to delete chunks for {}: {}", file_path, response.text().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// Deletes every chunk on this branch/version in one request.
+    pub fn delete_branch(&self, git_branch: &str, version: Option<&str>) -> Result<()> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/delete", self.base_url, collection_id);
+        let body = serde_json::json!({ "where": scope_filter(git_branch, version, all_files_condition()) });
+
+        let response = self.client.post(&url).json(&body).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to delete chunks of {}: {}", git_branch, response.text().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    /// Deletes the chunks of `file_path` whose id isn't in `keep`.
+    pub fn delete_file_except(&self, git_branch: &str, version: Option<&str>, file_path: &str, keep: &HashSet<String>) -> Result<()> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/get", self.base_url, collection_id);
+        let filter = scope_filter(git_branch, version, serde_json::json!({"file_path": {"$eq": file_path}}));
+        let mut stale = Vec::new();
+        let mut offset = 0;
+        loop {
+            let request = ChromaQueryRequest { r#where: Some(filter.clone()), limit: Some(INDEXED_FILES_PAGE_SIZE), offset: Some(offset), include: vec![] };
+            let response = self.client.post(&url).json(&request).send()?;
+            if !response.status().is_success() {
+                anyhow::bail!("Failed to list chunks for {}: {}", file_path, response.text().unwrap_or_default());
+            }
+            let page: ChromaRecords = response.json()?;
+            if page.ids.is_empty() {
+                break;
+            }
+            offset += page.ids.len();
+            stale.extend(page.ids.into_iter().filter(|id| !keep.contains(id)));
+        }
+
+        let delete_url = format!("{}/collections/{}/delete", self.base_url, collection_id);
+        for ids in stale.chunks(CLEANUP_PAGE_SIZE) {
+            let response = self.client.post(&delete_url).json(&serde_json::json!({ "ids": ids })).send()?;
+            if !response.status().is_success() {
+                anyhow::bail!("Failed to delete chunks for {}: {}", file_path, response.text().unwrap_or_default());
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the chunks tagged as uncommitted changes on top of `git_commit`.
+    pub fn delete_dirty(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> Result<()> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/delete", self.base_url, collection_id);
+        let body = serde_json::json!({
+            "where": scope_filter(git_branch, version, serde_json::json!({"git_commit": {"$eq": dirty_commit(git_commit)}}))
+        });
+
+        let response = self.client.post(&url).json(&body).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to delete uncommitted chunks: {}", response.text().unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    pub fn count(&self) -> Result<usize> {
+        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
+        let url = format!("{}/collections/{}/count", self.base_url, collection_id);
+        let response = self.client.get(&url).send()?;
+        if !response.status().is_success() {
+            anyhow::bail!("Failed to count {}: {}", self.collection_name, response.status());
+        }
+        Ok(response.json()?)
+    }
+
+    /// `count` with up to `retries` extra attempts; `None` if every attempt failed.
+    pub fn count_with_retries(&self, retries: usize) -> Option<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.count() {
+                Ok(count) => return Some(count),
+                Err(err) if attempt >= retries => {
+                    warn!("could not count {}: {}", self.collection_name, err);
+                    return None;
+                }
+                Err(_) => {
+                    attempt += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(500 * attempt as u64));
+                }
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Sharding
+// ============================================================================
+
+/// Metadata key on the base collection naming the shard currently written to.
+const ACTIVE_SHARD_KEY: &str = "active_shard";
+
+pub fn shard_collection_name(base: &str, shard: usize) -> String {
+    format!("{}_{:03}", base, shard)
+}
+
+/// The base collection name if `name` is a numbered shard (`{base}_NNN`).
+pub fn shard_base(name: &str) -> Option<&str> {
+    let (base, suffix) = name.rsplit_once('_')?;
+    (suffix.len() >= 3 && suffix.bytes().all(|b| b.is_ascii_digit())).then_some(base)
+}
+
+/// `base` followed by its numbered shards, in shard order, out of `all` collection names.
+pub fn with_shards(base: &str, all: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = all.iter().filter(|name| shard_base(name) == Some(base)).cloned().collect();
+    names.sort();
+    names.insert(0, base.to_string());
+    names
+}
+
+struct ShardState {
+    shards: Vec<ChromaClient>,
+    /// Chunks in the last shard, tracked locally so adds don't re-count
+    active_count: usize,
+}
+
+/// A logical collection spread over `{name}`, `{name}_001`, `{name}_002`, ...
+/// Adds go to the last shard and roll over to a new one once it would exceed
+/// `max_chunks`; lookups and deletes cover every shard.
+#[derive(Clone)]
+pub struct ShardedCollection {
+    state: Arc<Mutex<ShardState>>,
+    max_chunks: Option<usize>,
+    creation_metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ShardedCollection {
+    pub fn open(base: ChromaClient, max_chunks: Option<usize>, creation_metadata: &serde_json::Map<String, serde_json::Value>) -> Result<Self> {
+        let names = with_shards(&base.collection_name, &base.list_collections()?);
+        let mut shards = vec![base];
+        for name in &names[1..] {
+            shards.push(shards[0].sibling(name, creation_metadata)?);
+        }
+        let active_count = match max_chunks {
+            Some(_) => shards.last().map(ChromaClient::count).transpose()?.unwrap_or(0),
+            None => 0,
+        };
+        Ok(Self {
+            state: Arc::new(Mutex::new(ShardState { shards, active_count })),
+            max_chunks,
+            creation_metadata: creation_metadata.clone(),
+        })
+    }
+
+    fn shards(&self) -> Vec<ChromaClient> {
+        self.state.lock().unwrap().shards.clone()
+    }
+
+    fn base(&self) -> ChromaClient {
+        self.state.lock().unwrap().shards[0].clone()
+    }
+}
+
+impl VectorStore for ShardedCollection {
+    fn add_chunks(&self, chunks: &[Chunk], embeddings: Vec<Vec<f32>>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(max) = self.max_chunks {
+            if state.active_count > 0 && state.active_count + chunks.len() > max {
+                let base = state.shards[0].clone();
+                let shard = state.shards.len();
+                let next = base.sibling(&shard_collection_name(&base.collection_name, shard), &self.creation_metadata)?;
+                info!("{} reached {} chunks; continuing in {}", base.collection_name, max, next.collection_name);
+                let mut updates = serde_json::Map::new();
+                updates.insert(ACTIVE_SHARD_KEY.to_string(), next.collection_name.clone().into());
+                base.update_metadata(updates)?;
+                state.shards.push(next);
+                state.active_count = 0;
+            }
+        }
+        state.shards.last().context("No shards")?.add_chunks(chunks, embeddings)?;
+        state.active_count += chunks.len();
+        Ok(())
+    }
+
+    fn is_commit_indexed(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> bool {
+        self.shards().iter().any(|shard| shard.is_commit_indexed(git_branch, version, git_commit))
+    }
+
+    fn branch_schema(&self, git_branch: &str, version: Option<&str>) -> Result<Option<u64>> {
+        for shard in self.shards() {
+            if let Some(schema) = shard.branch_schema(git_branch, version)? {
+                return Ok(Some(schema));
+            }
+        }
+        Ok(None)
+    }
+
+    fn dimension(&self) -> Result<Option<usize>> {
+        for shard in self.shards() {
+            if let Some(dimension) = shard.dimension()? {
+                return Ok(Some(dimension));
+            }
+        }
+        Ok(None)
+    }
+
+    fn delete_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize> {
+        let mut deleted = 0;
+        for shard in self.shards() {
+            deleted += shard.delete_old_commits(git_branch, version, live_commits)?;
+        }
+        Ok(deleted)
+    }
+
+    fn archive_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize> {
+        let mut archived = 0;
+        for shard in self.shards() {
+            archived += shard.archive_old_commits(git_branch, version, live_commits)?;
+        }
+        Ok(archived)
+    }
+
+    fn purge_archived(&self, git_branch: &str, version: Option<&str>) -> Result<usize> {
+        let mut purged = 0;
+        for shard in self.shards() {
+            purged += shard.purge_archived(git_branch, version)?;
+        }
+        Ok(purged)
+    }
+
+    fn indexed_files(&self, git_branch: &str, version: Option<&str>) -> Result<HashSet<String>> {
+        let mut files = HashSet::new();
+        for shard in self.shards() {
+            files.extend(shard.indexed_files(git_branch, version)?);
+        }
+        Ok(files)
+    }
+
+    fn delete_file(&self, git_branch: &str, version: Option<&str>, file_path: &str) -> Result<()> {
+        for shard in self.shards() {
+            shard.delete_file(git_branch, version, file_path)?;
+        }
+        Ok(())
+    }
+
+    fn delete_branch(&self, git_branch: &str, version: Option<&str>) -> Result<()> {
+        for shard in self.shards() {
+            shard.delete_branch(git_branch, version)?;
+        }
+        Ok(())
+    }
+
+    fn delete_file_except(&self, git_branch: &str, version: Option<&str>, file_path: &str, keep: &HashSet<String>) -> Result<()> {
+        for shard in self.shards() {
+            shard.delete_file_except(git_branch, version, file_path, keep)?;
+        }
+        Ok(())
+    }
+
+    fn delete_dirty(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> Result<()> {
+        for shard in self.shards() {
+            shard.delete_dirty(git_branch, version, git_commit)?;
+        }
+        Ok(())
+    }
+
+    fn count_with_retries(&self, retries: usize) -> Option<usize> {
+        self.shards().iter().map(|shard| shard.count_with_retries(retries)).sum()
+    }
+
+    fn metadata_value(&self, key: &str) -> Option<serde_json::Value> {
+        self.base().metadata_value(key)
+    }
+
+    fn update_metadata(&self, updates: serde_json::Map<String, serde_json::Value>) -> Result<()> {
+        self.base().update_metadata(updates)
+    }
+}
+
+// ============================================================================
+// Vector Stores
+// ============================================================================
+
+/// Backend for `--store`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum StoreKind {
+    /// ChromaDB, sharded when --collection-max-chunks is set
+    Chroma,
+    /// An in-process map that is discarded on exit
+    Memory,
+    /// Qdrant at --qdrant-url
+    Qdrant,
+}
+
+/// Distance metric for `--distance`, stored as `hnsw:space` on new collections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Distance {
+    /// Cosine distance, for embeddings of any norm
+    Cosine,
+    /// Squared Euclidean distance
+    L2,
+    /// Inner product, for normalized embeddings
+    Ip,
+}
+
+impl Distance {
+    pub fn hnsw_space(self) -> &'static str {
+        match self {
+            Distance::Cosine => "cosine",
+            Distance::L2 => "l2",
+            Distance::Ip => "ip",
+        }
+    }
+}
+
+/// Where indexed chunks are written. Scoped calls take the branch and optional
+/// version label and only touch chunks tagged with them.
+pub trait VectorStore: Send + Sync {
+    fn add_chunks(&self, chunks: &[Chunk], embeddings: Vec<Vec<f32>>) -> Result<()>;
+    fn is_commit_indexed(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> bool;
+    /// `SCHEMA_VERSION` of some chunk on this branch/version, or `None` if it has none.
+    fn branch_schema(&self, git_branch: &str, version: Option<&str>) -> Result<Option<u64>>;
+    /// Length of the vectors already stored, or `None` while the store is empty.
+    fn dimension(&self) -> Result<Option<usize>>;
+    /// Removes chunks from every commit but `live_commits` and their dirty
+    /// variants. Archived chunks are left for `purge_archived`.
+    fn delete_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize>;
+    /// Tags the chunks `delete_old_commits` would remove as `archived` instead.
+    fn archive_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize>;
+    /// Removes chunks tagged as `archived`.
+    fn purge_archived(&self, git_branch: &str, version: Option<&str>) -> Result<usize>;
+    /// Every `file_path` with chunks on this branch/version.
+    fn indexed_files(&self, git_branch: &str, version: Option<&str>) -> Result<HashSet<String>>;
+    fn delete_file(&self, git_branch: &str, version: Option<&str>, file_path: &str) -> Result<()>;
+    /// Removes every chunk on this branch/version.
+    fn delete_branch(&self, git_branch: &str, version: Option<&str>) -> Result<()>;
+    /// Removes the chunks of `file_path` whose id isn't in `keep`.
+    fn delete_file_except(&self, git_branch: &str, version: Option<&str>, file_path: &str, keep: &HashSet<String>) -> Result<()>;
+    fn delete_dirty(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> Result<()>;
+    fn count_with_retries(&self, retries: usize) -> Option<usize>;
+    fn metadata_value(&self, key: &str) -> Option<serde_json::Value>;
+    fn update_metadata(&self, updates: serde_json::Map<String, serde_json::Value>) -> Result<()>;
+}
+
+/// Settings `open_stores` applies to every store it opens.
+pub struct StoreOptions {
+    /// Time limit for each request to the store
+    pub timeout: Duration,
+    /// Roll Chroma collections over to a new shard at this many chunks
+    pub collection_max_chunks: Option<usize>,
+    /// Gzip Chroma upserts
+    pub compress_uploads: bool,
+    /// Write chunks and vectors to this JSONL file instead of a vector store
+    pub output_embeddings: Option<PathBuf>,
+}
+
+/// Opens one store per routed collection.
+fn open_stores(
+    kind: StoreKind,
+    chroma: &ChromaEndpoint,
+    qdrant_url: &str,
+    router: &CollectionRouter,
+    collection_metadata: &serde_json::Map<String, serde_json::Value>,
+    options: &StoreOptions,
+) -> Result<BTreeMap<String, Arc<dyn VectorStore>>> {
+    let mut stores: BTreeMap<String, Arc<dyn VectorStore>> = BTreeMap::new();
+    if let Some(path) = &options.output_embeddings {
+        info!("Writing embeddings to {}; no vector store is used", path.display());
+        let file = fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let out = Arc::new(Mutex::new(std::io::BufWriter::new(file)));
+        for name in router.collections() {
+            stores.insert(name.to_string(), Arc::new(EmbeddingsFile::new(name, Arc::clone(&out), collection_metadata)));
+        }
+        return Ok(stores);
+    }
+    match kind {
+        StoreKind::Chroma => {
+            info!("Connecting to ChromaDB at {}...", chroma.url);
+            for name in router.collections() {
+                let base = ChromaClient::new(chroma, name, collection_metadata)?.with_compressed_uploads(options.compress_uploads);
+                stores.insert(name.to_string(), Arc::new(ShardedCollection::open(base, options.collection_max_chunks, collection_metadata)?));
+            }
+        }
+        StoreKind::Memory => {
+            info!("Using an in-memory store; the index is discarded on exit");
+            for name in router.collections() {
+                stores.insert(name.to_string(), Arc::new(InMemoryStore::new(collection_metadata)));
+            }
+        }
+        StoreKind::Qdrant => {
+            if options.collection_max_chunks.is_some() {
+                anyhow::bail!("--collection-max-chunks is only supported with --store chroma");
+            }
+            info!("Connecting to Qdrant at {}...", qdrant_url);
+            for name in router.collections() {
+                stores.insert(name.to_string(), Arc::new(QdrantStore::open(qdrant_url, name, collection_metadata, options.timeout)?));
+            }
+        }
+    }
+    Ok(stores)
+}
+
+struct StoredRecord {
+    metadata: serde_json::Value,
+    #[allow(dead_code)]
+    document: String,
+    embedding: Vec<f32>,
+}
+
+/// Keeps chunks in a map and evaluates the same `where` filters the ChromaDB
+/// client sends, so the full pipeline runs without any external service.
+pub struct InMemoryStore {
+    records: Mutex<HashMap<String, StoredRecord>>,
+    metadata: Mutex<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl InMemoryStore {
+    pub fn new(creation_metadata: &serde_json::Map<String, serde_json::Value>) -> Self {
+        Self { records: Mutex::new(HashMap::new()), metadata: Mutex::new(creation_metadata.clone()) }
+    }
+
+    fn delete_where(&self, filter: &serde_json::Value) -> usize {
+        let mut records = self.records.lock().unwrap();
+        let before = records.len();
+        records.retain(|_, record| !where_matches(&record.metadata, filter));
+        before - records.len()
+    }
+}
+
+impl VectorStore for InMemoryStore {
+    fn add_chunks(&self, chunks: &[Chunk], embeddings: Vec<Vec<f32>>) -> Result<()> {
+        if chunks.len() != embeddings.len() {
+            anyhow::bail!("Got {} embeddings for {} chunks", embeddings.len(), chunks.len());
+        }
+        let mut records = self.records.lock().unwrap();
+        for (chunk, embedding) in chunks.iter().zip(embeddings) {
+            let record = StoredRecord { metadata: serde_json::to_value(&chunk.metadata)?, document: chunk.text.clone(), embedding };
+            records.insert(chunk.id.clone(), record);
+        }
+        Ok(())
+    }
+
+    fn is_commit_indexed(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> bool {
+        let filter = scope_filter(git_branch, version, live_commit_condition(git_commit));
+        self.records.lock().unwrap().values().any(|record| where_matches(&record.metadata, &filter))
+    }
+
+    fn branch_schema(&self, git_branch: &str, version: Option<&str>) -> Result<Option<u64>> {
+        let filter = scope_filter(git_branch, version, all_files_condition());
+        let records = self.records.lock().unwrap();
+        Ok(records.values().find(|record| where_matches(&record.metadata, &filter)).map(|record| schema_version_of(&record.metadata)))
+    }
+
+    fn dimension(&self) -> Result<Option<usize>> {
+        Ok(self.records.lock().unwrap().values().next().map(|record| record.embedding.len()))
+    }
+
+    fn delete_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize> {
+        Ok(self.delete_where(&scope_filter(git_branch, version, old_commits_condition(live_commits))))
+    }
+
+    fn archive_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize> {
+        let filter = scope_filter(git_branch, version, old_commits_condition(live_commits));
+        let mut archived = 0;
+        for record in self.records.lock().unwrap().values_mut() {
+            if where_matches(&record.metadata, &filter) {
+                record.metadata[ARCHIVED_KEY] = true.into();
+                archived += 1;
+            }
+        }
+        Ok(archived)
+    }
+
+    fn purge_archived(&self, git_branch: &str, version: Option<&str>) -> Result<usize> {
+        Ok(self.delete_where(&scope_filter(git_branch, version, archived_condition())))
+    }
+
+    fn indexed_files(&self, git_branch: &str, version: Option<&str>) -> Result<HashSet<String>> {
+        let filter = scope_filter(git_branch, version, all_files_condition());
+        let records = self.records.lock().unwrap();
+        Ok(records
+            .values()
+            .filter(|record| where_matches(&record.metadata, &filter))
+            .filter_map(|record| record.metadata["file_path"].as_str().map(str::to_string))
+            .collect())
+    }
+
+    fn delete_file(&self, git_branch: &str, version: Option<&str>, file_path: &str) -> Result<()> {
+        self.delete_where(&scope_filter(git_branch, version, serde_json::json!({"file_path": {"$eq": file_path}})));
+        Ok(())
+    }
+
+    fn delete_branch(&self, git_branch: &str, version: Option<&str>) -> Result<()> {
+        self.delete_where(&scope_filter(git_branch, version, all_files_condition()));
+        Ok(())
+    }
+
+    fn delete_file_except(&self, git_branch: &str, version: Option<&str>, file_path: &str, keep: &HashSet<String>) -> Result<()> {
+        let filter = scope_filter(git_branch, version, serde_json::json!({"file_path": {"$eq": file_path}}));
+        self.records.lock().unwrap().retain(|id, record| keep.contains(id) || !where_matches(&record.metadata, &filter));
+        Ok(())
+    }
+
+    fn delete_dirty(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> Result<()> {
+        self.delete_where(&scope_filter(git_branch, version, serde_json::json!({"git_commit": {"$eq": dirty_commit(git_commit)}})));
+        Ok(())
+    }
+
+    fn count_with_retries(&self, _retries: usize) -> Option<usize> {
+        Some(self.records.lock().unwrap().len())
+    }
+
+    fn metadata_value(&self, key: &str) -> Option<serde_json::Value> {
+        self.metadata.lock().unwrap().get(key).cloned()
+    }
+
+    fn update_metadata(&self, updates: serde_json::Map<String, serde_json::Value>) -> Result<()> {
+        self.metadata.lock().unwrap().extend(updates);
+        Ok(())
+    }
+}
+
+/// `--output-embeddings`: appends one JSON line per chunk, with its text,
+/// vector and full metadata, for use outside a vector store. Nothing can be
+/// read back, so every run starts from an empty file and indexes everything.
+pub struct EmbeddingsFile {
+    collection: String,
+    /// Shared by the routed collections, which each tag their lines
+    out: Arc<Mutex<std::io::BufWriter<fs::File>>>,
+    written: AtomicUsize,
+    dimension: Mutex<Option<usize>>,
+    metadata: Mutex<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl EmbeddingsFile {
+    fn new(collection: &str, out: Arc<Mutex<std::io::BufWriter<fs::File>>>, creation_metadata: &serde_json::Map<String, serde_json::Value>) -> Self {
+        Self {
+            collection: collection.to_string(),
+            out,
+            written: AtomicUsize::new(0),
+            dimension: Mutex::new(None),
+            metadata: Mutex::new(creation_metadata.clone()),
+        }
+    }
+}
+
+impl VectorStore for EmbeddingsFile {
+    fn add_chunks(&self, chunks: &[Chunk], embeddings: Vec<Vec<f32>>) -> Result<()> {
+        if chunks.len() != embeddings.len() {
+            anyhow::bail!("Got {} embeddings for {} chunks", embeddings.len(), chunks.len());
+        }
+        let mut out = self.out.lock().unwrap();
+        for (chunk, embedding) in chunks.iter().zip(&embeddings) {
+            let line = serde_json::json!({
+                "id": chunk.id,
+                "collection": self.collection,
+                "document": chunk.text,
+                "embedding": embedding,
+                "metadata": chunk.metadata,
+            });
+            serde_json::to_writer(&mut *out, &line)?;
+            out.write_all(b"\n")?;
+        }
+        // Flush per batch so an interrupted run leaves whole lines behind
+        out.flush().context("Failed to write embeddings")?;
+        self.written.fetch_add(chunks.len(), Ordering::Relaxed);
+        if let Some(embedding) = embeddings.first() {
+            self.dimension.lock().unwrap().get_or_insert(embedding.len());
+        }
+        Ok(())
+    }
+
+    fn is_commit_indexed(&self, _git_branch: &str, _version: Option<&str>, _git_commit: &str) -> bool {
+        false
+    }
+
+    fn branch_schema(&self, _git_branch: &str, _version: Option<&str>) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn dimension(&self) -> Result<Option<usize>> {
+        Ok(*self.dimension.lock().unwrap())
+    }
+
+    fn delete_old_commits(&self, _git_branch: &str, _version: Option<&str>, _live_commits: &[&str]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn archive_old_commits(&self, _git_branch: &str, _version: Option<&str>, _live_commits: &[&str]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn purge_archived(&self, _git_branch: &str, _version: Option<&str>) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn indexed_files(&self, _git_branch: &str, _version: Option<&str>) -> Result<HashSet<String>> {
+        Ok(HashSet::new())
+    }
+
+    fn delete_file(&self, _git_branch: &str, _version: Option<&str>, _file_path: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete_branch(&self, _git_branch: &str, _version: Option<&str>) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete_file_except(&self, _git_branch: &str, _version: Option<&str>, _file_path: &str, _keep: &HashSet<String>) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete_dirty(&self, _git_branch: &str, _version: Option<&str>, _git_commit: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn count_with_retries(&self, _retries: usize) -> Option<usize> {
+        Some(self.written.load(Ordering::Relaxed))
+    }
+
+    fn metadata_value(&self, key: &str) -> Option<serde_json::Value> {
+        self.metadata.lock().unwrap().get(key).cloned()
+    }
+
+    fn update_metadata(&self, updates: serde_json::Map<String, serde_json::Value>) -> Result<()> {
+        self.metadata.lock().unwrap().extend(updates);
+        Ok(())
+    }
+}
+
+/// Evaluates the subset of ChromaDB's `where` syntax the indexer uses:
+/// `$and`, `$or`, and per-field `$eq`, `$ne`, `$in`, `$nin` or a bare value.
+/// A field missing from the metadata only matches `$ne` and `$nin`.
+fn where_matches(metadata: &serde_json::Value, filter: &serde_json::Value) -> bool {
+    let Some(clauses) = filter.as_object() else { return false };
+    clauses.iter().all(|(key, condition)| match key.as_str() {
+        "$and" => condition.as_array().is_some_and(|all| all.iter().all(|f| where_matches(metadata, f))),
+        "$or" => condition.as_array().is_some_and(|any| any.iter().any(|f| where_matches(metadata, f))),
+        field => {
+            let value = metadata.get(field);
+            let Some(ops) = condition.as_object() else { return value == Some(condition) };
+            ops.iter().all(|(op, operand)| {
+                let listed = || operand.as_array().is_some_and(|list| value.is_some_and(|v| list.contains(v)));
+                match op.as_str() {
+                    "$eq" => value == Some(operand),
+                    "$ne" => value != Some(operand),
+                    "$in" => listed(),
+                    "$nin" => !listed(),
+                    _ => false,
+                }
+            })
+        }
+    })
+}
+
+/// Translates the ChromaDB `where` subset handled by `where_matches` into a
+/// Qdrant payload filter.
+fn qdrant_filter(filter: &serde_json::Value) -> serde_json::Value {
+    let mut must = Vec::new();
+    let mut must_not = Vec::new();
+    let mut should = Vec::new();
+    for (key, condition) in filter.as_object().into_iter().flatten() {
+        let nested = || condition.as_array().into_iter().flatten().map(qdrant_filter);
+        match key.as_str() {
+            "$and" => must.extend(nested()),
+            "$or" => should.extend(nested()),
+            field => {
+                let Some(ops) = condition.as_object() else {
+                    must.push(serde_json::json!({"key": field, "match": {"value": condition}}));
+                    continue;
+                };
+                for (op, operand) in ops {
+                    match op.as_str() {
+                        "$eq" => must.push(serde_json::json!({"key": field, "match": {"value": operand}})),
+                        "$ne" => must_not.push(serde_json::json!({"key": field, "match": {"value": operand}})),
+                        "$in" => must.push(serde_json::json!({"key": field, "match": {"any": operand}})),
+                        "$nin" => must_not.push(serde_json::json!({"key": field, "match": {"any": operand}})),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    let mut out = serde_json::Map::new();
+    for (key, conditions) in [("must", must), ("must_not", must_not), ("should", should)] {
+        if !conditions.is_empty() {
+            out.insert(key.to_string(), conditions.into());
+        }
+    }
+    out.into()
+}
+
+/// Qdrant point ids must be integers or UUIDs, so chunk ids are hashed into a
+/// UUID-shaped id and kept in the `chunk_id` payload field.
+fn qdrant_point_id(chunk_id: &str) -> String {
+    let hex = content_digest(chunk_id);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Suffix of the companion Qdrant collection that stands in for collection
+/// metadata, which Qdrant doesn't have.
+const QDRANT_METADATA_SUFFIX: &str = "__metadata";
+
+/// The one point in the companion collection; its payload is the metadata.
+const QDRANT_METADATA_POINT: &str = "00000000-0000-0000-0000-000000000000";
+
+/// A Qdrant collection, reached over its REST API. The collection is created
+/// on the first add, once the vector size is known. Settings like
+/// `last_full_index_ts` are kept in the payload of a single point in a
+/// companion `<collection>__metadata` collection.
+pub struct QdrantStore {
+    client: Client,
+    collection_url: String,
+    metadata_url: String,
+    distance: &'static str,
+    exists: AtomicBool,
+    /// Written to the metadata point when the collection is created
+    creation_metadata: serde_json::Map<String, serde_json::Value>,
+    metadata: Mutex<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl QdrantStore {
+    pub fn open(qdrant_url: &str, collection: &str, creation_metadata: &serde_json::Map<String, serde_json::Value>, timeout: Duration) -> Result<Self> {
+        let client = http_client(timeout)?;
+        let collection_url = format!("{}/collections/{}", qdrant_url.trim_end_matches('/'), collection);
+        let response = client.get(&collection_url).send().with_context(|| format!("Failed to reach Qdrant at {}", qdrant_url))?;
+        let exists = match response.status() {
+            reqwest::StatusCode::NOT_FOUND => false,
+            _ => {
+                response.error_for_status().context("Failed to look up Qdrant collection")?;
+                true
+            }
+        };
+        let distance = match creation_metadata.get("hnsw:space").and_then(|v| v.as_str()) {
+            Some("l2") => "Euclid",
+            Some("ip") => "Dot",
+            _ => "Cosine",
+        };
+        let metadata_url = format!("{}{}", collection_url, QDRANT_METADATA_SUFFIX);
+        let response = client.get(format!("{}/points/{}", metadata_url, QDRANT_METADATA_POINT)).send()?;
+        let metadata = match response.status() {
+            reqwest::StatusCode::NOT_FOUND => serde_json::Map::new(),
+            _ => {
+                let mut body: serde_json::Value = response.error_for_status().context("Failed to read Qdrant collection metadata")?.json()?;
+                match body["result"]["payload"].take() {
+                    serde_json::Value::Object(payload) => payload,
+                    _ => serde_json::Map::new(),
+                }
+            }
+        };
+        Ok(Self {
+            client,
+            collection_url,
+            metadata_url,
+            distance,
+            exists: AtomicBool::new(exists),
+            creation_metadata: creation_metadata.clone(),
+            metadata: Mutex::new(metadata),
+        })
+    }
+
+    fn send(&self, request: reqwest::blocking::RequestBuilder) -> Result<serde_json::Value> {
+        let response = request.send()?.error_for_status()?;
+        let mut body: serde_json::Value = response.json().context("Invalid response from Qdrant")?;
+        Ok(body["result"].take())
+    }
+
+    fn ensure_collection(&self, dimension: usize) -> Result<()> {
+        if self.exists.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let body = serde_json::json!({"vectors": {"size": dimension, "distance": self.distance}});
+        self.send(self.client.put(&self.collection_url).json(&body)).context("Failed to create Qdrant collection")?;
+        self.exists.store(true, Ordering::Release);
+        self.update_metadata(self.creation_metadata.clone())
+    }
+
+    /// Writes the whole metadata map as the payload of the metadata point,
+    /// creating the companion collection the first time.
+    fn save_metadata(&self, metadata: &serde_json::Map<String, serde_json::Value>) -> Result<()> {
+        let point = serde_json::json!({"points": [{"id": QDRANT_METADATA_POINT, "vector": [1.0], "payload": metadata}]});
+        let url = format!("{}/points?wait=true", self.metadata_url);
+        let response = self.client.put(&url).json(&point).send()?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            response.error_for_status().context("Failed to write Qdrant collection metadata")?;
+            return Ok(());
+        }
+        let body = serde_json::json!({"vectors": {"size": 1, "distance": "Dot"}});
+        self.send(self.client.put(&self.metadata_url).json(&body)).context("Failed to create Qdrant metadata collection")?;
+        self.send(self.client.put(&url).json(&point)).context("Failed to write Qdrant collection metadata")?;
+        Ok(())
+    }
+
+    fn count_where(&self, filter: &serde_json::Value) -> Result<usize> {
+        if !self.exists.load(Ordering::Acquire) {
+            return Ok(0);
+        }
+        let body = serde_json::json!({"filter": qdrant_filter(filter), "exact": true});
+        let result = self.send(self.client.post(format!("{}/points/count", self.collection_url)).json(&body))?;
+        Ok(result["count"].as_u64().unwrap_or(0) as usize)
+    }
+
+    fn delete_where(&self, filter: &serde_json::Value) -> Result<()> {
+        if !self.exists.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let body = serde_json::json!({"filter": qdrant_filter(filter)});
+        self.send(self.client.post(format!("{}/points/delete?wait=true", self.collection_url)).json(&body))?;
+        Ok(())
+    }
+}
+
+impl VectorStore for QdrantStore {
+    fn add_chunks(&self, chunks: &[Chunk], embeddings: Vec<Vec<f32>>) -> Result<()> {
+        let Some(dimension) = embeddings.first().map(Vec::len) else { return Ok(()) };
+        self.ensure_collection(dimension)?;
+        let mut points = Vec::with_capacity(chunks.len());
+        for (chunk, embedding) in chunks.iter().zip(embeddings) {
+            let mut payload = serde_json::to_value(&chunk.metadata)?;
+            payload["chunk_id"] = chunk.id.clone().into();
+            payload["document"] = chunk.text.clone().into();
+            points.push(serde_json::json!({"id": qdrant_point_id(&chunk.id), "vector": embedding, "payload": payload}));
+        }
+        let body = serde_json::json!({ "points": points });
+        self.send(self.client.put(format!("{}/points?wait=true", self.collection_url)).json(&body))
+            .context("Failed to upsert points into Qdrant")?;
+        Ok(())
+    }
+
+    fn is_commit_indexed(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> bool {
+        let filter = scope_filter(git_branch, version, live_commit_condition(git_commit));
+        self.count_where(&filter).is_ok_and(|count| count > 0)
+    }
+
+    fn branch_schema(&self, git_branch: &str, version: Option<&str>) -> Result<Option<u64>> {
+        if !self.exists.load(Ordering::Acquire) {
+            return Ok(None);
+        }
+        let body = serde_json::json!({
+            "filter": qdrant_filter(&scope_filter(git_branch, version, all_files_condition())),
+            "limit": 1,
+            "with_payload": ["schema_version"],
+            "with_vector": false,
+        });
+        let result = self.send(self.client.post(format!("{}/points/scroll", self.collection_url)).json(&body))?;
+        Ok(result["points"].as_array().and_then(|points| points.first()).map(|point| schema_version_of(&point["payload"])))
+    }
+
+    fn dimension(&self) -> Result<Option<usize>> {
+        if !self.exists.load(Ordering::Acquire) {
+            return Ok(None);
+        }
+        let result = self.send(self.client.get(&self.collection_url))?;
+        Ok(result["config"]["params"]["vectors"]["size"].as_u64().map(|size| size as usize))
+    }
+
+    fn delete_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize> {
+        let filter = scope_filter(git_branch, version, old_commits_condition(live_commits));
+        let count = self.count_where(&filter)?;
+        if count > 0 {
+            self.delete_where(&filter)?;
+        }
+        Ok(count)
+    }
+
+    fn archive_old_commits(&self, git_branch: &str, version: Option<&str>, live_commits: &[&str]) -> Result<usize> {
+        let filter = scope_filter(git_branch, version, old_commits_condition(live_commits));
+        let count = self.count_where(&filter)?;
+        if count > 0 {
+            let body = serde_json::json!({"payload": {ARCHIVED_KEY: true}, "filter": qdrant_filter(&filter)});
+            self.send(self.client.post(format!("{}/points/payload?wait=true", self.collection_url)).json(&body))
+                .context("Failed to archive old Qdrant points")?;
+        }
+        Ok(count)
+    }
+
+    fn purge_archived(&self, git_branch: &str, version: Option<&str>) -> Result<usize> {
+        let filter = scope_filter(git_branch, version, archived_condition());
+        let count = self.count_where(&filter)?;
+        if count > 0 {
+            self.delete_where(&filter)?;
+        }
+        Ok(count)
+    }
+
+    fn indexed_files(&self, git_branch: &str, version: Option<&str>) -> Result<HashSet<String>> {
+        let mut files = HashSet::new();
+        if !self.exists.load(Ordering::Acquire) {
+            return Ok(files);
+        }
+        let filter = qdrant_filter(&scope_filter(git_branch, version, all_files_condition()));
+        let mut offset = serde_json::Value::Null;
+        loop {
+            let body = serde_json::json!({
+                "filter": filter,
+                "limit": INDEXED_FILES_PAGE_SIZE,
+                "offset": offset,
+                "with_payload": ["file_path"],
+                "with_vector": false,
+            });
+            let mut result = self.send(self.client.post(format!("{}/points/scroll", self.collection_url)).json(&body))?;
+            if let Some(points) = result["points"].as_array() {
+                files.extend(points.iter().filter_map(|p| p["payload"]["file_path"].as_str()).map(str::to_string));
+            }
+            offset = result["next_page_offset"].take();
+            if offset.is_null() {
+                return Ok(files);
+            }
+        }
+    }
+
+    fn delete_file(&self, git_branch: &str, version: Option<&str>, file_path: &str) -> Result<()> {
+        self.delete_where(&scope_filter(git_branch, version, serde_json::json!({"file_path": {"$eq": file_path}})))
+    }
+
+    fn delete_branch(&self, git_branch: &str, version: Option<&str>) -> Result<()> {
+        self.delete_where(&scope_filter(git_branch, version, all_files_condition()))
+    }
+
+    fn delete_file_except(&self, git_branch: &str, version: Option<&str>, file_path: &str, keep: &HashSet<String>) -> Result<()> {
+        if keep.is_empty() {
+            return self.delete_file(git_branch, version, file_path);
+        }
+        let keep: Vec<&String> = keep.iter().collect();
+        let condition = serde_json::json!({"$and": [{"file_path": {"$eq": file_path}}, {"chunk_id": {"$nin": keep}}]});
+        self.delete_where(&scope_filter(git_branch, version, condition))
+    }
+
+    fn delete_dirty(&self, git_branch: &str, version: Option<&str>, git_commit: &str) -> Result<()> {
+        self.delete_where(&scope_filter(git_branch, version, serde_json::json!({"git_commit": {"$eq": dirty_commit(git_commit)}})))
+    }
+
+    fn count_with_retries(&self, retries: usize) -> Option<usize> {
+        for attempt in 0..=retries {
+            match self.count_where(&serde_json::json!({})) {
+                Ok(count) => return Some(count),
+                Err(err) if attempt == retries => warn!("failed to count Qdrant points: {:#}", err),
+                Err(_) => thread::sleep(Duration::from_millis(500 * (attempt as u64 + 1))),
+            }
+        }
+        None
+    }
+
+    fn metadata_value(&self, key: &str) -> Option<serde_json::Value> {
+        // Until the first add creates it, the collection has the metadata it will be created with
+        if !self.exists.load(Ordering::Acquire) {
+            return self.creation_metadata.get(key).cloned();
+        }
+        self.metadata.lock().unwrap().get(key).cloned()
+    }
+
+    fn update_metadata(&self, updates: serde_json::Map<String, serde_json::Value>) -> Result<()> {
+        let mut metadata = self.metadata.lock().unwrap();
+        let mut merged = metadata.clone();
+        merged.extend(updates);
+        self.save_metadata(&merged)?;
+        *metadata = merged;
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Collection Routing
+// ============================================================================
+
+/// Maps file extensions to target collections, e.g. `md,mdx=docs;default=code`.
+#[derive(Debug, Clone)]
+pub struct CollectionRouter {
+    routes: HashMap<String, String>,
+    default: String,
+}
+
+impl CollectionRouter {
+    pub fn single(collection: &str) -> Self {
+        Self { routes: HashMap::new(), default: collection.to_string() }
+    }
+
+    /// Parses a `ext,ext=collection;...` spec. `default=<name>` overrides the
+    /// fallback collection, which otherwise is `default`.
+    pub fn parse(spec: &str, default: &str) -> Result<Self> {
+        let mut router = Self::single(default);
+
+        for rule in spec.split(';').map(str::trim).filter(|r| !r.is_empty()) {
+            let (exts, collection) = rule
+                .split_once('=')
+                .with_context(|| format!("Invalid collection rule '{}', expected ext,ext=collection", rule))?;
+            let collection = collection.trim();
+            if collection.is_empty() {
+                anyhow::bail!("Missing collection name in rule '{}'", rule);
+            }
+
+            for ext in exts.split(',').map(|e| e.trim().trim_start_matches('.').to_lowercase()) {
+                if ext.is_empty() { continue; }
+                if ext == "default" {
+                    router.default = collection.to_string();
+                } else {
+                    router.routes.insert(ext, collection.to_string());
+                }
+            }
+        }
+
+        Ok(router)
+    }
+
+    /// Suffixes every target collection with the branch name.
+    pub fn per_branch(self, git_branch: &str) -> Self {
+        Self {
+            routes: self.routes.into_iter().map(|(ext, name)| (ext, branch_collection_name(&name, git_branch))).collect(),
+            default: branch_collection_name(&self.default, git_branch),
+        }
+    }
+
+    pub fn route(&self, file_type: &str) -> &str {
+        let ext = file_type.trim_start_matches('.').to_lowercase();
+        self.routes.get(&ext).unwrap_or(&self.default)
+    }
+
+    pub fn collections(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.routes.values().map(String::as_str).collect();
+        names.push(&self.default);
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+const BRANCH_COLLECTION_SEPARATOR: &str = "__";
+
+/// Chroma only allows `[A-Za-z0-9._-]` in collection names.
+fn sanitize_collection_part(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '-' })
+        .collect()
+}
+
+pub fn branch_collection_name(base: &str, git_branch: &str) -> String {
+    format!("{}{}{}", base, BRANCH_COLLECTION_SEPARATOR, sanitize_collection_part(git_branch))
+}
+
+/// Deletes per-branch collections of `bases` whose branch is not in `live_branches`.
+/// Without `apply` it only reports what would be removed.
+pub fn prune_collections(chroma: &ChromaClient, bases: &[&str], live_branches: &[String], apply: bool) -> Result<()> {
+    let live: HashSet<String> = live_branches.iter().map(|b| sanitize_collection_part(b)).collect();
+    let mut stale = 0usize;
+
+    for name in chroma.list_collections()? {
+        let Some(branch) = bases
+            .iter()
+            .find_map(|base| name.strip_prefix(base)?.strip_prefix(BRANCH_COLLECTION_SEPARATOR))
+        else {
+            continue;
+        };
+        if live.contains(branch) || shard_base(branch).is_some_and(|b| live.contains(b)) {
+            continue;
+        }
+
+        stale += 1;
+        if apply {
+            chroma.delete_collection(&name)?;
+            println!("Deleted {} (branch '{}' no longer exists)", name, branch);
+        } else {
+            println!("Would delete {} (branch '{}' no longer exists)", name, branch);
+        }
+    }
+
+    if stale == 0 {
+        println!("No stale branch collections");
+    } else if !apply {
+        println!("Re-run with --apply to delete {} collections", stale);
+    }
+    Ok(())
+}
+
+/// Settings reported by `describe-collection`, as (label, metadata key).
+const DESCRIBED_SETTINGS: &[(&str, &str)] = &[
+    ("Embedding model", "embedding_model"),
+    ("Dimension", "dimension"),
+    ("Distance", "hnsw:space"),
+    ("HNSW M", "hnsw:M"),
+    ("HNSW construction ef", "hnsw:construction_ef"),
+    ("HNSW search ef", "hnsw:search_ef"),
+    ("Chunk size", "chunk_size"),
+    ("Chunk overlap", "chunk_overlap"),
+    ("Chunk size unit", "chunk_size_unit"),
+    ("Hash algorithm", "hash_algorithm"),
+    ("Namespace", "namespace"),
+];
+
+/// Collects the settings a collection was built with. Anything that wasn't
+/// stored (e.g. an index from an older indexer) is reported as "unknown".
+pub fn describe_collection(chroma: &ChromaClient, name: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let info = chroma.collection_info(name)?;
+    let metadata = info.get("metadata").and_then(|m| m.as_object());
+
+    let mut settings = serde_json::Map::new();
+    for (_, key) in DESCRIBED_SETTINGS {
+        let value = metadata
+            .and_then(|m| m.get(*key))
+            .or_else(|| info.get(*key))
+            .filter(|v| !v.is_null())
+            .cloned()
+            .unwrap_or_else(|| serde_json::Value::from("unknown"));
+        settings.insert(key.to_string(), value);
+    }
+
+    if let Some(metadata) = metadata {
+        for (key, value) in metadata {
+            settings.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+
+    Ok(settings)
+}
+
+// ============================================================================
+// Search
+// ============================================================================
+
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub collection: String,
+    pub id: String,
+    pub file_path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub document: String,
+    pub distance: f32,
+    /// Similarity derived from `distance` for the collection's space; higher is closer
+    pub score: f32,
+}
+
+pub struct SearchOptions<'a> {
+    pub query: &'a str,
+    /// Overrides the query prefix derived from each collection's stored embed prefix
+    pub query_prefix: Option<&'a str>,
+    /// The embed prefix the caller expects collections to have been indexed with
+    pub embed_prefix: Option<&'a str>,
+    pub top_k: usize,
+    pub max_concurrent: usize,
+    /// The model queries are embedded with, checked against each collection's
+    pub embedding_model: Option<&'a str>,
+}
+
+/// Document-side prefixes of instruction-tuned embedding models and the query
+/// prefix each expects at search time.
+const QUERY_PREFIXES: &[(&str, &str)] = &[
+    ("passage: ", "query: "),
+    ("search_document: ", "search_query: "),
+    ("Represent this document for retrieval: ", "Represent this query for retrieving relevant documents: "),
+];
+
+/// The query prefix matching `embed_prefix`; unknown prefixes are used as-is.
+fn query_prefix_for(embed_prefix: &str) -> String {
+    QUERY_PREFIXES
+        .iter()
+        .find(|(document, _)| *document == embed_prefix)
+        .map_or(embed_prefix, |(_, query)| *query)
+        .to_string()
+}
+
+/// Maps a raw distance onto a similarity that's comparable across spaces.
+fn distance_to_score(space: &str, distance: f32) -> f32 {
+    match space {
+        "l2" => 1.0 / (1.0 + distance),
+        // Chroma reports cosine and ip as 1 - similarity
+        _ => 1.0 - distance,
+    }
+}
+
+/// Queries every collection in `names` with `embedding`, at most `max_concurrent`
+/// at a time, and merges the hits into one ranking. The same file and line
+/// range found in several collections is kept once, at its best score.
+pub fn search_collections(endpoint: &ChromaEndpoint, names: &[String], embedder: &dyn Embedder, options: &SearchOptions) -> Result<Vec<SearchHit>> {
+    let top_k = options.top_k;
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(options.max_concurrent.max(1)).build()?;
+    let per_collection: Vec<Vec<SearchHit>> = pool.install(|| {
+        names
+            .par_iter()
+            .map(|name| {
+                let chroma = ChromaClient::open(endpoint, name)?;
+                let metadata_str = |key: &str| chroma.metadata_value(key).and_then(|v| v.as_str().map(str::to_string));
+                let space = metadata_str("hnsw:space").unwrap_or_else(|| "l2".to_string());
+                check_embedding_model(name, metadata_str(EMBEDDING_MODEL_KEY).as_deref(), options.embedding_model)?;
+
+                // Embed the query the way this collection's documents were embedded
+                let stored_prefix = metadata_str(EMBED_PREFIX_KEY);
+                if let Some(expected) = options.embed_prefix {
+                    if stored_prefix.as_deref() != Some(expected) {
+                        warn!("{} was indexed with embed prefix {:?}, not {:?}", name, stored_prefix.as_deref().unwrap_or_default(), expected);
+                    }
+                }
+                let query_prefix = match options.query_prefix {
+                    Some(prefix) => prefix.to_string(),
+                    None => stored_prefix.as_deref().map(query_prefix_for).unwrap_or_default(),
+                };
+                let query = format!("{}{}", query_prefix, options.query);
+                let embedding = embedder
+                    .encode(&[query.as_str()])?
+                    .pop()
+                    .context("Embedding service returned no vector for the query")?;
+
+                let mut hits = chroma.nearest(&embedding, top_k)?;
+                for hit in &mut hits {
+                    hit.score = distance_to_score(&space, hit.distance);
+                }
+                Ok(hits)
+            })
+            .collect::<Result<_>>()
+    })?;
+
+    let mut hits: Vec<SearchHit> = per_collection.into_iter().flatten().collect();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    let mut seen = HashSet::new();
+    hits.retain(|hit| seen.insert((hit.file_path.clone(), hit.start_line, hit.end_line)));
+    hits.truncate(top_k);
+    Ok(hits)
+}
+
+// ============================================================================
+// Verification
+// ============================================================================
+
+/// Samples up to `sample_size` chunks spread evenly through `chroma` and
+/// reports those whose nearest other chunk is closer than `threshold`, in the
+/// collection's own distance units. Returns the number of suspected duplicates.
+pub fn find_near_duplicates(chroma: &ChromaClient, threshold: f32, sample_size: usize) -> Result<usize> {
+    let total = chroma.count()?;
+    let space = chroma
+        .metadata_value("hnsw:space")
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_else(|| "l2".to_string());
+    let sample = sample_size.min(total);
+    println!("Checking {} of {} chunks for near-duplicates ({} distance < {})", sample, total, space, threshold);
+
+    let step = total / sample.max(1);
+    let mut suspected = 0usize;
+    for k in 0..sample {
+        let record = chroma.get_records(k * step, 1, &["metadatas", "embeddings"])?;
+        let (Some(id), Some(embedding)) = (record.ids.first(), record.embeddings.and_then(|e| e.into_iter().next())) else {
+            continue;
+        };
+
+        let Some(neighbor) = chroma.nearest(&embedding, 2)?.into_iter().find(|hit| &hit.id != id) else {
+            continue;
+        };
+        if neighbor.distance < threshold {
+            suspected += 1;
+            let metadata = record.metadatas.and_then(|m| m.into_iter().next().flatten()).unwrap_or_default();
+            let line = |key: &str| metadata.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+            println!(
+                "  {}:{}-{} ~ {}:{}-{} (distance {:.4})",
+                metadata.get("file_path").and_then(|v| v.as_str()).unwrap_or(id),
+                line("start_line"),
+                line("end_line"),
+                neighbor.file_path,
+                neighbor.start_line,
+                neighbor.end_line,
+                neighbor.distance,
+            );
+        }
+    }
+
+    println!("{} of {} sampled chunks have a near-duplicate", suspected, sample);
+    Ok(suspected)
+}
+
+/// Records read per request by the consistency check.
+const VERIFY_PAGE_SIZE: usize = 1000;
+/// Examples printed for each kind of problem.
+const VERIFY_EXAMPLES: usize = 10;
+
+/// The chunks a fresh run would store for one file: their commit (with the
+/// dirty suffix for uncommitted files) and ids.
+pub struct ExpectedFile {
+    pub git_commit: String,
+    pub ids: BTreeSet<String>,
+}
+
+/// `SCHEMA_VERSION` recorded in a chunk's metadata; chunks written before
+/// the field existed count as version 0.
+fn schema_version_of(metadata: &serde_json::Value) -> u64 {
+    metadata["schema_version"].as_u64().unwrap_or(0)
+}
+
+/// Branch, version and file path of stored chunks.
+type FileScope = (String, Option<String>, String);
+
+/// Problems found by `check_consistency`, with examples of each kind.
+#[derive(Default)]
+struct Inconsistencies {
+    found: BTreeMap<&'static str, Vec<String>>,
+}
+
+impl Inconsistencies {
+    fn add(&mut self, kind: &'static str, example: String) {
+        self.found.entry(kind).or_default().push(example);
+    }
+
+    fn total(&self) -> usize {
+        self.found.values().map(Vec::len).sum()
+    }
+
+    fn report(&self) {
+        for (kind, examples) in &self.found {
+            println!("{}: {}", kind, examples.len());
+            for example in examples.iter().take(VERIFY_EXAMPLES) {
+                println!("  {}", example);
+            }
+            if examples.len() > VERIFY_EXAMPLES {
+                println!("  ... and {} more", examples.len() - VERIFY_EXAMPLES);
+            }
+        }
+    }
+}
+
+/// Reads the id and metadata of every record in `clients` and checks, without
+/// changing anything, that no id is stored twice, every record has its file,
+/// branch and commit, and no file has chunks from more than one commit on a
+/// branch. When the branch is indexed at `git_commit`, its files are also
+/// compared with `expected`, a fresh chunking of the tree. Returns the number
+/// of problems found.
+pub fn check_consistency(
+    clients: &[ChromaClient],
+    git_branch: &str,
+    version: Option<&str>,
+    git_commit: &str,
+    expected: &BTreeMap<String, ExpectedFile>,
+) -> Result<usize> {
+    let mut problems = Inconsistencies::default();
+    let mut locations: HashMap<String, String> = HashMap::new();
+    let mut stored: BTreeMap<FileScope, BTreeMap<String, BTreeSet<String>>> = BTreeMap::new();
+    let mut archived = 0usize;
+
+    for client in clients {
+        let mut offset = 0;
+        loop {
+            let page = client.get_records(offset, VERIFY_PAGE_SIZE, &["metadatas"])?;
+            if page.ids.is_empty() {
+                break;
+            }
+            offset += page.ids.len();
+            let metadatas = page.metadatas.unwrap_or_default();
+            for (i, id) in page.ids.into_iter().enumerate() {
+                if let Some(first) = locations.insert(id.clone(), client.collection_name.clone()) {
+                    problems.add("Ids stored more than once", format!("{} (in {} and {})", id, first, client.collection_name));
+                }
+                let metadata = metadatas.get(i).cloned().flatten().unwrap_or_default();
+                // Archived chunks are old commits kept on purpose
+                if metadata.get(ARCHIVED_KEY).and_then(|v| v.as_bool()) == Some(true) {
+                    archived += 1;
+                    continue;
+                }
+                let field = |key: &str| metadata.get(key).and_then(|v| v.as_str()).map(str::to_string);
+                let (Some(file_path), Some(branch), Some(commit)) = (field("file_path"), field("git_branch"), field("git_commit")) else {
+                    problems.add("Records missing file, branch or commit metadata", format!("{} in {}", id, client.collection_name));
+                    continue;
+                };
+                stored.entry((branch, field("version"), file_path)).or_default().entry(commit).or_default().insert(id);
+            }
+        }
+    }
+    println!("Read {} records", locations.len());
+    if archived > 0 {
+        println!("{} archived records left out of the checks", archived);
+    }
+
+    for ((branch, _, file_path), commits) in &stored {
+        let bases: BTreeSet<&str> = commits.keys().map(|c| c.strip_suffix("-dirty").unwrap_or(c)).collect();
+        if bases.len() > 1 {
+            let shown: Vec<&str> = bases.iter().map(|c| &c[..8.min(c.len())]).collect();
+            problems.add("Files with chunks from several commits on one branch", format!("{} on {} ({})", file_path, branch, shown.join(", ")));
+        }
+    }
+
+    let in_scope = |key: &FileScope| key.0 == git_branch && key.1.as_deref() == version;
+    let current_indexed = stored.iter().any(|(key, commits)| in_scope(key) && commits.contains_key(git_commit));
+    if git_branch.is_empty() || git_commit.is_empty() {
+        println!("No git branch and commit; skipping the comparison with the working tree");
+    } else if !current_indexed {
+        println!("Branch {} isn't indexed at commit {}; skipping the comparison with the working tree", git_branch, &git_commit[..8.min(git_commit.len())]);
+    } else {
+        let mut older = 0usize;
+        for (file_path, file) in expected {
+            let key = (git_branch.to_string(), version.map(str::to_string), file_path.clone());
+            match stored.get(&key).map(|commits| commits.get(&file.git_commit)) {
+                None => problems.add("Files in the tree that aren't indexed", file_path.clone()),
+                Some(None) => older += 1,
+                Some(Some(ids)) if ids != &file.ids => {
+                    let missing = file.ids.difference(ids).count();
+                    let extra = ids.difference(&file.ids).count();
+                    problems.add("Files whose chunks differ from a fresh chunking", format!("{} ({} missing, {} unexpected)", file_path, missing, extra));
+                }
+                Some(Some(_)) => {}
+            }
+        }
+        for (key, _) in stored.iter().filter(|(key, _)| in_scope(key) && !expected.contains_key(&key.2)) {
+            problems.add("Indexed files no longer in the tree", key.2.clone());
+        }
+        if older > 0 {
+            println!("{} files are indexed at an older commit, as incremental runs leave them", older);
+        }
+    }
+
+    problems.report();
+    match problems.total() {
+        0 => println!("No inconsistencies found"),
+        n => println!("Found {} inconsistencies", n),
+    }
+    Ok(problems.total())
+}
+
+// ============================================================================
+// Collection Stats
+// ============================================================================
+
+/// What `--stats-only` tallies from the stored metadata of one collection.
+#[derive(Default)]
+struct CollectionStats {
+    files: HashSet<String>,
+    /// Chunks per branch (and version label), then per commit
+    commits: BTreeMap<(String, Option<String>), BTreeMap<String, usize>>,
+    dirty: usize,
+    archived: usize,
+    /// Records without branch or commit metadata
+    untagged: usize,
+    /// Records read, at most the sample size
+    sampled: usize,
+    /// Oldest and newest `indexed_at` seen
+    indexed_at: Option<(u64, u64)>,
+}
+
+/// "3h ago" style age of a unix timestamp, in its largest whole unit.
+fn format_age(ts: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let secs = now.saturating_sub(ts);
+    match secs {
+        0..60 => format!("{}s ago", secs),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// Prints chunk counts, files, branches and commits for the logical collection
+/// `name` (with its shards) from its stored metadata, without embedding anything.
+/// Only the first `sample` records are read; the chunk total is always exact.
+pub fn print_collection_stats(chroma: &ChromaEndpoint, name: &str, sample: usize) -> Result<()> {
+    let all = ChromaClient::connect(chroma)?.list_collections()?;
+    if !all.iter().any(|existing| existing == name) {
+        println!("Collection: {} (does not exist)", name);
+        return Ok(());
+    }
+    let shards = with_shards(name, &all);
+    let mut total = 0;
+    let mut stats = CollectionStats::default();
+    for shard in &shards {
+        let client = ChromaClient::open(chroma, shard)?;
+        total += client.count()?;
+        let mut offset = 0;
+        while stats.sampled < sample {
+            let page = client.get_records(offset, VERIFY_PAGE_SIZE.min(sample - stats.sampled), &["metadatas"])?;
+            if page.ids.is_empty() {
+                break;
+            }
+            offset += page.ids.len();
+            stats.sampled += page.ids.len();
+            for metadata in page.metadatas.unwrap_or_default().into_iter().flatten() {
+                let field = |key: &str| metadata.get(key).and_then(|v| v.as_str()).map(str::to_string);
+                let flag = |key: &str| metadata.get(key).and_then(|v| v.as_bool()) == Some(true);
+                if let Some(file_path) = field("file_path") {
+                    stats.files.insert(file_path);
+                }
+                stats.dirty += flag("dirty") as usize;
+                stats.archived += flag(ARCHIVED_KEY) as usize;
+                if let Some(ts) = metadata.get("indexed_at").and_then(|v| v.as_u64()) {
+                    let (oldest, newest) = stats.indexed_at.unwrap_or((ts, ts));
+                    stats.indexed_at = Some((oldest.min(ts), newest.max(ts)));
+                }
+                let (Some(branch), Some(commit)) = (field("git_branch"), field("git_commit")) else {
+                    stats.untagged += 1;
+                    continue;
+                };
+                *stats.commits.entry((branch, field("version"))).or_default().entry(commit).or_default() += 1;
+            }
+        }
+    }
+
+    println!("Collection: {}", name);
+    match shards.len() {
+        1 => println!("  Chunks: {}", total),
+        n => println!("  Chunks: {} across {} shards", total, n),
+    }
+    if stats.sampled < total {
+        println!("  Counts below are from the first {} chunks (raise --stats-sample to read more)", stats.sampled);
+    }
+    println!("  Files: {}", stats.files.len());
+    for ((branch, version), commits) in &stats.commits {
+        let chunks: usize = commits.values().sum();
+        let mut by_size: Vec<(&String, &usize)> = commits.iter().collect();
+        by_size.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let shown: Vec<String> = by_size.iter().map(|(commit, n)| format!("{} ({})", &commit[..8.min(commit.len())], n)).collect();
+        let label = match version {
+            Some(version) => format!("{} [{}]", branch, version),
+            None => branch.clone(),
+        };
+        println!("  Branch {}: {} chunks, commits {}", label, chunks, shown.join(", "));
+    }
+    if stats.dirty > 0 {
+        println!("  Uncommitted-change chunks: {}", stats.dirty);
+    }
+    if stats.archived > 0 {
+        println!("  Archived chunks: {}", stats.archived);
+    }
+    if stats.untagged > 0 {
+        println!("  Chunks without branch or commit: {}", stats.untagged);
+    }
+    if let Some((oldest, newest)) = stats.indexed_at {
+        println!("  Chunks indexed: oldest {} ({}), newest {} ({})", oldest, format_age(oldest), newest, format_age(newest));
+    }
+
+    let info = ChromaClient::connect(chroma)?.collection_info(name)?;
+    let metadata = info.get("metadata").and_then(|m| m.as_object());
+    match metadata.and_then(|m| m.get(LAST_FULL_INDEX_KEY)).and_then(|v| v.as_u64()) {
+        Some(ts) => println!("  Last full index: {} ({})", ts, format_age(ts)),
+        None => println!("  Last full index: never recorded"),
+    }
+    if let Some(model) = metadata.and_then(|m| m.get(EMBEDDING_MODEL_KEY)).and_then(|v| v.as_str()) {
+        println!("  Embedding model: {}", model);
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Migration
+// ============================================================================
+
+/// Re-embeds every stored document in `source` and writes it, with its id and
+/// metadata, into `target`. Works from the stored documents alone, so the
+/// source tree isn't needed and the embedding dimension is free to change.
+pub fn migrate_collection(source: &ChromaClient, target: &ChromaClient, embedder: &dyn Embedder, page_size: usize) -> Result<()> {
+    let total = source.count()?;
+    println!("Migrating {} records from {} to {}", total, source.collection_name, target.collection_name);
+
+    let prefix = target.metadata_value(EMBED_PREFIX_KEY).and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+    let mut offset = 0usize;
+    let mut migrated = 0usize;
+    let mut missing_documents = 0usize;
+    let mut expected_dim = None;
+
+    loop {
+        let page = source.get_records(offset, page_size, &["documents", "metadatas"])?;
+        if page.ids.is_empty() {
+            break;
+        }
+        offset += page.ids.len();
+
+        let documents = page.documents.unwrap_or_default();
+        let metadatas = page.metadatas.unwrap_or_default();
+
+        let mut request = ChromaUpsertRequest { ids: Vec::new(), embeddings: Vec::new(), documents: Vec::new(), metadatas: Vec::new() };
+        for (i, id) in page.ids.into_iter().enumerate() {
+            let Some(Some(document)) = documents.get(i) else {
+                missing_documents += 1;
+                continue;
+            };
+            request.ids.push(id);
+            request.documents.push(document.clone());
+            request.metadatas.push(metadatas.get(i).cloned().flatten().unwrap_or_default());
+        }
+
+        if !request.ids.is_empty() {
+            let inputs: Vec<String> = request.documents.iter().map(|d| format!("{}{}", prefix, d)).collect();
+            let texts: Vec<&str> = inputs.iter().map(String::as_str).collect();
+            let embeddings = embedder.encode(&texts)?;
+            if embeddings.len() != texts.len() {
+                anyhow::bail!("Embedding service returned {} vectors for {} inputs", embeddings.len(), texts.len());
+            }
+            for (id, embedding) in request.ids.iter().zip(&embeddings) {
+                if let Some(problem) = embedding_problem(embedding, expected_dim) {
+                    anyhow::bail!("Bad embedding for {}: {}", id, problem);
+                }
+                expected_dim.get_or_insert(embedding.len());
+            }
+            request.embeddings = embeddings;
+
+            migrated += request.ids.len();
+            target.upsert_records(request)?;
+        }
+
+        println!("Migrated {}/{}", migrated, total);
+    }
+
+    if missing_documents > 0 {
+        println!("Skipped {} records with no stored document (indexed before documents were stored)", missing_documents);
+    }
+    if let Some(count) = target.count_with_retries(3) {
+        println!("Done! {} now holds {} records", target.collection_name, count);
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Git
+// ============================================================================
+
+fn git_output(directory: &Path, args: &[&str]) -> Result<String> {
+    Ok(git_stdout(directory, args)?.trim().to_string())
+}
+
+/// Like `git_output`, but without trimming, for formats where leading
+/// whitespace is significant.
+fn git_stdout(directory: &Path, args: &[&str]) -> Result<String> {
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(directory)
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        anyhow::bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The author of each line of `relative`, in line order, from `git blame`.
+/// `None` when the file has no history (untracked, or not in a repository).
+pub fn blame_authors(directory: &Path, relative: &str) -> Option<Vec<String>> {
+    let output = git_output(directory, &["blame", "--line-porcelain", "--", relative]).ok()?;
+    // Content lines are tab-prefixed, so only header fields can match
+    let authors: Vec<String> = output.lines().filter_map(|l| l.strip_prefix("author ")).map(String::from).collect();
+    (!authors.is_empty()).then_some(authors)
+}
+
+/// Sets `primary_author` and `author_lines` on `metadata` from the blamed
+/// authors of its line range. `author_lines` is a JSON object string, since
+/// Chroma metadata values must be scalars.
+fn apply_blame(metadata: &mut ChunkMetadata, authors: &[String]) {
+    let start = metadata.start_line.saturating_sub(1).min(authors.len());
+    let end = metadata.end_line.min(authors.len());
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for author in &authors[start..end] {
+        if author != UNCOMMITTED_AUTHOR {
+            *counts.entry(author).or_default() += 1;
+        }
+    }
+
+    let mut ranked: Vec<(&str, usize)> = counts.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    ranked.truncate(BLAME_MAX_AUTHORS);
+
+    metadata.primary_author = ranked.first().map(|(author, _)| author.to_string());
+    if !ranked.is_empty() {
+        let map: serde_json::Map<String, serde_json::Value> = ranked.iter().map(|(a, n)| (a.to_string(), (*n).into())).collect();
+        metadata.author_lines = Some(serde_json::Value::Object(map).to_string());
+    }
+}
+
+/// The commit id recorded on chunks of files with uncommitted changes.
+pub fn dirty_commit(git_commit: &str) -> String {
+    format!("{}-dirty", git_commit)
+}
+
+/// Files under `directory` that differ between the merge base of `git_ref`
+/// and HEAD, relative to `directory`. Includes files deleted since then.
+pub fn changed_since(directory: &Path, git_ref: &str) -> Result<Vec<String>> {
+    let range = format!("{}...HEAD", git_ref);
+    let diff = git_stdout(directory, &["diff", "--name-only", "-z", "--relative", &range, "--", "."])
+        .with_context(|| format!("Failed to list files changed since {}", git_ref))?;
+    Ok(diff.split('\0').filter(|path| !path.is_empty()).map(str::to_string).collect())
+}
+
+/// Files under `directory` with uncommitted changes, including untracked ones,
+/// relative to `directory`. Empty outside a repository.
+pub fn dirty_files(directory: &Path) -> HashSet<String> {
+    let Ok(prefix) = git_output(directory, &["rev-parse", "--show-prefix"]) else {
+        return HashSet::new();
+    };
+    let Ok(status) = git_stdout(directory, &["--no-optional-locks", "status", "--porcelain", "-z", "--untracked-files=all", "--", "."]) else {
+        return HashSet::new();
+    };
+
+    let mut files = HashSet::new();
+    let mut entries = status.split('\0');
+    while let Some(entry) = entries.next() {
+        if entry.len() < 4 {
+            continue;
+        }
+        let (code, path) = entry.split_at(3);
+        // Renames and copies are followed by their source path
+        if code.starts_with(['R', 'C']) {
+            entries.next();
+        }
+        if code.contains('D') {
+            continue;
+        }
+        // Porcelain paths are relative to the repository root
+        if let Some(relative) = path.strip_prefix(prefix.as_str()) {
+            files.insert(relative.to_string());
+        }
+    }
+    files
+}
+
+/// Local and remote-tracking branch names, with the remote prefix stripped.
+pub fn git_branches(directory: &Path) -> Result<Vec<String>> {
+    if git_output(directory, &["rev-parse", "--git-dir"]).is_err() {
+        anyhow::bail!("{} is not a git repository; pass --branches instead", directory.display());
+    }
+
+    let local = git_output(directory, &["for-each-ref", "--format=%(refname:lstrip=2)", "refs/heads"])?;
+    let remote = git_output(directory, &["for-each-ref", "--format=%(refname:lstrip=3)", "refs/remotes"])?;
+
+    let mut branches: Vec<String> = local
+        .lines()
+        .chain(remote.lines())
+        .filter(|b| !b.is_empty() && *b != "HEAD")
+        .map(str::to_string)
+        .collect();
+    branches.sort();
+    branches.dedup();
+    Ok(branches)
+}
+
+/// Reads `var`, falling back to `git <args>` in `directory` when it's unset or
+/// empty. Empty if neither works, e.g. outside a git work tree.
+fn env_or_git(var: &str, directory: &Path, args: &[&str]) -> String {
+    match env::var(var) {
+        Ok(value) if !value.is_empty() => value,
+        _ => git_output(directory, args).unwrap_or_default(),
+    }
+}
+
+/// CI variables naming the ref being built, checked in order when HEAD is detached.
+const CI_BRANCH_VARS: &[&str] = &["GITHUB_HEAD_REF", "GITHUB_REF", "CI_COMMIT_REF_NAME"];
+
+/// Picks the branch recorded on chunks. `--branch` wins; otherwise `GIT_BRANCH`
+/// is used unless it's the `HEAD` that `rev-parse --abbrev-ref` prints for a
+/// detached checkout, in which case the CI ref variables and then a tag on HEAD
+/// are tried. Returns the branch and, for a fallback, where it came from.
+fn resolve_branch(directory: &Path, branch_override: Option<&str>, env_branch: String) -> (String, Option<String>) {
+    if let Some(branch) = branch_override {
+        return (branch.to_string(), None);
+    }
+    if env_branch != "HEAD" {
+        return (env_branch, None);
+    }
+
+    for var in CI_BRANCH_VARS {
+        let value = env::var(var).unwrap_or_default();
+        let value = value.strip_prefix("refs/heads/").or_else(|| value.strip_prefix("refs/tags/")).unwrap_or(&value);
+        if !value.is_empty() {
+            return (value.to_string(), Some(var.to_string()));
+        }
+    }
+    if let Ok(tags) = git_output(directory, &["tag", "--points-at", "HEAD"]) {
+        if let Some(tag) = tags.lines().next() {
+            return (tag.to_string(), Some("tag on HEAD".to_string()));
+        }
+    }
+
+    warn!("GIT_BRANCH is a detached HEAD and no CI ref or tag was found; pass --branch to name it");
+    (env_branch, None)
+}
+
+// ============================================================================
+// Resume Manifest
+// ============================================================================
+
+/// Chunk ids already uploaded for a branch/commit, so a capped or interrupted
+/// run can pick up where it left off.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ResumeManifest {
+    git_branch: String,
+    git_commit: String,
+    uploaded: BTreeSet<String>,
+    /// Progress as of the last checkpoint, for whoever is watching the file
+    #[serde(default)]
+    uploaded_batches: usize,
+    #[serde(default)]
+    planned_batches: usize,
+    #[serde(default)]
+    elapsed_secs: u64,
+    #[serde(default)]
+    remaining_secs: Option<u64>,
+}
+
+impl ResumeManifest {
+    /// Loads the manifest at `path` if it belongs to this branch/commit, otherwise
+    /// starts a fresh one.
+    pub fn load(path: &Path, git_branch: &str, git_commit: &str) -> Result<Self> {
+        if path.exists() {
+            let data = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read resume manifest {}", path.display()))?;
+            let manifest: Self = serde_json::from_str(&data)
+                .with_context(|| format!("Invalid resume manifest {}", path.display()))?;
+            if manifest.git_branch == git_branch && manifest.git_commit == git_commit {
+                return Ok(manifest);
+            }
+        }
+        Ok(Self { git_branch: git_branch.to_string(), git_commit: git_commit.to_string(), ..Default::default() })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)
+            .with_context(|| format!("Failed to write resume manifest {}", path.display()))?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn is_resuming(&self) -> bool {
+        !self.uploaded.is_empty()
+    }
+}
+
+// ============================================================================
+// Run Manifest
+// ============================================================================
+
+/// What a run did with a chunk, as recorded by `--manifest`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkStatus {
+    /// Embedded and written to the store this run
+    Uploaded,
+    /// Uploaded by an earlier run, per the resume manifest
+    Resumed,
+    /// Its collection was already indexed at this commit
+    AlreadyIndexed,
+    /// Held back by --max-chunks, a bad embedding or Ctrl-C
+    NotUploaded,
+}
+
+#[derive(Serialize)]
+struct RunManifestEntry<'a> {
+    id: &'a str,
+    file_path: &'a str,
+    start_line: usize,
+    end_line: usize,
+    collection: &'a str,
+    status: ChunkStatus,
+}
+
+/// Writes one JSON line per chunk the run produced. An empty file means the
+/// run chunked nothing, so a manifest from an earlier run is never left behind.
+fn write_run_manifest<'a>(path: &Path, chunks: impl IntoIterator<Item = (&'a str, &'a Chunk, ChunkStatus)>) -> Result<()> {
+    let file = fs::File::create(path).with_context(|| format!("Failed to write manifest {}", path.display()))?;
+    let mut out = std::io::BufWriter::new(file);
+    let mut count = 0usize;
+    for (collection, chunk, status) in chunks {
+        let entry = RunManifestEntry {
+            id: &chunk.id,
+            file_path: &chunk.metadata.file_path,
+            start_line: chunk.metadata.start_line,
+            end_line: chunk.metadata.end_line,
+            collection,
+            status,
+        };
+        serde_json::to_writer(&mut out, &entry)?;
+        out.write_all(b"\n")?;
+        count += 1;
+    }
+    out.flush().with_context(|| format!("Failed to write manifest {}", path.display()))?;
+    info!("Wrote {} chunk ids to {}", count, path.display());
+    Ok(())
+}
+
+// ============================================================================
+// Indexer
+// ============================================================================
+
+/// When stale chunks from older commits are removed relative to uploading the
+/// new ones.
+///
+/// `delete-first` never holds two commits at once but leaves the branch empty
+/// while the new chunks upload. `add-first` keeps the old chunks searchable
+/// until the new ones land, at the cost of briefly returning both. Chunk ids
+/// embed the commit, so the two sets never collide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReindexOrder {
+    DeleteFirst,
+    AddFirst,
+}
+
+/// Counting semaphore bounding how many embedding requests, or file reads,
+/// are in flight.
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    released: Condvar,
+}
+
+struct SemaphorePermit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self { permits: Mutex::new(permits.max(1)), released: Condvar::new() }
+    }
+
+    fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.released.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphorePermit(self)
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *self.0.permits.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Per-run counters shared across the parallel file workers.
+#[derive(Default)]
+struct RunStats {
+    unknown_language: AtomicUsize,
+    errored_files: AtomicUsize,
+    generated_files: AtomicUsize,
+    low_information_chunks: AtomicUsize,
+    undecodable_files: AtomicUsize,
+    binary_content_files: AtomicUsize,
+    /// Files over --max-chunks-per-file that were dropped
+    chunk_limit_files: AtomicUsize,
+    /// Files over --max-chunks-per-file that kept only their first chunks
+    truncated_files: AtomicUsize,
+    /// Scan-time skips: files over --max-file-size, outside --include/--exclude,
+    /// or turned away by the extension and name lists
+    too_large_files: AtomicUsize,
+    excluded_files: AtomicUsize,
+    filtered_files: AtomicUsize,
+    /// Files and directories matched by an ignore rule or an ignored directory
+    /// name; an ignored directory counts once, not per file in it
+    ignored_entries: AtomicUsize,
+    too_deep_dirs: AtomicUsize,
+}
+
+impl RunStats {
+    fn report(&self, detect_encoding: bool) {
+        let unknown_language = self.unknown_language.load(Ordering::Relaxed);
+        if unknown_language > 0 {
+            info!("Skipped {} files with no recognised language", unknown_language);
+        }
+        let generated_files = self.generated_files.load(Ordering::Relaxed);
+        if generated_files > 0 {
+            info!("Skipped {} files marked as generated", generated_files);
+        }
+        let low_information_chunks = self.low_information_chunks.load(Ordering::Relaxed);
+        if low_information_chunks > 0 {
+            info!("Dropped {} chunks with too few distinct tokens", low_information_chunks);
+        }
+        let undecodable_files = self.undecodable_files.load(Ordering::Relaxed);
+        if undecodable_files > 0 {
+            match detect_encoding {
+                true => info!("Skipped {} files that couldn't be decoded as text", undecodable_files),
+                false => info!("Skipped {} files that aren't valid UTF-8 (pass --detect-encoding to decode other encodings)", undecodable_files),
+            }
+        }
+        let binary_content_files = self.binary_content_files.load(Ordering::Relaxed);
+        if binary_content_files > 0 {
+            info!("Skipped {} files whose content looks binary", binary_content_files);
+        }
+        let chunk_limit_files = self.chunk_limit_files.load(Ordering::Relaxed);
+        if chunk_limit_files > 0 {
+            info!("Skipped {} files over --max-chunks-per-file (consider ignore rules for them)", chunk_limit_files);
+        }
+        let truncated_files = self.truncated_files.load(Ordering::Relaxed);
+        if truncated_files > 0 {
+            info!("Truncated {} files to --max-chunks-per-file chunks", truncated_files);
+        }
+        let errored_files = self.errored_files.load(Ordering::Relaxed);
+        if errored_files > 0 {
+            info!("Failed to read {} files", errored_files);
+        }
+    }
+
+    /// Non-zero skip counts keyed by reason, for the JSON summary.
+    fn skipped_files(&self) -> BTreeMap<String, usize> {
+        [
+            ("unknown_language", &self.unknown_language),
+            ("generated", &self.generated_files),
+            ("undecodable", &self.undecodable_files),
+            ("binary_content", &self.binary_content_files),
+            ("too_many_chunks", &self.chunk_limit_files),
+            ("read_error", &self.errored_files),
+            ("too_large", &self.too_large_files),
+            ("excluded", &self.excluded_files),
+            ("filtered", &self.filtered_files),
+            ("ignored", &self.ignored_entries),
+            ("max_depth_dirs", &self.too_deep_dirs),
+            ("truncated", &self.truncated_files),
+            ("low_information_chunks", &self.low_information_chunks),
+        ]
+        .into_iter()
+        .map(|(reason, count)| (reason.to_string(), count.load(Ordering::Relaxed)))
+        .filter(|(_, count)| *count > 0)
+        .collect()
+    }
+}
+
+/// What to do with a file that chunks into more than --max-chunks-per-file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChunkLimitPolicy {
+    /// Leave the whole file out
+    Skip,
+    /// Keep its first chunks, up to the limit
+    Truncate,
+}
+
+/// What a completed run did. Non-zero `errored_files` or `skipped_chunks`
+/// makes it a partial success.
+#[derive(Debug, Default)]
+pub struct IndexOutcome {
+    pub errored_files: usize,
+    pub skipped_chunks: usize,
+    /// The branch was already indexed at this commit, so nothing was scanned
+    pub already_indexed: bool,
+    pub files_scanned: usize,
+    /// Files that produced at least one chunk
+    pub files_indexed: usize,
+    pub skipped_files: BTreeMap<String, usize>,
+    /// Chunks written to the store
+    pub chunks: usize,
+    pub embedding_batches: usize,
+    /// Ctrl-C stopped the run before every batch was uploaded
+    pub interrupted: bool,
+    /// Counts per `--directory`, when there are several
+    pub directories: Vec<DirectorySummary>,
+}
+
+/// One root's share of a multi-root run.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DirectorySummary {
+    pub directory: String,
+    pub git_branch: String,
+    pub git_commit: String,
+    pub files_scanned: usize,
+    /// Files that produced at least one chunk
+    pub files_indexed: usize,
+    /// Chunks generated from the root's files
+    pub chunks: usize,
+}
+
+/// The `--json-summary` report of an indexing run.
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub git_branch: String,
+    pub git_commit: String,
+    pub already_indexed: bool,
+    pub files_scanned: usize,
+    pub files_indexed: usize,
+    /// Files left out of the run, by reason. `max_depth_dirs` counts
+    /// directories and `low_information_chunks` chunks instead
+    pub files_skipped: BTreeMap<String, usize>,
+    pub chunks: usize,
+    pub chunks_skipped: usize,
+    pub embedding_batches: usize,
+    pub elapsed_secs: f64,
+    pub partial: bool,
+    pub interrupted: bool,
+    /// Per-root counts; only written for runs over several roots
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub directories: Vec<DirectorySummary>,
+}
+
+impl RunSummary {
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json + "\n").with_context(|| format!("Failed to write JSON summary {}", path.display()))
+    }
+}
+
+impl IndexOutcome {
+    pub fn is_partial(&self) -> bool {
+        self.errored_files > 0 || self.skipped_chunks > 0
+    }
+}
+
+pub struct IndexOptions {
+    /// Chunks per `add` request to the vector store
+    pub upload_batch_size: usize,
+    pub on_bad_embedding: BadEmbeddingPolicy,
+    pub max_chunks: Option<usize>,
+    pub resume_manifest: Option<PathBuf>,
+    /// Where to list every chunk id the run produced and what happened to it
+    pub manifest: Option<PathBuf>,
+    pub reindex_order: ReindexOrder,
+    /// Tag chunks of earlier commits as archived instead of deleting them
+    pub archive_old_commits: bool,
+    /// Delete the branch's archived chunks before indexing
+    pub purge_archived: bool,
+    pub detect_encoding: bool,
+    pub store_embedding_norm: bool,
+    pub require_known_language: bool,
+    pub max_inflight_embeddings: usize,
+    pub modified_since_last_index: bool,
+    /// Only re-index files changed between this git ref and HEAD
+    pub since_ref: Option<String>,
+    pub transforms: TransformPipeline,
+    pub filter: FileFilter,
+    pub append_only: bool,
+    pub stats_retries: usize,
+    pub file_summary_chunks: bool,
+    pub with_blame: bool,
+    pub embed_prefix: Option<String>,
+    /// Lowercase markers that flag a file as generated; empty to index generated files
+    pub generated_markers: Vec<String>,
+    pub checkpoint_interval: usize,
+    /// Files (relative to the indexed directory) with uncommitted changes
+    pub dirty_files: HashSet<String>,
+    /// Files (relative to the indexed directory) whose chunks are rebuilt
+    /// whatever the incremental checks say
+    pub force_reindex: HashSet<String>,
+    /// Drop chunks with fewer distinct whitespace-delimited tokens than this
+    pub min_distinct_tokens: Option<usize>,
+    /// Show progress bars when stdout is a terminal
+    pub progress: bool,
+    /// Embed and store chunks with identical text once per run
+    pub dedup_chunks: bool,
+    /// Cap on chunks from a single file, and what happens to files over it
+    pub max_chunks_per_file: Option<usize>,
+    pub on_chunk_limit: ChunkLimitPolicy,
+    /// Bounds how many files are open at once, whatever the thread count
+    pub read_permits: Semaphore,
+    /// `--directory` roots scanned at once
+    pub dir_concurrency: usize,
+}
+
+/// Set by the Ctrl-C handler; indexing stops at the next batch boundary.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// The first Ctrl-C lets in-flight batches finish so none is left
+/// half-uploaded; a second one exits straight away.
+fn install_interrupt_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(EXIT_INTERRUPTED.into());
+        }
+        warn!("Interrupted: finishing in-flight batches (Ctrl-C again to stop now)");
+    })
+    .context("Failed to install the Ctrl-C handler")
+}
+
+/// A progress bar counting `len` items, or `None` when bars are disabled or
+/// stdout isn't a terminal, in which case callers print periodic lines instead.
+fn progress_bar(enabled: bool, len: usize, label: &str, unit: &str) -> Option<ProgressBar> {
+    if !enabled || !std::io::stdout().is_terminal() {
+        return None;
+    }
+    let template = format!("{{msg}} [{{bar:40}}] {{pos}}/{{len}} {} ({{per_sec}}, ETA {{eta}})", unit);
+    let bar = ProgressBar::new(len as u64)
+        .with_style(ProgressStyle::with_template(&template).ok()?.progress_chars("=> "))
+        .with_message(label.to_string());
+    Some(bar)
+}
+
+/// Keeps the first chunk for each distinct embedding input, recording every
+/// file that held it in `source_files`. Returns the number of chunks dropped.
+fn dedup_chunks(chunks: &mut Vec<Chunk>) -> usize {
+    let before = chunks.len();
+    let mut first: HashMap<String, usize> = HashMap::new();
+    let mut files: Vec<Vec<String>> = Vec::new();
+    let mut kept = Vec::with_capacity(chunks.len());
+    for chunk in chunks.drain(..) {
+        let hash = content_hash(chunk.embedding_input());
+        match first.get(&hash) {
+            Some(&index) => {
+                if !files[index].contains(&chunk.metadata.file_path) {
+                    files[index].push(chunk.metadata.file_path);
+                }
+            }
+            None => {
+                first.insert(hash, kept.len());
+                files.push(vec![chunk.metadata.file_path.clone()]);
+                kept.push(chunk);
+            }
+        }
+    }
+    for (chunk, files) in kept.iter_mut().zip(files) {
+        if files.len() > 1 {
+            chunk.metadata.source_files = serde_json::to_string(&files).ok();
+        }
+    }
+    *chunks = kept;
+    before - chunks.len()
+}
+
+/// Roots at the same branch and commit, checked against the store and
+/// cleaned up together. Roots from one repository share a group.
+struct RootGroup<'a> {
+    roots: Vec<&'a SourceRoot>,
+    git_commit: &'a str,
+    git_branch: &'a str,
+    /// Collections that still need this commit
+    pending: HashSet<String>,
+    /// Only uncommitted and forced files are re-indexed
+    refresh_only: bool,
+}
+
+impl<'a> RootGroup<'a> {
+    fn of(roots: &'a [SourceRoot]) -> Vec<RootGroup<'a>> {
+        let mut groups: Vec<RootGroup> = Vec::new();
+        for root in roots {
+            match groups.iter_mut().find(|group| group.roots[0].same_git(root)) {
+                Some(group) => group.roots.push(root),
+                None => groups.push(RootGroup {
+                    roots: vec![root],
+                    git_commit: &root.git_commit,
+                    git_branch: &root.git_branch,
+                    pending: HashSet::new(),
+                    refresh_only: false,
+                }),
+            }
+        }
+        groups
+    }
+
+    fn contains(&self, root: &SourceRoot) -> bool {
+        self.roots[0].same_git(root)
+    }
+
+    /// Whether a stored `file_path` is under one of the group's roots.
+    fn owns(&self, relative: &str) -> bool {
+        self.roots.iter().any(|root| root.owns(relative))
+    }
+
+    fn name(&self) -> String {
+        self.roots.iter().map(|root| root.name()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+pub struct CodebaseIndexer {
+    collections: BTreeMap<String, Arc<dyn VectorStore>>,
+    router: CollectionRouter,
+    embedding_client: Box<dyn Embedder>,
+    chunker: CodeChunker,
+    git_commit: String,
+    git_branch: String,
+    version: Option<String>,
+    embedding_cache: Option<EmbeddingCache>,
+}
+
+impl CodebaseIndexer {
+    pub fn new(
+        collections: BTreeMap<String, Arc<dyn VectorStore>>,
+        router: CollectionRouter,
+        embedding_client: Box<dyn Embedder>,
+        chunker: CodeChunker,
+    ) -> Self {
+        let git_commit = chunker.git_commit.clone();
+        let git_branch = chunker.git_branch.clone();
+        let version = chunker.version.clone();
+
+        Self { collections, router, embedding_client, chunker, git_commit, git_branch, version, embedding_cache: None }
+    }
+
+    /// Fails if any collection was built with a model other than `model`.
+    pub fn check_embedding_model(&self, model: Option<&str>) -> Result<()> {
+        for (name, collection) in &self.collections {
+            let stored = collection.metadata_value(EMBEDDING_MODEL_KEY);
+            check_embedding_model(name, stored.as_ref().and_then(|v| v.as_str()), model)?;
+        }
+        Ok(())
+    }
+
+    pub fn run_summary(&self, outcome: &IndexOutcome, elapsed: Duration) -> RunSummary {
+        RunSummary {
+            git_branch: self.git_branch.clone(),
+            git_commit: self.git_commit.clone(),
+            already_indexed: outcome.already_indexed,
+            files_scanned: outcome.files_scanned,
+            files_indexed: outcome.files_indexed,
+            files_skipped: outcome.skipped_files.clone(),
+            chunks: outcome.chunks,
+            chunks_skipped: outcome.skipped_chunks,
+            embedding_batches: outcome.embedding_batches,
+            elapsed_secs: elapsed.as_secs_f64(),
+            partial: outcome.is_partial(),
+            interrupted: outcome.interrupted,
+            directories: outcome.directories.clone(),
+        }
+    }
+
+    pub fn with_embedding_cache(mut self, cache: EmbeddingCache) -> Self {
+        self.embedding_cache = Some(cache);
+        self
+    }
+
+    /// Embeds `chunks`, serving what it can from the embedding cache and only
+    /// sending misses to the embedding service.
+    fn embed_chunks(&self, chunks: &[Chunk], inflight: &Semaphore) -> Result<Vec<Vec<f32>>> {
+        let Some(cache) = &self.embedding_cache else {
+            let texts: Vec<&str> = chunks.iter().map(Chunk::embedding_input).collect();
+            let _permit = inflight.acquire();
+            return self.embedding_client.encode(&texts);
+        };
+
+        let digests: Vec<String> = chunks.iter().map(|c| content_digest(c.embedding_input())).collect();
+        let mut embeddings = cache.get_many(&digests)?;
+        let missing: Vec<usize> = (0..chunks.len()).filter(|&i| embeddings[i].is_none()).collect();
+
+        if !missing.is_empty() {
+            let texts: Vec<&str> = missing.iter().map(|&i| chunks[i].embedding_input()).collect();
+            let fresh = {
+                let _permit = inflight.acquire();
+                self.embedding_client.encode(&texts)?
+            };
+            if fresh.len() != missing.len() {
+                anyhow::bail!("Embedding service returned {} vectors for {} inputs", fresh.len(), missing.len());
+            }
+            for (i, embedding) in missing.into_iter().zip(fresh) {
+                embeddings[i] = Some(embedding);
+            }
+        }
+
+        Ok(embeddings.into_iter().map(Option::unwrap_or_default).collect())
+    }
+
+    /// Stores validated embeddings so later runs can reuse them.
+    fn cache_embeddings(&self, chunks: &[Chunk], embeddings: &[Vec<f32>]) -> Result<()> {
+        let Some(cache) = &self.embedding_cache else { return Ok(()) };
+        let entries: Vec<(String, &[f32])> = chunks
+            .iter()
+            .zip(embeddings)
+            .map(|(c, e)| (content_digest(c.embedding_input()), e.as_slice()))
+            .collect();
+        cache.put_many(&entries)
+    }
+
+    /// Reports chunk counts. Runs after the real work is done, so a failing count
+    /// query is retried and then shown as "unknown" rather than failing the run.
+    fn print_counts(&self, label: &str, retries: usize) {
+        let show = |count: Option<usize>| count.map_or_else(|| "unknown".to_string(), |c| c.to_string());
+        if self.collections.len() == 1 {
+            let total = self.collections.values().next().and_then(|chroma| chroma.count_with_retries(retries));
+            info!("{}: {}", label, show(total));
+            return;
+        }
+        for (name, chroma) in &self.collections {
+            info!("{} [{}]: {}", label, name, show(chroma.count_with_retries(retries)));
+        }
+    }
+
+    /// Removes or archives chunks on the group's branch from every commit but
+    /// `live_commits`, the commits every root of the run is at.
+    fn cleanup_old_commits<'a>(&self, names: impl IntoIterator<Item = &'a str>, group: &RootGroup, live_commits: &[&str], archive: bool) -> Result<()> {
+        if group.git_commit.is_empty() || group.git_branch.is_empty() {
+            return Ok(());
+        }
+        let git_branch = group.git_branch;
+        let version = self.version.as_deref();
+        for name in names {
+            let collection = &self.collections[name];
+            if archive {
+                let archived = collection.archive_old_commits(git_branch, version, live_commits)?;
+                if archived > 0 {
+                    info!("Archived {} old chunks in {}", archived, name);
+                }
+                continue;
+            }
+            let deleted = collection.delete_old_commits(git_branch, version, live_commits)?;
+            if deleted > 0 {
+                info!("Cleaned up {} old chunks from {}", deleted, name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the branch's archived chunks from every collection.
+    fn purge_archived(&self, git_branch: &str) -> Result<()> {
+        for (name, collection) in &self.collections {
+            let purged = collection.purge_archived(git_branch, self.version.as_deref())?;
+            info!("Purged {} archived chunks from {}", purged, name);
+        }
+        Ok(())
+    }
+
+    /// Warns about collections created with a different embed prefix; mixing
+    /// prefixes in one collection quietly degrades retrieval.
+    fn check_embed_prefix(&self, prefix: Option<&str>) {
+        for (name, collection) in &self.collections {
+            let stored = collection.metadata_value(EMBED_PREFIX_KEY);
+            let stored = stored.as_ref().and_then(|v| v.as_str());
+            if stored != prefix {
+                warn!("{} was created with embed prefix {:?}, but this run uses {:?}", name, stored.unwrap_or_default(), prefix.unwrap_or_default());
+            }
+        }
+    }
+
+    /// Drops this branch's chunks from collections where they were written
+    /// with another `SCHEMA_VERSION`, so they're rebuilt in full. Returns
+    /// whether any were dropped.
+    fn reset_stale_schema(&self, git_branch: &str) -> Result<bool> {
+        let version = self.version.as_deref();
+        let mut reset = false;
+        for (name, collection) in &self.collections {
+            let Some(schema) = collection.branch_schema(git_branch, version)? else { continue };
+            if schema == SCHEMA_VERSION {
+                continue;
+            }
+            warn!("{} holds chunks of {} from schema version {}, not {}; re-indexing the branch in full", name, git_branch, schema, SCHEMA_VERSION);
+            collection.delete_branch(git_branch, version)?;
+            reset = true;
+        }
+        Ok(reset)
+    }
+
+    /// Oldest `last_full_index_ts` across the target collections, or `None` if
+    /// any of them has never recorded a full run.
+    fn last_full_index(&self) -> Option<u64> {
+        self.collections
+            .values()
+            .map(|chroma| chroma.metadata_value(LAST_FULL_INDEX_KEY)?.as_u64())
+            .collect::<Option<Vec<u64>>>()?
+            .into_iter()
+            .min()
+    }
+
+    /// Identifies a branch, version and commit in `INCOMPLETE_INDEX_KEY`.
+    fn incomplete_marker(&self, git_branch: &str, git_commit: &str) -> String {
+        format!("{}@{}@{}", git_branch, self.version.as_deref().unwrap_or_default(), git_commit)
+    }
+
+    fn incomplete_markers(&self, name: &str) -> Vec<String> {
+        let value = self.collections[name].metadata_value(INCOMPLETE_INDEX_KEY);
+        value.as_ref().and_then(|v| v.as_str()).unwrap_or_default().split_whitespace().map(str::to_string).collect()
+    }
+
+    fn is_incomplete(&self, name: &str, marker: &str) -> bool {
+        self.incomplete_markers(name).iter().any(|m| m == marker)
+    }
+
+    /// Flags `names` as partly uploaded at the commit `marker` names, or
+    /// clears the flag, leaving other repositories' flags alone.
+    fn set_incomplete<'a>(&self, names: impl Iterator<Item = &'a str>, marker: &str, incomplete: bool) -> Result<()> {
+        for name in names {
+            let mut markers = self.incomplete_markers(name);
+            if incomplete == markers.iter().any(|m| m == marker) {
+                continue;
+            }
+            match incomplete {
+                true => markers.push(marker.to_string()),
+                false => markers.retain(|m| m != marker),
+            }
+            let mut updates = serde_json::Map::new();
+            updates.insert(INCOMPLETE_INDEX_KEY.to_string(), markers.join(" ").into());
+            self.collections[name].update_metadata(updates)?;
+        }
+        Ok(())
+    }
+
+    fn record_full_index(&self, started: u64) -> Result<()> {
+        for chroma in self.collections.values() {
+            let mut updates = serde_json::Map::new();
+            updates.insert(LAST_FULL_INDEX_KEY.to_string(), started.into());
+            chroma.update_metadata(updates)?;
+        }
+        Ok(())
+    }
+
+    pub fn index(&self, roots: &[SourceRoot], options: &IndexOptions) -> Result<IndexOutcome> {
+        let names: Vec<String> = roots.iter().map(|root| root.path.display().to_string()).collect();
+        info!("Indexing {}...", names.join(", "));
+        let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        // Roots from different repositories are checked and cleaned up apart
+        let groups = RootGroup::of(roots);
+        let branches: BTreeSet<&str> = groups.iter().map(|group| group.git_branch).filter(|branch| !branch.is_empty()).collect();
+
+        // Chunks from an older schema can't be updated piecemeal
+        let mut schema_reset = false;
+        if !options.append_only {
+            for branch in &branches {
+                schema_reset |= self.reset_stale_schema(branch)?;
+            }
+        }
+
+        // With --modified-since-last-index, only files touched since the last full
+        // run are re-chunked; everything else keeps its existing chunks
+        let since = if options.modified_since_last_index && !schema_reset {
+            let since = self.last_full_index();
+            if since.is_none() {
+                info!("No previous full index recorded; indexing everything");
+            }
+            since
+        } else {
+            None
+        };
+        // With --since, only files changed relative to the ref are re-chunked
+        let changed = match options.since_ref.as_ref().filter(|_| !schema_reset) {
+            Some(git_ref) => {
+                let mut changed = Vec::new();
+                for root in roots {
+                    changed.extend(changed_since(&root.path, git_ref)?.into_iter().map(|path| root.labelled(path)));
+                }
+                Some(changed)
+            }
+            None => None,
+        };
+        if options.purge_archived {
+            for branch in &branches {
+                self.purge_archived(branch)?;
+            }
+        }
+
+        // --append-only assumes an empty collection: no commit check, no cleanup
+        let cleanup_commits = since.is_none() && changed.is_none() && !options.append_only;
+        if options.append_only {
+            info!("Append-only: skipping index checks and cleanup");
+        } else {
+            self.check_embed_prefix(options.embed_prefix.as_deref());
+        }
+
+        let manifest = match &options.resume_manifest {
+            Some(path) => {
+                let branches: Vec<&str> = groups.iter().map(|group| group.git_branch).collect();
+                let commits: Vec<&str> = groups.iter().map(|group| group.git_commit).collect();
+                Some(ResumeManifest::load(path, &branches.join(","), &commits.join(","))?)
+            }
+            None => None,
+        };
+        if let Some(path) = &options.resume_manifest {
+            info!("Checkpointing to {} every {} batches", path.display(), options.checkpoint_interval.max(1));
+        }
+        let resuming = manifest.as_ref().is_some_and(ResumeManifest::is_resuming);
+        if resuming {
+            info!("Resuming: {} chunks already uploaded", manifest.as_ref().map_or(0, |m| m.uploaded.len()));
+        }
+
+        // Cleanup keeps every root's current commit, so one repository never
+        // removes another's chunks when they share a branch name
+        let live_commits: Vec<&str> = groups.iter().map(|group| group.git_commit).collect();
+        let group_count = groups.len();
+        let all_collections = || self.collections.keys().cloned().collect::<HashSet<String>>();
+
+        // Check if already indexed, per repository and collection
+        let mut active = Vec::new();
+        for mut group in groups {
+            group.pending = all_collections();
+            let (git_branch, git_commit) = (group.git_branch, group.git_commit);
+            if !git_commit.is_empty() && !git_branch.is_empty() {
+                if !resuming && !options.append_only {
+                    let marker = self.incomplete_marker(git_branch, git_commit);
+                    group.pending.retain(|name| {
+                        !self.collections[name].is_commit_indexed(git_branch, self.version.as_deref(), git_commit) || self.is_incomplete(name, &marker)
+                    });
+                }
+
+                // The commit itself is indexed; only the uncommitted files need
+                // refreshing, replacing whatever dirty chunks an earlier run left
+                let dirty = options.dirty_files.iter().filter(|relative| group.owns(relative)).count();
+                if group.pending.is_empty() && dirty > 0 {
+                    info!("Commit already indexed; re-indexing {} uncommitted files", dirty);
+                    group.refresh_only = true;
+                    group.pending = all_collections();
+                    for collection in self.collections.values() {
+                        collection.delete_dirty(git_branch, self.version.as_deref(), git_commit)?;
+                    }
+                }
+                let forced = options.force_reindex.iter().filter(|relative| group.owns(relative)).count();
+                if group.pending.is_empty() && forced > 0 {
+                    info!("Commit already indexed; re-indexing {} forced files", forced);
+                    group.refresh_only = true;
+                    group.pending = all_collections();
+                }
+
+                if group.pending.is_empty() {
+                    let short = &git_commit[..8.min(git_commit.len())];
+                    match group_count {
+                        1 => info!("Branch {} at commit {} already indexed.", git_branch, short),
+                        _ => info!("{}: branch {} at commit {} already indexed.", group.name(), git_branch, short),
+                    }
+                    if cleanup_commits && options.reindex_order == ReindexOrder::AddFirst {
+                        // An interrupted add-first run can leave the previous commit behind
+                        self.cleanup_old_commits(self.collections.keys().map(String::as_str), &group, &live_commits, options.archive_old_commits)?;
+                    }
+                    continue;
+                }
+
+                // Clean up old commits for this branch
+                if cleanup_commits && !group.refresh_only && options.reindex_order == ReindexOrder::DeleteFirst {
+                    self.cleanup_old_commits(group.pending.iter().map(String::as_str), &group, &live_commits, options.archive_old_commits)?;
+                }
+            }
+            active.push(group);
+        }
+        if active.is_empty() {
+            self.print_counts("Total chunks", options.stats_retries);
+            if let Some(path) = &options.manifest {
+                write_run_manifest(path, [])?;
+            }
+            return Ok(IndexOutcome { already_indexed: true, ..IndexOutcome::default() });
+        }
+        // Old commits are only cleaned up after a full run of the repository
+        let cleans_up = |group: &RootGroup| cleanup_commits && !group.refresh_only;
+        let group_of = |relative: &str| active.iter().find(|group| group.owns(relative));
+
+        // Scan files
+        info!("Scanning...");
+        let scanned: Vec<&SourceRoot> = active.iter().flat_map(|group| group.roots.iter().copied()).collect();
+        let stats = RunStats::default();
+        let mut files = scan_roots(&scanned, &options.filter, options.dir_concurrency, &stats)?;
+        let files_scanned = files.len();
+        let mut directories: Vec<(&SourceRoot, DirectorySummary)> = scanned
+            .iter()
+            .map(|root| {
+                let summary = DirectorySummary {
+                    directory: root.name(),
+                    git_branch: root.git_branch.clone(),
+                    git_commit: root.git_commit.clone(),
+                    files_scanned: files.iter().filter(|(r, _)| r.path == root.path).count(),
+                    ..DirectorySummary::default()
+                };
+                (*root, summary)
+            })
+            .collect();
+
+        // An mtime-based run never sees deleted files, so drop chunks of
+        // anything indexed that the scan no longer finds
+        if since.is_some() {
+            for group in &active {
+                self.prune_missing_files(group, &files)?;
+            }
+        }
+
+        // Forced files lose their chunks up front and survive every filter below
+        if !options.force_reindex.is_empty() {
+            let found: HashSet<String> = files.iter().map(|(root, path)| root.relative(path)).collect();
+            let mut forced: Vec<&String> = options.force_reindex.iter().collect();
+            forced.sort();
+            for relative in forced {
+                if !found.contains(relative) {
+                    warn!("--force-reindex {}: not found by the scan (missing or filtered out); its chunks are only removed", relative);
+                }
+                if let Some(group) = group_of(relative) {
+                    self.delete_file_chunks(group.git_branch, relative)?;
+                }
+            }
+        }
+        let forced = |root: &SourceRoot, path: &Path| options.force_reindex.contains(&root.relative(path));
+
+        if active.iter().any(|group| group.refresh_only) {
+            files.retain(|(root, path)| {
+                let relative = root.relative(path);
+                let refresh_only = active.iter().any(|group| group.refresh_only && group.contains(root));
+                !refresh_only || options.dirty_files.contains(&relative) || options.force_reindex.contains(&relative)
+            });
+        }
+
+        if let Some(since) = since {
+            let cutoff = UNIX_EPOCH + Duration::from_secs(since.saturating_sub(MTIME_SLACK_SECS));
+            files.retain(|(root, path)| {
+                forced(root, path) || path.metadata().and_then(|m| m.modified()).map_or(true, |mtime| mtime >= cutoff)
+            });
+            info!("{} files modified since last full index", files.len());
+
+            for (root, path) in &files {
+                self.delete_file_chunks(&root.git_branch, &root.relative(path))?;
+            }
+        }
+
+        if let (Some(changed), Some(git_ref)) = (&changed, &options.since_ref) {
+            let changed_set: HashSet<&str> = changed.iter().map(String::as_str).collect();
+            files.retain(|(root, path)| forced(root, path) || changed_set.contains(root.relative(path).as_str()));
+            info!("{} files changed since {} ({} still present)", changed.len(), git_ref, files.len());
+
+            // Deleted files are in the diff too, so their chunks go as well
+            for relative in changed {
+                if let Some(group) = group_of(relative) {
+                    self.delete_file_chunks(group.git_branch, relative)?;
+                }
+            }
+        }
+
+        if files.is_empty() {
+            if options.reindex_order == ReindexOrder::AddFirst {
+                for group in active.iter().filter(|group| cleans_up(group)) {
+                    self.cleanup_old_commits(group.pending.iter().map(String::as_str), group, &live_commits, options.archive_old_commits)?;
+                }
+            }
+            if let Some(path) = &options.manifest {
+                write_run_manifest(path, [])?;
+            }
+            let directories = match roots.len() {
+                1 => Vec::new(),
+                _ => directories.into_iter().map(|(_, summary)| summary).collect(),
+            };
+            return Ok(IndexOutcome { files_scanned, directories, skipped_files: stats.skipped_files(), ..IndexOutcome::default() });
+        }
+
+        // Process files in parallel to generate chunks
+        let processed = Arc::new(Mutex::new(0usize));
+        let total = files.len();
+        let files_indexed = AtomicUsize::new(0);
+        let file_bar = progress_bar(options.progress, total, "Chunking", "files");
+        // Each root's chunks carry its own commit and branch
+        let chunkers: HashMap<&Path, CodeChunker> = scanned.iter().map(|root| (root.path.as_path(), self.chunker.for_root(root))).collect();
+
+        let mut chunks: Vec<Chunk> = files
+            .par_iter()
+            .filter_map(|(root, path)| {
+                let file_chunks = chunkers[root.path.as_path()].chunk_file(root, path, options, &stats);
+                if let Some(bar) = &file_bar {
+                    bar.inc(1);
+                }
+                let file_chunks = file_chunks?;
+                if !file_chunks.is_empty() {
+                    files_indexed.fetch_add(1, Ordering::Relaxed);
+                }
+
+                if file_bar.is_none() {
+                    let mut count = processed.lock().unwrap();
+                    *count += 1;
+                    if *count % 100 == 0 {
+                        info!("Processed {}/{} files", *count, total);
+                    }
+                }
+
+                Some(file_chunks)
+            })
+            .flatten()
+            .collect();
+        if let Some(bar) = file_bar {
+            bar.finish_and_clear();
+        }
+
+        for (root, summary) in &mut directories {
+            let owned: Vec<&Chunk> = chunks.iter().filter(|chunk| root.owns(&chunk.metadata.file_path)).collect();
+            summary.chunks = owned.len();
+            summary.files_indexed = owned.iter().map(|chunk| chunk.metadata.file_path.as_str()).collect::<HashSet<_>>().len();
+        }
+        match directories.len() {
+            1 => info!("Generated {} chunks", chunks.len()),
+            _ => {
+                let counts: Vec<String> = directories.iter().map(|(_, summary)| format!("{}: {}", summary.directory, summary.chunks)).collect();
+                info!("Generated {} chunks ({})", chunks.len(), counts.join(", "));
+            }
+        }
+        stats.report(options.detect_encoding);
+        if options.dedup_chunks {
+            let duplicates = dedup_chunks(&mut chunks);
+            if duplicates > 0 {
+                info!("Collapsed {} duplicate chunks; {} left to embed", duplicates, chunks.len());
+            }
+        }
+
+        // Group by target collection so every batch goes to a single collection
+        let mut routed: BTreeMap<&str, Vec<Chunk>> = BTreeMap::new();
+        let mut to_upload = 0usize;
+        // Chunks left out here, kept only for --manifest
+        let mut held_back: Vec<(&str, Chunk, ChunkStatus)> = Vec::new();
+        for chunk in chunks {
+            let target = self.router.route(&chunk.metadata.file_type);
+            let status = if manifest.as_ref().is_some_and(|m| m.uploaded.contains(&chunk.id)) {
+                ChunkStatus::Resumed
+            } else if !group_of(&chunk.metadata.file_path).is_some_and(|group| group.pending.contains(target)) {
+                ChunkStatus::AlreadyIndexed
+            } else {
+                routed.entry(target).or_default().push(chunk);
+                to_upload += 1;
+                continue;
+            };
+            if options.manifest.is_some() {
+                held_back.push((target, chunk, status));
+            }
+        }
+
+        // Upload with pipelining
+        let batches: Vec<(&str, &[Chunk])> = routed
+            .iter()
+            .flat_map(|(name, chunks)| chunks.chunks(options.upload_batch_size).map(move |b| (*name, b)))
+            .collect();
+        let total_batches = batches.len();
+
+        // Apply --max-chunks up front so parallel batches can't overshoot it
+        let mut budget = options.max_chunks.unwrap_or(usize::MAX);
+        let mut planned = Vec::with_capacity(batches.len());
+        for (name, batch) in batches {
+            if budget == 0 { break; }
+            let batch = &batch[..batch.len().min(budget)];
+            budget -= batch.len();
+            planned.push((name, batch));
+        }
+        let sent: usize = planned.iter().map(|(_, batch)| batch.len()).sum();
+
+        let (tx, rx) = mpsc::channel::<(String, Vec<Chunk>, Vec<Vec<f32>>)>();
+        let collections = self.collections.clone();
+        let manifest_path = options.resume_manifest.clone();
+        let mut upload_manifest = manifest;
+        let checkpoint_interval = options.checkpoint_interval.max(1);
+        let planned_batches = planned.len();
+        let track_uploaded = options.manifest.is_some();
+
+        // Checkpoints run here, off the embedding threads, so a slow manifest
+        // write only delays uploads
+        let upload_thread = thread::spawn(move || -> Result<HashSet<String>> {
+            let upload_started = Instant::now();
+            let mut uploaded_batches = 0usize;
+            let mut unsaved = false;
+            let mut uploaded_ids = HashSet::new();
+            let mut failure = None;
+            while let Ok((name, chunks, embeddings)) = rx.recv() {
+                // Stop taking batches but still save what made it, so a re-run
+                // with the manifest resumes from here
+                if let Err(err) = collections[&name].add_chunks(&chunks, embeddings) {
+                    failure = Some(err);
+                    break;
+                }
+                uploaded_batches += 1;
+                debug!(collection = %name, chunks = chunks.len(), "uploaded batch");
+                if track_uploaded {
+                    uploaded_ids.extend(chunks.iter().map(|c| c.id.clone()));
+                }
+                if let Some(manifest) = &mut upload_manifest {
+                    manifest.uploaded.extend(chunks.iter().map(|c| c.id.clone()));
+                    unsaved = true;
+                }
+
+                if uploaded_batches % checkpoint_interval == 0 {
+                    let elapsed = upload_started.elapsed().as_secs();
+                    let remaining = elapsed * (planned_batches.saturating_sub(uploaded_batches)) as u64 / uploaded_batches as u64;
+                    info!("Checkpoint: {}/{} batches uploaded, {}s elapsed, ~{}s remaining", uploaded_batches, planned_batches, elapsed, remaining);
+                    if let (Some(path), Some(manifest)) = (&manifest_path, &mut upload_manifest) {
+                        manifest.uploaded_batches = uploaded_batches;
+                        manifest.planned_batches = planned_batches;
+                        manifest.elapsed_secs = elapsed;
+                        manifest.remaining_secs = Some(remaining);
+                        manifest.save(path)?;
+                        unsaved = false;
+                    }
+                }
+            }
+            if let (Some(path), Some(manifest), true) = (&manifest_path, &mut upload_manifest, unsaved) {
+                manifest.uploaded_batches = uploaded_batches;
+                manifest.planned_batches = planned_batches;
+                manifest.elapsed_secs = upload_started.elapsed().as_secs();
+                manifest.remaining_secs = None;
+                manifest.save(path)?;
+            }
+            match failure {
+                Some(err) => Err(err),
+                None => Ok(uploaded_ids),
+            }
+        });
+
+        // The store rejects vectors of another length with an unhelpful error,
+        // so catch a model swap on the first batch instead
+        let mut stored_dims = HashMap::new();
+        for name in routed.keys() {
+            if let Some(dimension) = self.collections[*name].dimension()? {
+                stored_dims.insert(*name, dimension);
+            }
+        }
+
+        let inflight = Semaphore::new(options.max_inflight_embeddings);
+        let expected_dim = Mutex::new(None);
+        let skipped = AtomicUsize::new(0);
+        let unsent_batches = AtomicUsize::new(0);
+        let unsent_chunks = AtomicUsize::new(0);
+        let embed_bar = progress_bar(options.progress, sent, "Embedding", "chunks");
+        let embedded = planned.par_iter().enumerate().try_for_each_with(tx, |tx, (i, (name, batch))| -> Result<()> {
+            if interrupted() {
+                unsent_batches.fetch_add(1, Ordering::Relaxed);
+                unsent_chunks.fetch_add(batch.len(), Ordering::Relaxed);
+                return Ok(());
+            }
+            if embed_bar.is_none() {
+                debug!("Batch {}/{}", i + 1, total_batches);
+            }
+            let embeddings = self.embed_chunks(batch, &inflight)?;
+            if let Some(bar) = &embed_bar {
+                bar.inc(batch.len() as u64);
+            }
+            let (mut batch, embeddings, bad) = validate_embeddings(batch, embeddings, &mut expected_dim.lock().unwrap(), options.on_bad_embedding)?;
+            skipped.fetch_add(bad, Ordering::Relaxed);
+            if let (Some(embedding), Some(&stored)) = (embeddings.first(), stored_dims.get(name)) {
+                if embedding.len() != stored {
+                    anyhow::bail!(
+                        "{} holds {}-dimensional vectors, but the embedding model returns {} dimensions; index into another --collection or re-embed it with `migrate`",
+                        name, stored, embedding.len()
+                    );
+                }
+            }
+            self.cache_embeddings(&batch, &embeddings)?;
+            if options.store_embedding_norm {
+                for (chunk, embedding) in batch.iter_mut().zip(&embeddings) {
+                    chunk.metadata.embedding_norm = Some(l2_norm(embedding));
+                }
+            }
+            if !batch.is_empty() && tx.send((name.to_string(), batch, embeddings)).is_err() {
+                // The upload thread stopped on an error, which it reports
+                anyhow::bail!("Upload stopped");
+            }
+            Ok(())
+        });
+        let skipped = skipped.into_inner();
+        if let Some(bar) = embed_bar {
+            bar.finish_and_clear();
+        }
+
+        // Wait for the upload thread even after a failed batch, so the chunks
+        // already stored are in the resume manifest before the error surfaces
+        let uploaded = upload_thread.join().map_err(|_| anyhow::anyhow!("Upload thread panicked"))?;
+        if let (Some(path), true) = (&options.resume_manifest, uploaded.is_err() || embedded.is_err()) {
+            warn!("Indexing failed; uploaded chunks are recorded in {}, re-run to resume", path.display());
+        }
+        let uploaded_ids = uploaded?;
+        embedded?;
+        if let Some(path) = &options.manifest {
+            let routed_chunks = routed.iter().flat_map(|(name, chunks)| {
+                chunks.iter().map(|chunk| {
+                    let status = if uploaded_ids.contains(&chunk.id) { ChunkStatus::Uploaded } else { ChunkStatus::NotUploaded };
+                    (*name, chunk, status)
+                })
+            });
+            write_run_manifest(path, routed_chunks.chain(held_back.iter().map(|(name, chunk, status)| (*name, chunk, *status))))?;
+        }
+
+        if skipped > 0 {
+            info!("Skipped {} chunks with bad embeddings", skipped);
+        }
+        if let Some(cache) = &self.embedding_cache {
+            cache.report();
+        }
+
+        let unsent_batches = unsent_batches.into_inner();
+        let unsent_chunks = unsent_chunks.into_inner();
+        let remaining = to_upload - sent;
+        if unsent_batches > 0 {
+            // Uploaded batches are complete; the marker keeps the next run from
+            // treating this commit as indexed
+            warn!(
+                "Interrupted: {} of {} batches uploaded; {} batches ({} chunks) remain unindexed. Re-run to continue.",
+                planned_batches - unsent_batches,
+                planned_batches,
+                unsent_batches + total_batches - planned_batches,
+                unsent_chunks + remaining
+            );
+            for group in &active {
+                let marker = self.incomplete_marker(group.git_branch, group.git_commit);
+                self.set_incomplete(group.pending.iter().map(String::as_str), &marker, true)?;
+            }
+        } else if remaining > 0 {
+            // As with an interrupt, the commit stays pending even if the
+            // next run doesn't pass the same manifest
+            info!("Reached --max-chunks limit: {} chunks remain unindexed. Re-run to continue.", remaining);
+            for group in &active {
+                let marker = self.incomplete_marker(group.git_branch, group.git_commit);
+                self.set_incomplete(group.pending.iter().map(String::as_str), &marker, true)?;
+            }
+        } else {
+            if let Some(path) = &options.resume_manifest {
+                if path.exists() {
+                    fs::remove_file(path).with_context(|| format!("Failed to remove resume manifest {}", path.display()))?;
+                }
+            }
+            if options.reindex_order == ReindexOrder::AddFirst {
+                for group in active.iter().filter(|group| cleans_up(group)) {
+                    self.cleanup_old_commits(group.pending.iter().map(String::as_str), group, &live_commits, options.archive_old_commits)?;
+                }
+            }
+            if active.iter().all(cleans_up) {
+                if let Err(err) = self.record_full_index(started) {
+                    warn!("failed to record full index time: {}", err);
+                }
+            }
+            for group in &active {
+                let marker = self.incomplete_marker(group.git_branch, group.git_commit);
+                self.set_incomplete(group.pending.iter().map(String::as_str), &marker, false)?;
+            }
+        }
+
+        self.print_counts("Done! Total chunks", options.stats_retries);
+        Ok(IndexOutcome {
+            errored_files: stats.errored_files.load(Ordering::Relaxed),
+            skipped_chunks: skipped,
+            already_indexed: false,
+            files_scanned,
+            files_indexed: files_indexed.into_inner(),
+            skipped_files: stats.skipped_files(),
+            chunks: sent - skipped - unsent_chunks,
+            embedding_batches: planned_batches - unsent_batches,
+            interrupted: unsent_batches > 0,
+            directories: match roots.len() {
+                1 => Vec::new(),
+                _ => directories.into_iter().map(|(_, summary)| summary).collect(),
+            },
+        })
+    }
+}
+
+// ============================================================================
+// File Processing
+// ============================================================================
+
+impl CodeChunker {
+    /// Reads one file and turns it into the chunks an indexing run would
+    /// upload, applying the file-level skips, filters and tagging in `options`.
+    /// `None` if the file is skipped or can't be read.
+    fn chunk_file(&self, root: &SourceRoot, path: &Path, options: &IndexOptions, stats: &RunStats) -> Option<Vec<Chunk>> {
+        let read = {
+            let _permit = options.read_permits.acquire();
+            read_text_file(path, options.detect_encoding)
+        };
+        let (content, encoding) = match read {
+            Ok(FileContent::Text(text, encoding)) => (text, encoding),
+            Ok(FileContent::Binary) => {
+                stats.binary_content_files.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            Ok(FileContent::Undecodable) => {
+                stats.undecodable_files.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            Err(err) => {
+                warn!(path = %path.display(), error = %err, "failed to read file");
+                stats.errored_files.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        if content.is_empty() { return None; }
+
+        if let Some(marker) = generated_marker(&content, &options.generated_markers) {
+            info!(path = %root.relative(path), marker, "skipping generated file");
+            stats.generated_files.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        if options.require_known_language && detect_language(path, &content).is_none() {
+            stats.unknown_language.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let relative = root.relative(path);
+        let mut file_chunks = self.chunk_code(&content, &relative);
+        if file_chunks.len() > FORCED_CHUNK_WARNING && root.filter(&options.filter).is_forced(Path::new(&relative)) {
+            warn!(path = %relative, chunks = file_chunks.len(), "force-indexed file produced many chunks");
+        }
+        if let Some(limit) = options.max_chunks_per_file.filter(|limit| file_chunks.len() > *limit) {
+            match options.on_chunk_limit {
+                ChunkLimitPolicy::Skip => {
+                    warn!(path = %relative, chunks = file_chunks.len(), limit, "skipping file over --max-chunks-per-file");
+                    stats.chunk_limit_files.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+                ChunkLimitPolicy::Truncate => {
+                    warn!(path = %relative, chunks = file_chunks.len(), limit, "truncating file over --max-chunks-per-file");
+                    stats.truncated_files.fetch_add(1, Ordering::Relaxed);
+                    file_chunks.truncate(limit);
+                }
+            }
+        }
+        if options.file_summary_chunks {
+            file_chunks.extend(self.summary_chunk(&content, &relative, detect_language(path, &content)));
+        }
+        file_chunks.retain(|c| is_embeddable(&c.text));
+        drop_low_information(&mut file_chunks, options.min_distinct_tokens, stats);
+        if options.with_blame {
+            if let Some(authors) = blame_authors(&root.path, &root.root_relative(path)) {
+                for chunk in &mut file_chunks {
+                    apply_blame(&mut chunk.metadata, &authors);
+                }
+            }
+        }
+        for chunk in &mut file_chunks {
+            options.transforms.apply(chunk);
+            if let Some(prefix) = &options.embed_prefix {
+                chunk.embed_text = Some(format!("{}{}", prefix, chunk.embedding_input()));
+            }
+        }
+        if options.dirty_files.contains(&relative) {
+            for chunk in &mut file_chunks {
+                chunk.id.push_str("_dirty");
+                chunk.metadata.git_commit = dirty_commit(&self.git_commit);
+                chunk.metadata.dirty = Some(true);
+            }
+        }
+        if let Some(encoding) = encoding {
+            for chunk in &mut file_chunks {
+                chunk.metadata.source_encoding = Some(encoding.to_string());
+            }
+        }
+        // An upsert can keep metadata keys it doesn't set, so a chunk reusing an
+        // archived id (same content under --stable-ids) would stay archived
+        if options.archive_old_commits {
+            for chunk in &mut file_chunks {
+                chunk.metadata.archived = Some(false);
+            }
+        }
+        let indexed_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        for chunk in &mut file_chunks {
+            chunk.metadata.indexed_at = Some(indexed_at);
+        }
+        Some(file_chunks)
+    }
+}
+
+/// One `--directory`. With several, each is labelled with its directory name,
+/// which prefixes its file paths so paths and chunk ids can't collide across
+/// roots. A lone root has no label and its paths are unchanged.
+pub struct SourceRoot {
+    pub path: PathBuf,
+    pub label: Option<String>,
+    /// Commit and branch of the repository the root is in, set by `run`
+    pub git_commit: String,
+    pub git_branch: String,
+    /// The file filter with this root's own project config applied
+    pub filter: Option<FileFilter>,
+}
+
+impl SourceRoot {
+    pub fn from_args(directories: &[String]) -> Result<Vec<SourceRoot>> {
+        let mut roots: Vec<SourceRoot> = Vec::new();
+        for raw in directories {
+            let path = PathBuf::from(raw);
+            if !path.is_dir() {
+                anyhow::bail!("{} is not a directory", raw);
+            }
+            let label = match directories.len() {
+                1 => None,
+                _ => {
+                    let canonical = path.canonicalize().with_context(|| format!("Failed to resolve {}", raw))?;
+                    let name = canonical.file_name().with_context(|| format!("{} has no directory name to label it with", raw))?;
+                    let name = name.to_string_lossy().to_string();
+                    if roots.iter().any(|root| root.label.as_deref() == Some(name.as_str())) {
+                        anyhow::bail!("Two --directory values are named {}; their paths would collide", name);
+                    }
+                    Some(name)
+                }
+            };
+            roots.push(SourceRoot { path, label, git_commit: String::new(), git_branch: String::new(), filter: None });
+        }
+        Self::check_overlap(&roots)?;
+        Ok(roots)
+    }
+
+    /// Fails if two roots are the same directory or one is inside another,
+    /// since their files would be indexed twice under different paths.
+    fn check_overlap(roots: &[SourceRoot]) -> Result<()> {
+        let resolved = roots
+            .iter()
+            .map(|root| root.path.canonicalize().with_context(|| format!("Failed to resolve {}", root.path.display())))
+            .collect::<Result<Vec<PathBuf>>>()?;
+        for (i, a) in resolved.iter().enumerate() {
+            for (j, b) in resolved.iter().enumerate().skip(i + 1) {
+                if a == b {
+                    anyhow::bail!("--directory {} and {} are the same directory", roots[i].path.display(), roots[j].path.display());
+                }
+                let (inner, outer) = if a.starts_with(b) { (i, j) } else if b.starts_with(a) { (j, i) } else { continue };
+                anyhow::bail!("--directory {} is inside --directory {}", roots[inner].path.display(), roots[outer].path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// The root a stored `file_path` belongs to, going by its label.
+    pub fn owning<'a>(roots: &'a [SourceRoot], relative: &str) -> Option<&'a SourceRoot> {
+        roots.iter().find(|root| root.owns(relative))
+    }
+
+    /// Whether a stored `file_path` is under this root.
+    fn owns(&self, relative: &str) -> bool {
+        match &self.label {
+            Some(label) => relative.strip_prefix(label.as_str()).is_some_and(|rest| rest.starts_with('/')),
+            None => true,
+        }
+    }
+
+    /// The file filter for this root, `default` unless it has its own config.
+    pub fn filter<'a>(&'a self, default: &'a FileFilter) -> &'a FileFilter {
+        self.filter.as_ref().unwrap_or(default)
+    }
+
+    /// Whether this root is indexed at the same branch and commit as `other`.
+    fn same_git(&self, other: &SourceRoot) -> bool {
+        self.git_commit == other.git_commit && self.git_branch == other.git_branch
+    }
+
+    /// The root `path` belongs to, and `path` made absolute. A relative path
+    /// may start with a root's label; otherwise it's taken as under the first root.
+    pub fn locate<'a>(roots: &'a [SourceRoot], path: &Path) -> (&'a SourceRoot, PathBuf) {
+        if path.is_absolute() {
+            let root = roots.iter().find(|root| path.starts_with(&root.path)).unwrap_or(&roots[0]);
+            return (root, path.to_path_buf());
+        }
+        for root in roots {
+            if let Some(rest) = root.label.as_ref().and_then(|label| path.strip_prefix(label).ok()) {
+                return (root, root.path.join(rest));
+            }
+        }
+        (&roots[0], roots[0].path.join(path))
+    }
+
+    /// A `--force-reindex` path as stored on chunks. It must name a file
+    /// inside one of the roots.
+    pub fn force_reindex_path(roots: &[SourceRoot], raw: &str) -> Result<String> {
+        let (root, path) = Self::locate(roots, Path::new(raw));
+        if !path.starts_with(&root.path) || path.components().any(|c| c == std::path::Component::ParentDir) {
+            anyhow::bail!("--force-reindex {} is not inside {}", raw, root.path.display());
+        }
+        if path == root.path {
+            anyhow::bail!("--force-reindex {} names the directory itself, not a file in it", raw);
+        }
+        Ok(root.relative(&path))
+    }
+
+    /// `path` relative to this root, as git sees it.
+    fn root_relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.path).unwrap_or(path).to_string_lossy().to_string()
+    }
+
+    /// `path` as stored on chunks: relative to the root, under its label.
+    pub fn relative(&self, path: &Path) -> String {
+        self.labelled(self.root_relative(path))
+    }
+
+    /// Puts a root-relative path under this root's label.
+    fn labelled(&self, relative: String) -> String {
+        match &self.label {
+            Some(label) => format!("{}/{}", label, relative),
+            None => relative,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        self.label.clone().unwrap_or_else(|| self.path.display().to_string())
+    }
+}
+
+/// Scans every root, pairing each file with the root it came from.
+fn scan_roots<'a>(roots: &[&'a SourceRoot], filter: &FileFilter, concurrency: usize, stats: &RunStats) -> Result<Vec<(&'a SourceRoot, PathBuf)>> {
+    // Up to `concurrency` roots are walked at once; results keep root order
+    let next = AtomicUsize::new(0);
+    let found: Vec<Mutex<Option<Result<Vec<PathBuf>>>>> = roots.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, roots.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(root) = roots.get(i) else { break };
+                *found[i].lock().unwrap() = Some(scan_directory(&root.path, root.filter(filter), stats));
+            });
+        }
+    });
+    let mut files = Vec::new();
+    let mut counts = Vec::new();
+    for (root, found) in roots.iter().zip(found) {
+        let found = found.into_inner().unwrap().context("root was not scanned")?;
+        let found = found.with_context(|| format!("Failed to scan {}", root.path.display()))?;
+        counts.push(format!("{}: {}", root.name(), found.len()));
+        files.extend(found.into_iter().map(|path| (*root, path)));
+    }
+    match roots.len() {
+        1 => info!("Found {} files", files.len()),
+        _ => info!("Found {} files ({})", files.len(), counts.join(", ")),
+    }
+    if filter.follow_symlinks {
+        files = dedup_linked_files(files);
+    }
+    Ok(files)
+}
+
+/// Keeps one path per file when symlinks lead to it more than once: its real
+/// path if that was scanned, otherwise the one that sorts first, so its
+/// chunks keep the same path from run to run.
+fn dedup_linked_files(files: Vec<(&SourceRoot, PathBuf)>) -> Vec<(&SourceRoot, PathBuf)> {
+    let before = files.len();
+    let mut keyed: Vec<(PathBuf, (&SourceRoot, PathBuf))> = files
+        .into_iter()
+        .map(|(root, path)| (fs::canonicalize(&path).unwrap_or_else(|_| path.clone()), (root, path)))
+        .collect();
+    keyed.sort_by(|(a_real, (_, a)), (b_real, (_, b))| (a_real, a != a_real, a).cmp(&(b_real, b != b_real, b)));
+    keyed.dedup_by(|(a_real, _), (b_real, _)| a_real == b_real);
+    if keyed.len() < before {
+        info!("Skipped {} files already reached through another path", before - keyed.len());
+    }
+    keyed.into_iter().map(|(_, file)| file).collect()
+}
+
+/// Files under `directory` that pass `filter`, the gitignore and the
+/// always-ignored directories. What the scan leaves out is counted in `stats`.
+fn scan_directory(directory: &Path, filter: &FileFilter, stats: &RunStats) -> Result<Vec<PathBuf>> {
+    let files = Mutex::new(Vec::new());
+    let oversized = Mutex::new(Vec::new());
+    let too_deep = Arc::new(AtomicUsize::new(0));
+    let ignored = Arc::new(AtomicUsize::new(0));
+
+    // Only .gitignore and .vdbignore files at or below `directory`, applied per
+    // directory as the walk descends; hidden files are left to the filter lists.
+    // They're matched here rather than by the walker so what they leave out can
+    // be counted; every .vdbignore is checked before any .gitignore
+    let dir_filter = filter.clone();
+    let dir_too_deep = Arc::clone(&too_deep);
+    let dir_ignored = Arc::clone(&ignored);
+    let gitignore = GitignoreTree::new(directory);
+    let walker = ignore::WalkBuilder::new(directory)
+        .standard_filters(false)
+        // The walker detects symlink cycles and reports them as errors, which are skipped below
+        .follow_links(filter.follow_symlinks)
+        .filter_entry(move |e| {
+            let is_dir = e.file_type().is_some_and(|t| t.is_dir());
+            if is_dir && dir_filter.ignores_dir(e.file_name().to_str().unwrap_or("")) {
+                dir_ignored.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            if is_dir && dir_filter.beyond_max_depth(e.depth()) {
+                dir_too_deep.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            if e.depth() > 0 && gitignore.skip_reason(e.path(), is_dir).is_some() {
+                dir_ignored.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            true
+        })
+        .build_parallel();
+
+    // Each walker thread checks its own entries; the per-file checks stat
+    // the file, which dominates on large trees
+    walker.run(|| {
+        Box::new(|entry| {
+            let entry = match entry { Ok(e) => e, Err(_) => return ignore::WalkState::Continue };
+            if !entry.file_type().is_some_and(|t| t.is_file()) { return ignore::WalkState::Continue; }
+
+            let path = entry.path();
+            if filter.scope_skip_reason(path.strip_prefix(directory).unwrap_or(path)).is_some() {
+                stats.excluded_files.fetch_add(1, Ordering::Relaxed);
+                return ignore::WalkState::Continue;
+            }
+            if !filter.should_index_file(path) {
+                stats.filtered_files.fetch_add(1, Ordering::Relaxed);
+                return ignore::WalkState::Continue;
+            }
+
+            if let Some(size) = filter.too_large(directory, path) {
+                oversized.lock().unwrap().push((path.strip_prefix(directory).unwrap_or(path).display().to_string(), size));
+                return ignore::WalkState::Continue;
+            }
+
+            files.lock().unwrap().push(path.to_path_buf());
+            ignore::WalkState::Continue
+        })
+    });
+
+    let too_deep = too_deep.load(Ordering::Relaxed);
+    if too_deep > 0 {
+        info!("Skipped {} directories below --max-depth", too_deep);
+    }
+    stats.too_deep_dirs.fetch_add(too_deep, Ordering::Relaxed);
+    stats.ignored_entries.fetch_add(ignored.load(Ordering::Relaxed), Ordering::Relaxed);
+    let mut oversized = oversized.into_inner().unwrap();
+    stats.too_large_files.fetch_add(oversized.len(), Ordering::Relaxed);
+    report_oversized(directory, &mut oversized, filter);
+    // Walker threads finish in any order; sort so runs process files alike
+    let mut files = files.into_inner().unwrap();
+    files.sort();
+    Ok(files)
+}
+
+/// Warns about files the size limit kept out, which are otherwise easy to miss,
+/// and lists them with `--list-skipped`.
+fn report_oversized(directory: &Path, oversized: &mut [(String, u64)], filter: &FileFilter) {
+    if oversized.is_empty() {
+        return;
+    }
+    let total: u64 = oversized.iter().map(|(_, size)| size).sum();
+    warn!(
+        "Skipped {} files in {} over the {} byte size limit ({} bytes in all); raise --max-file-size, force them with --force-index, or add an ignore rule{}",
+        oversized.len(),
+        directory.display(),
+        filter.max_file_size,
+        total,
+        if filter.list_skipped { "" } else { " (--list-skipped lists them)" }
+    );
+    if filter.list_skipped {
+        oversized.sort();
+        for (path, size) in oversized.iter() {
+            println!("  {} ({} bytes)", path, size);
+        }
+    }
+}
+
+/// Per-file totals for `--dry-run`.
+struct DryRunFile {
+    path: String,
+    chunks: usize,
+    bytes: usize,
+}
+
+/// The chunks an indexing run would store for each file under `roots`, by path.
+fn expected_chunks(roots: &[SourceRoot], chunker: &CodeChunker, options: &IndexOptions) -> Result<BTreeMap<String, ExpectedFile>> {
+    let stats = RunStats::default();
+    let files = scan_roots(&roots.iter().collect::<Vec<_>>(), &options.filter, options.dir_concurrency, &stats)?;
+    let mut chunks: Vec<Chunk> = files
+        .par_iter()
+        .filter_map(|(root, path)| chunker.chunk_file(root, path, options, &stats))
+        .flatten()
+        .collect();
+    // Duplicates dropped by --dedup-chunks were never stored
+    if options.dedup_chunks {
+        dedup_chunks(&mut chunks);
+    }
+    let mut expected: BTreeMap<String, ExpectedFile> = BTreeMap::new();
+    for chunk in chunks {
+        expected
+            .entry(chunk.metadata.file_path)
+            .or_insert_with(|| ExpectedFile { git_commit: chunk.metadata.git_commit, ids: Default::default() })
+            .ids
+            .insert(chunk.id);
+    }
+    Ok(expected)
+}
+
+/// Scans and chunks like an indexing run, printing what would be embedded
+/// without contacting the embedding service or the vector store.
+fn dry_run(roots: &[SourceRoot], chunker: &CodeChunker, options: &IndexOptions) -> Result<()> {
+    println!("Dry run: nothing will be embedded or uploaded");
+    println!("Scanning...");
+    let stats = RunStats::default();
+    let files = scan_roots(&roots.iter().collect::<Vec<_>>(), &options.filter, options.dir_concurrency, &stats)?;
+
+    let mut report: Vec<DryRunFile> = files
+        .par_iter()
+        .filter_map(|(root, path)| {
+            let chunks = chunker.chunk_file(root, path, options, &stats)?;
+            Some(DryRunFile {
+                path: root.relative(path),
+                chunks: chunks.len(),
+                bytes: chunks.iter().map(|c| c.embedding_input().len()).sum(),
+            })
+        })
+        .collect();
+    stats.report(options.detect_encoding);
+    report.sort_by(|a, b| a.path.cmp(&b.path));
+
+    for file in &report {
+        println!("  {}: {} chunks, {} bytes", file.path, file.chunks, file.bytes);
+    }
+    let chunks: usize = report.iter().map(|f| f.chunks).sum();
+    let bytes: usize = report.iter().map(|f| f.bytes).sum();
+    println!("Would embed {} chunks ({} bytes) from {} files", chunks, bytes, report.iter().filter(|f| f.chunks > 0).count());
+    for label in roots.iter().filter_map(|root| root.label.as_deref()) {
+        let prefix = format!("{}/", label);
+        let files: Vec<&DryRunFile> = report.iter().filter(|f| f.chunks > 0 && f.path.starts_with(&prefix)).collect();
+        let chunks: usize = files.iter().map(|f| f.chunks).sum();
+        let bytes: usize = files.iter().map(|f| f.bytes).sum();
+        println!("  {}: {} chunks ({} bytes) from {} files", label, chunks, bytes, files.len());
+    }
+    Ok(())
+}
+
+// ============================================================================
+// Watch Mode
+// ============================================================================
+
+impl CodebaseIndexer {
+    /// Re-indexes files as they change on disk.
+    ///
+    /// Events are coalesced until `debounce` passes with no further changes, so
+    /// a branch switch or bulk format becomes one embedding pass instead of one
+    /// per file. A longer window batches more work per pass but delays how soon
+    /// an edit becomes searchable.
+    pub fn watch(&self, root: &SourceRoot, options: &IndexOptions, debounce: Duration) -> Result<()> {
+        let directory = root.path.as_path();
+        let (tx, rx) = mpsc::channel::<notify::Event>();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            if let Ok(event) = res {
+                if !matches!(event.kind, notify::EventKind::Access(_)) {
+                    tx.send(event).ok();
+                }
+            }
+        })?;
+        watcher.watch(directory, notify::RecursiveMode::Recursive)?;
+        info!("Watching {} (debounce {}ms)...", directory.display(), debounce.as_millis());
+
+        let gitignore = GitignoreTree::new(directory);
+        while !interrupted() {
+            let event = match rx.recv_timeout(WATCH_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            let mut paths: BTreeSet<PathBuf> = event.paths.into_iter().collect();
+            while let Ok(event) = rx.recv_timeout(debounce) {
+                paths.extend(event.paths);
+            }
+            self.apply_changes(root, &gitignore, paths, options)?;
+        }
+        info!("Stopped watching");
+
+        Ok(())
+    }
+
+    fn apply_changes(&self, root: &SourceRoot, gitignore: &GitignoreTree, paths: BTreeSet<PathBuf>, options: &IndexOptions) -> Result<()> {
+        let mut changed = Vec::new();
+        let mut deleted = Vec::new();
+        for path in paths {
+            if path.is_dir() || root.filter(&options.filter).path_skip_reason(&root.path, gitignore, &path).is_some() {
+                continue;
+            }
+            if path.is_file() {
+                changed.push(path);
+            } else if path.starts_with(&root.path) {
+                deleted.push(root.relative(&path));
+            }
+        }
+
+        if changed.is_empty() && deleted.is_empty() {
+            return Ok(());
+        }
+        info!("Re-indexing {} changed, {} deleted files", changed.len(), deleted.len());
+
+        // Embed everything before touching the collection so a failed request
+        // leaves the previous chunks in place
+        let stats = RunStats::default();
+        let chunks: Vec<Chunk> = changed
+            .par_iter()
+            .filter_map(|path| self.chunker.chunk_file(root, path, options, &stats))
+            .flatten()
+            .collect();
+        stats.report(options.detect_encoding);
+
+        let mut expected_dim = None;
+        let mut embedded = Vec::new();
+        let inflight = Semaphore::new(1);
+        for batch in chunks.chunks(options.upload_batch_size) {
+            let embeddings = self.embed_chunks(batch, &inflight)?;
+            let (mut batch, embeddings, _) = validate_embeddings(batch, embeddings, &mut expected_dim, options.on_bad_embedding)?;
+            self.cache_embeddings(&batch, &embeddings)?;
+            if options.store_embedding_norm {
+                for (chunk, embedding) in batch.iter_mut().zip(&embeddings) {
+                    chunk.metadata.embedding_norm = Some(l2_norm(embedding));
+                }
+            }
+            embedded.push((batch, embeddings));
+        }
+
+        // Add-first uploads before removing anything, then drops only the old
+        // ids the new chunks didn't reuse, so a changed file never has no chunks
+        let add_first = options.reindex_order == ReindexOrder::AddFirst;
+        if !add_first {
+            for path in &changed {
+                self.delete_file_chunks(&root.git_branch, &root.relative(path))?;
+            }
+        }
+
+        let mut uploaded = 0usize;
+        let mut uploaded_ids: HashMap<String, HashSet<String>> = HashMap::new();
+        for (batch, embeddings) in embedded {
+            let mut routed: BTreeMap<&str, (Vec<Chunk>, Vec<Vec<f32>>)> = BTreeMap::new();
+            for (chunk, embedding) in batch.into_iter().zip(embeddings) {
+                uploaded_ids.entry(chunk.metadata.file_path.clone()).or_default().insert(chunk.id.clone());
+                let entry = routed.entry(self.router.route(&chunk.metadata.file_type)).or_default();
+                entry.0.push(chunk);
+                entry.1.push(embedding);
+            }
+            for (name, (chunks, embeddings)) in routed {
+                uploaded += chunks.len();
+                self.collections[name].add_chunks(&chunks, embeddings)?;
+            }
+        }
+        if add_first {
+            let none = HashSet::new();
+            for path in &changed {
+                let relative = root.relative(path);
+                self.delete_stale_file_chunks(&root.git_branch, &relative, uploaded_ids.get(&relative).unwrap_or(&none))?;
+            }
+        }
+
+        // Deletions go last so a rename never leaves a window with neither path
+        for relative in &deleted {
+            self.delete_file_chunks(&root.git_branch, relative)?;
+        }
+
+        info!("Updated {} chunks", uploaded);
+        Ok(())
+    }
+
+    /// Deletes the group's chunks of files that were indexed but aren't in
+    /// `files` any more, because they were deleted or are now excluded.
+    fn prune_missing_files(&self, group: &RootGroup, files: &[(&SourceRoot, PathBuf)]) -> Result<()> {
+        let on_disk: HashSet<String> = files.iter().map(|(root, path)| root.relative(path)).collect();
+        let mut pruned = 0usize;
+        for collection in self.collections.values() {
+            for file_path in collection.indexed_files(group.git_branch, self.version.as_deref())? {
+                if group.owns(&file_path) && !on_disk.contains(&file_path) {
+                    collection.delete_file(group.git_branch, self.version.as_deref(), &file_path)?;
+                    pruned += 1;
+                }
+            }
+        }
+        if pruned > 0 {
+            info!("Pruned chunks of {} files no longer on disk", pruned);
+        }
+        Ok(())
+    }
+
+    fn delete_file_chunks(&self, git_branch: &str, relative: &str) -> Result<()> {
+        self.file_collection(relative).delete_file(git_branch, self.version.as_deref(), relative)
+    }
+
+    /// Deletes a file's chunks except the ids in `keep`, the ones just uploaded.
+    fn delete_stale_file_chunks(&self, git_branch: &str, relative: &str, keep: &HashSet<String>) -> Result<()> {
+        self.file_collection(relative).delete_file_except(git_branch, self.version.as_deref(), relative, keep)
+    }
+
+    /// The collection a file's chunks are routed to.
+    fn file_collection(&self, relative: &str) -> &dyn VectorStore {
+        let file_type = Path::new(relative)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| format!(".{}", e))
+            .unwrap_or_default();
+        self.collections[self.router.route(&file_type)].as_ref()
+    }
+}
+
+// ============================================================================
+// Error Reporting
+// ============================================================================
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// Human-readable error and cause chain
+    Text,
+    /// One JSON object on stderr
+    Json,
+}
+
+/// Broad kind of a fatal error, for automation deciding between retry and alert.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// A service (ChromaDB, embeddings) couldn't be reached or timed out; usually worth retrying
+    Unavailable,
+    /// A service answered with an error status or a response that couldn't be parsed
+    Service,
+    /// Reading or writing local files failed
+    Io,
+    /// The embedding cache database failed
+    Cache,
+    /// Anything else, including invalid arguments and data
+    Other,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorReport {
+    class: ErrorClass,
+    message: String,
+    causes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+}
+
+impl ErrorReport {
+    /// Classifies `err` by the first recognisable error in its cause chain.
+    fn new(err: &anyhow::Error) -> Self {
+        let mut report = Self {
+            class: ErrorClass::Other,
+            message: err.to_string(),
+            causes: err.chain().skip(1).map(ToString::to_string).collect(),
+            url: None,
+            status: None,
+        };
+        for cause in err.chain() {
+            if let Some(http) = cause.downcast_ref::<reqwest::Error>() {
+                report.url = http.url().map(ToString::to_string);
+                report.status = http.status().map(|s| s.as_u16());
+                report.class = if http.is_connect() || http.is_timeout() { ErrorClass::Unavailable } else { ErrorClass::Service };
+                break;
+            }
+            if cause.is::<serde_json::Error>() {
+                report.class = ErrorClass::Service;
+                break;
+            }
+            if cause.is::<rusqlite::Error>() {
+                report.class = ErrorClass::Cache;
+                break;
+            }
+            if cause.is::<std::io::Error>() {
+                report.class = ErrorClass::Io;
+                break;
+            }
+        }
+        report
+    }
+}
+
+fn report_error(err: &anyhow::Error, format: ErrorFormat) {
+    match format {
+        ErrorFormat::Text => eprintln!("Error: {:?}", err),
+        ErrorFormat::Json => {
+            let report = serde_json::json!({ "error": ErrorReport::new(err) });
+            eprintln!("{}", report);
+        }
+    }
+}
+
+// ============================================================================
+// CLI
+// ============================================================================
+
+#[derive(Parser)]
+#[command(name = "indexer", about = "Index codebase for vector search")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Directory to index; repeat or comma-separate for several roots, whose paths are then prefixed with the directory name
+    #[arg(long, required = true, value_delimiter = ',')]
+    directory: Vec<String>,
+    #[arg(long, default_value = "chromadb")]
+    host: String,
+    #[arg(long, default_value = "8000")]
+    port: String,
+    /// Full ChromaDB database URL, e.g. `https://chroma:8000/api/v2/tenants/acme/databases/code`; overrides --host/--port
+    #[arg(long)]
+    chroma_url: Option<String>,
+    /// ChromaDB tenant (default `default_tenant`)
+    #[arg(long)]
+    chroma_tenant: Option<String>,
+    /// ChromaDB database within the tenant (default `default_database`)
+    #[arg(long)]
+    chroma_database: Option<String>,
+    /// Scheme for reaching ChromaDB at --host/--port
+    #[arg(long, value_enum, default_value_t = UrlScheme::Http)]
+    store_scheme: UrlScheme,
+    /// Bearer token sent with every ChromaDB request (default: CHROMA_TOKEN)
+    #[arg(long)]
+    store_token: Option<String>,
+    #[arg(long, default_value = "codebase")]
+    collection: String,
+    /// Route chunks to collections by extension, e.g. `md,mdx=docs;default=code`
+    #[arg(long)]
+    collection_by_type: Option<String>,
+    /// Keep a separate collection per branch, named `<collection>__<branch>`
+    #[arg(long)]
+    collection_per_branch: bool,
+    /// Target chunk size in --size-unit units; keep it within the embedding model's context window
+    #[arg(long, default_value_t = DEFAULT_CHUNK_SIZE)]
+    chunk_size: usize,
+    /// Size of trailing lines repeated at the start of the next chunk; must be less than --chunk-size
+    #[arg(long, default_value_t = DEFAULT_CHUNK_OVERLAP)]
+    overlap: usize,
+    /// What --chunk-size and --overlap count; `tokens` needs --tokenizer and falls back to bytes without one
+    #[arg(long, value_enum, default_value_t = SizeUnit::Bytes)]
+    size_unit: SizeUnit,
+    /// The embedding model's tokenizer.json, for --size-unit tokens
+    #[arg(long)]
+    tokenizer: Option<PathBuf>,
+    /// How files are cut into chunks; `syntactic` keeps top-level declarations whole in .rs/.ts/.tsx/.js/.py/.go files
+    #[arg(long, value_enum, default_value_t = ChunkStrategy::Lines)]
+    chunk_strategy: ChunkStrategy,
+    /// Retries for an embedding request that hits 429/5xx, a connection error or a timeout, with exponential backoff
+    #[arg(long, default_value_t = 3)]
+    embed_max_retries: usize,
+    /// How long to wait for the embedding service to come up before giving up
+    #[arg(long, default_value_t = DEFAULT_EMBED_STARTUP_TIMEOUT_SECS)]
+    embed_startup_timeout_secs: u64,
+    /// How long one embedding request may take; raise it for large batches on a slow server
+    #[arg(long, default_value_t = DEFAULT_EMBED_TIMEOUT_SECS)]
+    embed_timeout_secs: u64,
+    /// How long one vector store request may take before the store is treated as unreachable
+    #[arg(long, default_value_t = DEFAULT_STORE_TIMEOUT_SECS)]
+    store_timeout_secs: u64,
+    /// Scan and chunk, then print per-file chunk counts and totals without contacting ChromaDB or the embedding service
+    #[arg(long)]
+    dry_run: bool,
+    /// Print chunk, file, branch and commit counts for the collection and exit, without indexing or contacting the embedding service
+    #[arg(long, conflicts_with_all = ["dry_run", "output_embeddings"])]
+    stats_only: bool,
+    /// Records --stats-only reads per collection for its file, branch and commit counts
+    #[arg(long, default_value_t = 10000)]
+    stats_sample: usize,
+    /// Print periodic progress lines instead of progress bars, even on a terminal
+    #[arg(long)]
+    no_progress: bool,
+    /// Write a JSON report of the run (files, chunks, skips, timing) to this path
+    #[arg(long)]
+    json_summary: Option<PathBuf>,
+    /// Where to write the index; `memory` keeps it in-process and discards it on exit
+    #[arg(long, value_enum, default_value_t = StoreKind::Chroma)]
+    store: StoreKind,
+    /// Qdrant REST endpoint for --store qdrant
+    #[arg(long, default_value = "http://localhost:6333")]
+    qdrant_url: String,
+    /// Branch to record on chunks, overriding GIT_BRANCH and the detached-HEAD fallbacks
+    #[arg(long)]
+    branch: Option<String>,
+    /// Derive chunk ids from line ranges or from chunk content
+    #[arg(long, value_enum, default_value_t = IdMode::Lines)]
+    id_mode: IdMode,
+    /// Shorthand for --id-mode stable: ids from branch, path and content hash, kept across commits
+    #[arg(long, conflicts_with = "id_mode")]
+    stable_ids: bool,
+    /// Release label stored as `version` metadata; isolates cleanup per version
+    #[arg(long)]
+    version_label: Option<String>,
+    /// Line ending used to join chunk lines
+    #[arg(long, value_enum, default_value_t = LineEnding::Lf)]
+    line_ending: LineEnding,
+    /// Leave noise lines (banners, base64 blobs, --noise-pattern matches) out of chunks
+    #[arg(long)]
+    strip_noise: bool,
+    /// Regex for extra lines to treat as noise (repeatable)
+    #[arg(long = "noise-pattern", requires = "strip_noise")]
+    noise_patterns: Vec<String>,
+    /// Keep noise lines in the stored document; only the embedded text is cleaned
+    #[arg(long, requires = "strip_noise")]
+    keep_noise_in_document: bool,
+    /// Prefix prepended to every chunk before embedding, e.g. `passage: `; recorded on new collections
+    #[arg(long)]
+    embed_prefix: Option<String>,
+    /// Surrounding lines stored with each chunk as `context_before`/`context_after` metadata
+    #[arg(long, default_value_t = 0)]
+    context_lines: usize,
+    /// Extra metadata for newly created collections (repeatable), e.g. `team=search` or `hnsw:batch_size=200`
+    #[arg(long = "collection-meta", alias = "collection-metadata", value_parser = parse_metadata_pair)]
+    collection_meta: Vec<(String, serde_json::Value)>,
+    /// Description stored on newly created collections
+    #[arg(long)]
+    collection_description: Option<String>,
+    /// Distance metric for new collections (default cosine); overrides `hnsw:space` in --collection-meta
+    #[arg(long, value_enum)]
+    distance: Option<Distance>,
+    /// Deprecated: sets both --embed-batch-size and --upload-batch-size
+    #[arg(long)]
+    batch_size: Option<usize>,
+    /// Chunks per embedding request; lower it if the embedding server rejects large requests (default 128)
+    #[arg(long)]
+    embed_batch_size: Option<usize>,
+    /// Chunks per upload to the vector store. Each upload is embedded in --embed-batch-size requests first, so raising this makes fewer, larger writes without larger embedding requests (default 128)
+    #[arg(long)]
+    upload_batch_size: Option<usize>,
+    /// How to handle empty, wrong-dimension or all-zero embeddings
+    #[arg(long, value_enum, default_value_t = BadEmbeddingPolicy::Fail)]
+    on_bad_embedding: BadEmbeddingPolicy,
+    /// Leave out files that split into more than this many chunks, such as minified or vendored data
+    #[arg(long)]
+    max_chunks_per_file: Option<usize>,
+    /// What to do with a file over --max-chunks-per-file
+    #[arg(long, value_enum, default_value_t = ChunkLimitPolicy::Skip, requires = "max_chunks_per_file")]
+    on_chunk_limit: ChunkLimitPolicy,
+    /// Stop after uploading this many chunks; re-run to continue
+    #[arg(long, requires = "resume_manifest")]
+    max_chunks: Option<usize>,
+    /// File recording uploaded chunk ids so an interrupted, failed or capped run can resume
+    #[arg(long, alias = "checkpoint")]
+    resume_manifest: Option<PathBuf>,
+    /// Write one JSON line per chunk id with its file, lines, collection and whether this run uploaded it
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    /// Remove old-commit chunks before uploading (empty window) or after (duplicate window)
+    #[arg(long, value_enum, default_value_t = ReindexOrder::DeleteFirst)]
+    reindex_order: ReindexOrder,
+    /// Tag chunks of earlier commits on the branch with `archived: true` instead of deleting them
+    #[arg(long)]
+    archive_old_commits: bool,
+    /// Delete the branch's archived chunks, then index as usual
+    #[arg(long)]
+    purge_archived: bool,
+    /// Decode non-UTF-8 files (Shift-JIS, GBK, Latin-1, ...) instead of skipping them
+    #[arg(long)]
+    detect_encoding: bool,
+    /// Store each chunk's embedding L2 norm as `embedding_norm` metadata
+    #[arg(long)]
+    store_embedding_norm: bool,
+    /// Skip files whose language can't be identified from extension or shebang
+    #[arg(long)]
+    require_known_language: bool,
+    /// Worker threads for chunking and embedding (defaults to the CPU count)
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Files read at once (defaults to the CPU count); lower it on systems with a low open-file limit
+    #[arg(long)]
+    read_concurrency: Option<usize>,
+    /// `--directory` roots scanned at once; their files share one chunking and embedding pipeline
+    #[arg(long, default_value_t = 1)]
+    dir_concurrency: usize,
+    /// Maximum concurrent embedding requests; match to the embedding server's capacity
+    #[arg(long, alias = "embed-concurrency", default_value_t = 2)]
+    max_inflight_embeddings: usize,
+    /// SQLite file caching embeddings by content across runs (disabled by default)
+    #[arg(long)]
+    embedding_cache: Option<PathBuf>,
+    /// Keep the embedding cache as embeddings.sqlite in this directory, creating it if needed
+    #[arg(long, conflicts_with = "embedding_cache")]
+    cache_dir: Option<PathBuf>,
+    /// Ignore --embedding-cache and --cache-dir and embed every chunk
+    #[arg(long)]
+    no_cache: bool,
+    /// Embedding service to use; its URL comes from TEI_URL or OLLAMA_URL
+    #[arg(long, value_enum, default_value_t = EmbedBackend::Tei)]
+    embed_backend: EmbedBackend,
+    /// Embedding model; required for --embed-backend ollama, and keys the embedding cache (defaults to what TEI reports)
+    #[arg(long, alias = "embed-model")]
+    embedding_model: Option<String>,
+    /// Only re-index files modified since the last full run (trusts mtimes)
+    #[arg(long)]
+    modified_since_last_index: bool,
+    /// Only re-index files changed since this git ref (`git diff <ref>...HEAD`), removing chunks of deleted ones
+    #[arg(long, value_name = "GITREF", conflicts_with = "modified_since_last_index")]
+    since: Option<String>,
+    /// Transform applied to text before embedding (repeatable, applied in order)
+    #[arg(long = "transform", value_enum)]
+    transforms: Vec<TransformKind>,
+    /// Transform applied to the stored document (repeatable); stores raw text if omitted
+    #[arg(long = "document-transform", value_enum)]
+    document_transforms: Vec<TransformKind>,
+    /// Skip the already-indexed check and old-commit cleanup; only safe for a fresh collection
+    #[arg(long, conflicts_with_all = ["modified_since_last_index", "since"])]
+    append_only: bool,
+    /// Roll over to a new numbered collection (`<name>_001`, ...) once the current one holds this many chunks
+    #[arg(long)]
+    collection_max_chunks: Option<usize>,
+    /// Gzip document uploads to ChromaDB, for large batches that hit request size limits
+    #[arg(long)]
+    compress_uploads: bool,
+    /// Write each chunk, its vector and metadata to this JSONL file instead of a vector store
+    #[arg(long, value_name = "PATH", conflicts_with = "store")]
+    output_embeddings: Option<PathBuf>,
+    /// Add one summary chunk per file (path, language, doc comment, defined names)
+    #[arg(long)]
+    file_summary_chunks: bool,
+    /// Store each chunk's primary author and per-author line counts from `git blame`
+    #[arg(long)]
+    with_blame: bool,
+    /// Extra attempts for the final chunk count before reporting it as unknown
+    #[arg(long, default_value_t = 3)]
+    stats_retries: usize,
+    /// Glob of files to index regardless of the size limit (repeatable), e.g. `generated/api_client.ts`
+    #[arg(long)]
+    force_index: Vec<String>,
+    /// Delete and rebuild the chunks of this file (relative to --directory, repeatable), even if its commit is already indexed
+    #[arg(long, value_name = "PATH")]
+    force_reindex: Vec<String>,
+    /// Skip files larger than this many megabytes (default 10)
+    #[arg(long, value_name = "MB")]
+    max_file_size: Option<u64>,
+    /// Print the paths of files skipped for their size
+    #[arg(long)]
+    list_skipped: bool,
+    /// How a fatal error is printed to stderr
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+    /// Log more detail: -v for per-batch uploads, -vv for everything (RUST_LOG overrides this)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Don't descend more than this many directories below --directory (0 = top-level files only)
+    #[arg(long)]
+    max_depth: Option<usize>,
+    /// Follow symlinked directories and files; each file is indexed once however many links reach it
+    #[arg(long)]
+    follow_symlinks: bool,
+    /// Index test files (`*.test.*`, `*.spec.*`, `__tests__/`), e.g. to find usage examples
+    #[arg(long)]
+    include_tests: bool,
+    /// Index files whose header marks them as generated (`@generated`, `DO NOT EDIT`, ...)
+    #[arg(long)]
+    index_generated: bool,
+    /// Extra header marker that flags a file as generated (repeatable, case-insensitive)
+    #[arg(long = "generated-marker", conflicts_with = "index_generated")]
+    generated_markers: Vec<String>,
+    /// Log progress and save the resume manifest every N uploaded batches
+    #[arg(long, default_value_t = 10)]
+    checkpoint_interval: usize,
+    /// Extensionless file name to index in addition to the built-in list (repeatable), e.g. `Justfile`
+    #[arg(long = "allow-no-ext")]
+    allow_no_ext: Vec<String>,
+    /// Only index paths matching this glob, relative to --directory (repeatable), e.g. `packages/api/**`
+    #[arg(long)]
+    include: Vec<String>,
+    /// Never index paths matching this glob, relative to --directory (repeatable); wins over --include
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Project config with filter list changes [default: <directory>/.vdb-index.toml, if present]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Lists set in the project config replace the built-in ones instead of adding to them
+    #[arg(long)]
+    replace_default_lists: bool,
+    /// Drop chunks with fewer distinct whitespace-delimited tokens than this (e.g. walls of repeated lines)
+    #[arg(long)]
+    min_distinct_tokens: Option<usize>,
+    /// Embed and store byte-identical chunks (license headers, boilerplate) once per run, listing every file in `source_files`
+    #[arg(long)]
+    dedup_chunks: bool,
+}
+
+/// Parses `key=value`, reading the value as JSON when possible so numbers and
+/// booleans keep their type. Collection metadata only holds scalars, so JSON
+/// arrays, objects and null are rejected.
+fn parse_metadata_pair(raw: &str) -> Result<(String, serde_json::Value), String> {
+    let (key, value) = raw.split_once('=').ok_or_else(|| format!("expected key=value, got '{}'", raw))?;
+    if key.is_empty() {
+        return Err(format!("missing key in '{}'", raw));
+    }
+    let value = serde_json::from_str(value).unwrap_or_else(|_| serde_json::Value::from(value));
+    if !(value.is_string() || value.is_number() || value.is_boolean()) {
+        return Err(format!("'{}' must be a string, number or boolean, got {}", key, value));
+    }
+    Ok((key.to_string(), value))
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Report why a file would or wouldn't be indexed
+    Explain {
+        /// File to check, relative to --directory or absolute
+        path: PathBuf,
+    },
+    /// Delete per-branch collections whose branch no longer exists
+    PruneCollections {
+        /// Actually delete; without this only lists what would be removed
+        #[arg(long)]
+        apply: bool,
+        /// Live branches, instead of reading them from the repository
+        #[arg(long, value_delimiter = ',')]
+        branches: Vec<String>,
+    },
+    /// Print the settings a collection was built with
+    DescribeCollection {
+        /// Also write the settings as JSON to this file
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Re-embed a collection's stored documents into a new collection
+    Migrate {
+        /// Target collection, created fresh
+        #[arg(long)]
+        to: String,
+        /// Delete the target first if it already exists
+        #[arg(long)]
+        replace: bool,
+        /// Records read and re-embedded per request
+        #[arg(long, default_value_t = 256)]
+        page_size: usize,
+    },
+    /// Semantic search across one or more collections
+    Search {
+        /// Text to search for
+        #[arg(required_unless_present = "query_flag")]
+        query: Option<String>,
+        /// Text to search for, instead of the positional argument
+        #[arg(long = "query", id = "query_flag", value_name = "QUERY", conflicts_with = "query")]
+        query_flag: Option<String>,
+        /// Collection to search (repeatable); defaults to the indexing collections
+        #[arg(long = "collection")]
+        collections: Vec<String>,
+        /// Search every collection in the database
+        #[arg(long, conflicts_with = "collections")]
+        all_collections: bool,
+        /// Results to return after merging
+        #[arg(long, default_value_t = 10)]
+        top_k: usize,
+        /// Collections queried at once
+        #[arg(long, default_value_t = 4)]
+        max_concurrent: usize,
+        /// Prefix for the query text; derived from each collection's embed prefix if omitted
+        #[arg(long)]
+        query_prefix: Option<String>,
+    },
+    /// Check an existing index for problems
+    Verify {
+        /// Flag sampled chunks whose nearest neighbour is closer than this distance
+        #[arg(long)]
+        dup_threshold: Option<f32>,
+        /// Chunks sampled per collection for the duplicate check
+        #[arg(long, default_value_t = 200)]
+        sample_size: usize,
+    },
+    /// Print the chunks a file would be split into, as JSON line ranges
+    ChunkMap {
+        /// File to chunk, relative to --directory or absolute
+        path: PathBuf,
+    },
+    /// Index once, then keep re-indexing files as they change
+    Watch {
+        /// Quiet period to wait for before flushing a batch of changes
+        #[arg(long, default_value_t = 500)]
+        watch_debounce_ms: u64,
+    },
+}
+
+fn explain(directory: &Path, path: &Path, filter: &FileFilter) -> Result<()> {
+    let path = if path.is_absolute() { path.to_path_buf() } else { directory.join(path) };
+    let display = path.strip_prefix(directory).unwrap_or(&path).display().to_string();
+
+    match filter.explain_path(directory, &path)? {
+        Some(reason) => println!("{}: not indexed ({})", display, reason),
+        None => println!("{}: would be indexed", display),
+    }
+    Ok(())
+}
+
+fn print_hits(hits: &[SearchHit]) {
+    if hits.is_empty() {
+        println!("No results");
+    }
+    for (rank, hit) in hits.iter().enumerate() {
+        println!("{}. {}:{}-{}  score {:.3}  [{}]", rank + 1, hit.file_path, hit.start_line, hit.end_line, hit.score, hit.collection);
+        for line in hit.document.lines().take(3) {
+            println!("     {}", line);
         }
     }
+}
 
-    fn get_overlap_lines<'a>(&self, current_chunk: &[&'a str], overlap: usize) -> Vec<&'a str> {
-        let mut overlap_lines = Vec::new();
-        let mut overlap_size = 0usize;
-        for line in current_chunk.iter().rev() {
-            let line_size = line.len() + 1;
-            if overlap_size + line_size > overlap { break; }
-            overlap_lines.insert(0, *line);
-            overlap_size += line_size;
+fn migrate(
+    admin: &ChromaClient,
+    from: &str,
+    to: &str,
+    replace: bool,
+    page_size: usize,
+    collection_metadata: &serde_json::Map<String, serde_json::Value>,
+    embedder: &dyn Embedder,
+) -> Result<()> {
+    if admin.list_collections()?.iter().any(|name| name == to) {
+        if !replace {
+            anyhow::bail!("Collection {} already exists; pass --replace to overwrite it", to);
         }
-        overlap_lines
+        admin.delete_collection(to)?;
     }
+    if !admin.list_collections()?.iter().any(|name| name == from) {
+        anyhow::bail!("Collection {} does not exist", from);
+    }
+
+    let source = admin.sibling(from, &serde_json::Map::new())?;
+    let target = admin.sibling(to, collection_metadata)?;
+    migrate_collection(&source, &target, embedder, page_size)
 }
 
-// ============================================================================
-// ChromaDB Client
-// ============================================================================
+fn describe(chroma: &ChromaClient, router: &CollectionRouter, output: Option<&Path>) -> Result<()> {
+    let mut described = serde_json::Map::new();
+    for name in router.collections() {
+        let settings = describe_collection(chroma, name)?;
+        println!("Collection: {}", name);
+        for (label, key) in DESCRIBED_SETTINGS {
+            println!("  {}: {}", label, settings[*key]);
+        }
+        for (key, value) in settings.iter().filter(|(k, _)| !DESCRIBED_SETTINGS.iter().any(|(_, d)| d == k)) {
+            println!("  {}: {}", key, value);
+        }
+        described.insert(name.to_string(), serde_json::Value::Object(settings));
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ChromaCollection {
-    id: String,
-    name: String,
+    if let Some(path) = output {
+        fs::write(path, serde_json::to_string_pretty(&described)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
 }
 
-#[derive(Debug, Serialize)]
-struct ChromaAddRequest {
-    ids: Vec<String>,
-    embeddings: Vec<Vec<f32>>,
-    metadatas: Vec<serde_json::Value>,
+#[derive(Serialize)]
+struct ChunkMapEntry {
+    chunk_id: String,
+    start_line: usize,
+    end_line: usize,
 }
 
-#[derive(Debug, Serialize)]
-struct ChromaQueryRequest {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    r#where: Option<serde_json::Value>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    limit: Option<usize>,
-    include: Vec<String>,
+/// Prints the chunk ranges indexing would produce for one file. Ranges are
+/// listed as stored, so overlapping chunks show up as overlapping ranges.
+fn chunk_map(
+    root: &SourceRoot,
+    path: &Path,
+    chunker: &CodeChunker,
+    detect_encoding: bool,
+    file_summary_chunks: bool,
+    min_distinct_tokens: Option<usize>,
+) -> Result<()> {
+    let content = match read_text_file(path, detect_encoding).with_context(|| format!("Failed to read {}", path.display()))? {
+        FileContent::Text(content, _) => content,
+        FileContent::Binary => anyhow::bail!("{} looks like a binary file", path.display()),
+        FileContent::Undecodable => anyhow::bail!("{} is not a text file", path.display()),
+    };
+    let relative = root.relative(path);
+
+    let mut chunks = chunker.chunk_code(&content, &relative);
+    if file_summary_chunks {
+        chunks.extend(chunker.summary_chunk(&content, &relative, detect_language(path, &content)));
+    }
+    chunks.retain(|c| is_embeddable(&c.text));
+    drop_low_information(&mut chunks, min_distinct_tokens, &RunStats::default());
+    let dirty = !chunker.git_commit.is_empty() && dirty_files(&root.path).contains(&root.root_relative(path));
+
+    let entries: Vec<ChunkMapEntry> = chunks
+        .into_iter()
+        .map(|c| ChunkMapEntry {
+            chunk_id: if dirty { format!("{}_dirty", c.id) } else { c.id },
+            start_line: c.metadata.start_line,
+            end_line: c.metadata.end_line,
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&entries)?);
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct ChromaQueryResponse {
-    ids: Vec<String>,
+/// Exit code for a run that completed but couldn't index everything.
+const EXIT_PARTIAL: u8 = 2;
+/// Exit code for `verify` when it finds inconsistencies.
+const EXIT_INCONSISTENT: u8 = 3;
+/// Exit code for a run stopped with Ctrl-C, as shells report for SIGINT.
+const EXIT_INTERRUPTED: u8 = 130;
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    init_logging(args.verbose);
+    let error_format = args.error_format;
+    match run(args) {
+        Ok(code) => code,
+        Err(err) => {
+            report_error(&err, error_format);
+            ExitCode::FAILURE
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct ChromaClient {
-    client: Client,
-    base_url: String,
-    collection_id: Option<String>,
-    collection_name: String,
+/// Sends log events to stdout. RUST_LOG takes precedence over -v; other
+/// crates only log warnings unless RUST_LOG asks for more.
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(format!("warn,indexer={}", level)));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false)
+        .without_time()
+        .with_ansi(std::io::stdout().is_terminal())
+        .init();
 }
 
-impl ChromaClient {
-    pub fn new(host: &str, port: &str, collection_name: &str) -> Result<Self> {
-        let client = Client::builder().timeout(std::time::Duration::from_secs(300)).build()?;
-        let base_url = format!("http://{}:{}/api/v2/tenants/default_tenant/databases/default_database", host, port);
+fn run(args: Args) -> Result<ExitCode> {
+    let mut roots = SourceRoot::from_args(&args.directory)?;
+    if roots.len() > 1 && matches!(args.command, Some(Command::Watch { .. })) {
+        anyhow::bail!("watch takes a single --directory");
+    }
 
-        let mut chroma = Self {
-            client,
-            base_url,
-            collection_id: None,
-            collection_name: collection_name.to_string(),
-        };
+    let mut filter = FileFilter::new(args.allow_no_ext, &args.force_index)?
+        .with_max_depth(args.max_depth)
+        .with_max_file_size(args.max_file_size)
+        .with_list_skipped(args.list_skipped)
+        .with_follow_symlinks(args.follow_symlinks)
+        .with_include_tests(args.include_tests)
+        .with_scope(&args.include, &args.exclude)?;
+    // --config applies to every root; otherwise each root reads its own
+    if let Some(path) = &args.config {
+        if let Some(config) = ProjectConfig::load(path, true)? {
+            filter = filter.with_config(config, args.replace_default_lists);
+        }
+    } else {
+        for root in &mut roots {
+            if let Some(config) = ProjectConfig::load(&root.path.join(PROJECT_CONFIG_FILE), false)? {
+                root.filter = Some(filter.clone().with_config(config, args.replace_default_lists));
+            }
+        }
+    }
+    let noise = match args.strip_noise {
+        true => Some(NoiseFilter::new(&args.noise_patterns, args.keep_noise_in_document)?),
+        false => None,
+    };
+    let id_mode = if args.stable_ids { IdMode::Stable } else { args.id_mode };
+    let tokenizer = load_tokenizer(args.size_unit, args.tokenizer.as_deref());
+    let size_unit = if tokenizer.is_some() { SizeUnit::Tokens } else { SizeUnit::Bytes };
 
-        chroma.get_or_create_collection()?;
-        Ok(chroma)
+    if let Some(Command::Explain { path }) = &args.command {
+        let (root, path) = SourceRoot::locate(&roots, path);
+        return explain(&root.path, &path, root.filter(&filter)).map(|_| ExitCode::SUCCESS);
     }
 
-    fn get_or_create_collection(&mut self) -> Result<()> {
-        let url = format!("{}/collections", self.base_url);
+    // Each root is tagged with the commit and branch of its own repository
+    let mut branch_sources = Vec::new();
+    for root in &mut roots {
+        root.git_commit = env_or_git("GIT_HASH", &root.path, &["rev-parse", "HEAD"]);
+        let env_branch = env_or_git("GIT_BRANCH", &root.path, &["rev-parse", "--abbrev-ref", "HEAD"]);
+        let (git_branch, branch_source) = resolve_branch(&root.path, args.branch.as_deref(), env_branch);
+        root.git_branch = git_branch;
+        branch_sources.push(branch_source);
+    }
+    let (git_commit, git_branch) = (roots[0].git_commit.clone(), roots[0].git_branch.clone());
+    if let Some(Command::ChunkMap { path }) = &args.command {
+        let (root, path) = SourceRoot::locate(&roots, path);
+        let chunker = CodeChunker::new(root.git_commit.clone(), root.git_branch.clone(), id_mode).with_version(args.version_label)
+            .with_context_lines(args.context_lines)
+            .with_line_ending(args.line_ending)
+            .with_noise_filter(noise)
+            .with_sizes(args.chunk_size, args.overlap)?
+            .with_tokenizer(tokenizer)
+            .with_strategy(args.chunk_strategy);
+        return chunk_map(root, &path, &chunker, args.detect_encoding, args.file_summary_chunks, args.min_distinct_tokens)
+            .map(|_| ExitCode::SUCCESS);
+    }
 
-        if let Ok(resp) = self.client.get(&url).send() {
-            if resp.status().is_success() {
-                let collections: Vec<ChromaCollection> = resp.json().unwrap_or_default();
-                for collection in collections {
-                    if collection.name == self.collection_name {
-                        self.collection_id = Some(collection.id);
-                        println!("Using existing collection: {}", self.collection_name);
-                        return Ok(());
-                    }
+    let (embed_var, embed_default) = args.embed_backend.url_var();
+    let embed_startup_timeout = Duration::from_secs(args.embed_startup_timeout_secs);
+    let embed_timeout = Duration::from_secs(args.embed_timeout_secs);
+    let store_timeout = Duration::from_secs(args.store_timeout_secs);
+    let embed_url = env::var(embed_var).unwrap_or_else(|_| embed_default.to_string());
+    parse_service_url(embed_var, &embed_url)?;
+    let chroma = ChromaEndpoint {
+        url: chroma_base_url(
+            args.chroma_url.as_deref(),
+            args.store_scheme,
+            &args.host,
+            &args.port,
+            args.chroma_tenant.as_deref(),
+            args.chroma_database.as_deref(),
+        )?,
+        timeout: store_timeout,
+        token: args.store_token.clone().or_else(|| env::var("CHROMA_TOKEN").ok()).filter(|token| !token.is_empty()),
+    };
+
+    let router = match &args.collection_by_type {
+        Some(spec) => CollectionRouter::parse(spec, &args.collection)?,
+        None => CollectionRouter::single(&args.collection),
+    };
+
+    if let Some(Command::PruneCollections { apply, branches }) = &args.command {
+        let branches = match branches.is_empty() {
+            true => {
+                let mut all = BTreeSet::new();
+                for root in &roots {
+                    all.extend(git_branches(&root.path)?);
                 }
+                all.into_iter().collect()
             }
-        }
-
-        let body = serde_json::json!({
-            "name": self.collection_name,
-            "metadata": { "hnsw:space": "cosine" }
-        });
+            false => branches.clone(),
+        };
+        let client = ChromaClient::connect(&chroma)?;
+        return prune_collections(&client, &router.collections(), &branches, *apply).map(|_| ExitCode::SUCCESS);
+    }
 
-        let response = self.client.post(&url).json(&body).send()?;
-        if response.status().is_success() {
-            let collection: ChromaCollection = response.json()?;
-            self.collection_id = Some(collection.id);
-            println!("Created new collection: {}", self.collection_name);
-        } else {
-            anyhow::bail!("Failed to create collection");
+    let router = if args.collection_per_branch {
+        if git_branch.is_empty() {
+            anyhow::bail!("--collection-per-branch requires GIT_BRANCH");
         }
+        if roots.iter().any(|root| root.git_branch != git_branch) {
+            anyhow::bail!("--collection-per-branch needs every --directory on the same branch");
+        }
+        router.per_branch(&git_branch)
+    } else {
+        router
+    };
 
-        Ok(())
+    if let Some(Command::DescribeCollection { output }) = &args.command {
+        let client = ChromaClient::connect(&chroma)?;
+        return describe(&client, &router, output.as_deref()).map(|_| ExitCode::SUCCESS);
     }
 
-    pub fn add_chunks(&self, chunks: &[Chunk], embeddings: Vec<Vec<f32>>) -> Result<()> {
-        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
-        let url = format!("{}/collections/{}/add", self.base_url, collection_id);
+    if args.stats_only {
+        if args.store != StoreKind::Chroma {
+            anyhow::bail!("--stats-only reads collections from ChromaDB; it doesn't work with --store memory or --store qdrant");
+        }
+        for name in router.collections() {
+            print_collection_stats(&chroma, name, args.stats_sample)?;
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
 
-        let request = ChromaAddRequest {
-            ids: chunks.iter().map(|c| c.id.clone()).collect(),
-            embeddings,
-            metadatas: chunks.iter().map(|c| serde_json::to_value(&c.metadata).unwrap()).collect(),
+    if let Some(Command::Search { query, query_flag, collections, all_collections, top_k, max_concurrent, query_prefix }) = &args.command {
+        let query = query.as_deref().or(query_flag.as_deref()).context("search needs a query")?;
+        let names = if *all_collections {
+            ChromaClient::connect(&chroma)?.list_collections()?
+        } else if collections.is_empty() {
+            let all = ChromaClient::connect(&chroma)?.list_collections()?;
+            router.collections().into_iter().flat_map(|name| with_shards(name, &all)).collect()
+        } else {
+            collections.clone()
+        };
+        let embedder = connect_embedder(args.embed_backend, &embed_url, args.embedding_model.as_deref(), embed_startup_timeout, embed_timeout, args.embed_max_retries, usize::MAX)?;
+        let embedding_model = embedding_model_id(args.embedding_model.as_deref(), embedder.as_ref());
+        let options = SearchOptions {
+            query,
+            query_prefix: query_prefix.as_deref(),
+            embed_prefix: args.embed_prefix.as_deref(),
+            top_k: *top_k,
+            max_concurrent: *max_concurrent,
+            embedding_model: embedding_model.as_deref(),
         };
+        let hits = search_collections(&chroma, &names, embedder.as_ref(), &options)?;
+        print_hits(&hits);
+        return Ok(ExitCode::SUCCESS);
+    }
 
-        let response = self.client.post(&url).json(&request).send()?;
-        if !response.status().is_success() {
-            let error_text = response.text().unwrap_or_default();
-            if !error_text.contains("already exists") && !error_text.contains("Duplicate") {
-                anyhow::bail!("Failed to add chunks: {}", error_text);
-            }
-        }
+    if args.batch_size.is_some() {
+        warn!("--batch-size is deprecated; use --embed-batch-size and --upload-batch-size");
+    }
+    let embed_batch_size = args.embed_batch_size.or(args.batch_size).unwrap_or(DEFAULT_BATCH_SIZE);
 
-        Ok(())
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()?;
     }
 
-    pub fn is_commit_indexed(&self, git_branch: &str, git_commit: &str) -> bool {
-        let Some(collection_id) = &self.collection_id else { return false };
-        let url = format!("{}/collections/{}/get", self.base_url, collection_id);
+    let mut collection_metadata: serde_json::Map<String, serde_json::Value> = args.collection_meta.into_iter().collect();
+    if let Some(distance) = args.distance {
+        collection_metadata.insert("hnsw:space".to_string(), distance.hnsw_space().into());
+    }
+    if let Some(prefix) = &args.embed_prefix {
+        collection_metadata.insert(EMBED_PREFIX_KEY.to_string(), prefix.clone().into());
+    }
+    if let Some(description) = &args.collection_description {
+        collection_metadata.insert("description".to_string(), description.clone().into());
+    }
+    collection_metadata.entry("chunk_size").or_insert(args.chunk_size.into());
+    collection_metadata.entry("chunk_overlap").or_insert(args.overlap.into());
+    collection_metadata.entry("chunk_size_unit").or_insert(size_unit.name().into());
 
-        let request = ChromaQueryRequest {
-            r#where: Some(serde_json::json!({
-                "$and": [{"git_branch": {"$eq": git_branch}}, {"git_commit": {"$eq": git_commit}}]
-            })),
-            limit: Some(1),
-            include: vec![],
-        };
+    if let Some(Command::Migrate { to, replace, page_size }) = &args.command {
+        let embedder = connect_embedder(args.embed_backend, &embed_url, args.embedding_model.as_deref(), embed_startup_timeout, embed_timeout, args.embed_max_retries, embed_batch_size)?;
+        if let Some(model) = embedding_model_id(args.embedding_model.as_deref(), embedder.as_ref()) {
+            collection_metadata.insert(EMBEDDING_MODEL_KEY.to_string(), model.into());
+        }
+        return migrate(&ChromaClient::connect(&chroma)?, &args.collection, to, *replace, *page_size, &collection_metadata, embedder.as_ref())
+            .map(|_| ExitCode::SUCCESS);
+    }
 
-        if let Ok(resp) = self.client.post(&url).json(&request).send() {
-            if let Ok(result) = resp.json::<ChromaQueryResponse>() {
-                return !result.ids.is_empty();
-            }
+    println!("=== Rust Codebase Indexer ===");
+    println!("Directory: {}", args.directory.join(", "));
+    match (args.embed_backend, &args.embedding_model) {
+        (EmbedBackend::Ollama, Some(model)) => println!("Ollama: {} ({})", embed_url, model),
+        (EmbedBackend::Ollama, None) => println!("Ollama: {}", embed_url),
+        (EmbedBackend::Tei, _) => println!("TEI: {}", embed_url),
+    }
+    println!("Collection: {}", router.collections().join(", "));
+    for (root, branch_source) in roots.iter().zip(&branch_sources) {
+        let prefix = if roots.len() > 1 { format!("{}: ", root.name()) } else { String::new() };
+        match branch_source {
+            Some(source) => println!("{}Git branch: {} (HEAD is detached; from {})", prefix, root.git_branch, source),
+            None if !root.git_branch.is_empty() => println!("{}Git branch: {}", prefix, root.git_branch),
+            None => {}
         }
-        false
+        if !root.git_commit.is_empty() { println!("{}Git commit: {}", prefix, &root.git_commit[..8.min(root.git_commit.len())]); }
     }
+    if let Some(version) = &args.version_label { println!("Version: {}", version); }
 
-    pub fn delete_old_commits(&self, git_branch: &str, current_commit: &str) -> Result<usize> {
-        let collection_id = self.collection_id.as_ref().context("Collection not initialized")?;
-        let url = format!("{}/collections/{}/get", self.base_url, collection_id);
+    let dirty_files: HashSet<String> = roots
+        .iter()
+        .filter(|root| !root.git_commit.is_empty())
+        .flat_map(|root| dirty_files(&root.path).into_iter().map(|path| root.labelled(path)))
+        .collect();
+    if !dirty_files.is_empty() {
+        println!("Working tree: {} files with uncommitted changes, tagged as dirty", dirty_files.len());
+    }
+    let force_reindex = args.force_reindex.iter()
+        .map(|raw| SourceRoot::force_reindex_path(&roots, raw))
+        .collect::<Result<HashSet<String>>>()?;
 
-        let request = ChromaQueryRequest {
-            r#where: Some(serde_json::json!({
-                "$and": [{"git_branch": {"$eq": git_branch}}, {"git_commit": {"$ne": current_commit}}]
-            })),
-            limit: Some(50000),
-            include: vec![],
-        };
+    let chunker = CodeChunker::new(git_commit, git_branch, id_mode).with_version(args.version_label)
+        .with_context_lines(args.context_lines)
+        .with_line_ending(args.line_ending)
+        .with_noise_filter(noise)
+        .with_sizes(args.chunk_size, args.overlap)?
+        .with_tokenizer(tokenizer)
+        .with_strategy(args.chunk_strategy);
+    let options = IndexOptions {
+        upload_batch_size: args.upload_batch_size.or(args.batch_size).unwrap_or(DEFAULT_BATCH_SIZE).max(1),
+        on_bad_embedding: args.on_bad_embedding,
+        max_chunks: args.max_chunks,
+        resume_manifest: args.resume_manifest,
+        manifest: args.manifest,
+        reindex_order: args.reindex_order,
+        archive_old_commits: args.archive_old_commits,
+        purge_archived: args.purge_archived,
+        detect_encoding: args.detect_encoding,
+        store_embedding_norm: args.store_embedding_norm,
+        require_known_language: args.require_known_language,
+        max_inflight_embeddings: args.max_inflight_embeddings,
+        modified_since_last_index: args.modified_since_last_index,
+        since_ref: args.since.clone(),
+        transforms: TransformPipeline::new(&args.transforms, &args.document_transforms),
+        filter,
+        append_only: args.append_only,
+        stats_retries: args.stats_retries,
+        file_summary_chunks: args.file_summary_chunks,
+        with_blame: args.with_blame,
+        embed_prefix: args.embed_prefix.clone(),
+        checkpoint_interval: args.checkpoint_interval,
+        dirty_files,
+        force_reindex,
+        min_distinct_tokens: args.min_distinct_tokens,
+        progress: !args.no_progress,
+        dedup_chunks: args.dedup_chunks,
+        max_chunks_per_file: args.max_chunks_per_file,
+        on_chunk_limit: args.on_chunk_limit,
+        read_permits: Semaphore::new(args.read_concurrency.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))),
+        dir_concurrency: args.dir_concurrency.max(1),
+        generated_markers: match args.index_generated {
+            true => Vec::new(),
+            false => GENERATED_MARKERS.iter().map(|m| m.to_string()).chain(args.generated_markers.iter().map(|m| m.to_lowercase())).collect(),
+        },
+    };
 
-        let response = self.client.post(&url).json(&request).send()?;
-        if !response.status().is_success() {
-            return Ok(0);
-        }
+    if args.dry_run {
+        return dry_run(&roots, &chunker, &options).map(|_| ExitCode::SUCCESS);
+    }
 
-        let result: ChromaQueryResponse = response.json()?;
-        if result.ids.is_empty() {
-            return Ok(0);
+    if let Some(Command::Verify { dup_threshold, sample_size }) = &args.command {
+        if roots.iter().any(|root| !root.same_git(&roots[0])) {
+            anyhow::bail!("verify checks roots at one branch and commit; run it once per repository");
         }
+        let all_collections = ChromaClient::connect(&chroma)?.list_collections()?;
+        let mut clients = Vec::new();
+        for name in router.collections() {
+            for shard in with_shards(name, &all_collections) {
+                let client = ChromaClient::open(&chroma, &shard)?;
+                println!("Collection: {} ({} chunks)", shard, client.count()?);
+                if let Some(threshold) = dup_threshold {
+                    find_near_duplicates(&client, *threshold, *sample_size)?;
+                }
+                clients.push(client);
+            }
+        }
+        println!("Checking consistency...");
+        let expected = expected_chunks(&roots, &chunker, &options)?;
+        let problems = check_consistency(&clients, &chunker.git_branch, chunker.version.as_deref(), &chunker.git_commit, &expected)?;
+        return Ok(if problems > 0 { ExitCode::from(EXIT_INCONSISTENT) } else { ExitCode::SUCCESS });
+    }
 
-        let count = result.ids.len();
-        for chunk_ids in result.ids.chunks(1000) {
-            let delete_url = format!("{}/collections/{}/delete", self.base_url, collection_id);
-            let delete_body = serde_json::json!({ "ids": chunk_ids });
-            self.client.post(&delete_url).json(&delete_body).send()?;
+    let store_options = StoreOptions {
+        timeout: store_timeout,
+        collection_max_chunks: args.collection_max_chunks,
+        compress_uploads: args.compress_uploads,
+        output_embeddings: args.output_embeddings,
+    };
+    println!("Connecting to embedding service at {}...", embed_url);
+    let embedder = connect_embedder(args.embed_backend, &embed_url, args.embedding_model.as_deref(), embed_startup_timeout, embed_timeout, args.embed_max_retries, embed_batch_size)?;
+    println!("  Ready!");
+    // New collections record the model so later runs and searches can't mix models
+    let embedding_model = embedding_model_id(args.embedding_model.as_deref(), embedder.as_ref());
+    if let Some(model) = &embedding_model {
+        collection_metadata.insert(EMBEDDING_MODEL_KEY.to_string(), model.clone().into());
+    }
+    let stores = open_stores(args.store, &chroma, &args.qdrant_url, &router, &collection_metadata, &store_options)?;
+    let mut indexer = CodebaseIndexer::new(stores, router, embedder, chunker);
+    indexer.check_embedding_model(embedding_model.as_deref())?;
+    let cache_path = match (&args.embedding_cache, &args.cache_dir) {
+        _ if args.no_cache => None,
+        (Some(path), _) => Some(path.clone()),
+        (None, Some(dir)) => {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create cache directory {}", dir.display()))?;
+            Some(dir.join(EMBEDDING_CACHE_FILE))
         }
+        (None, None) => None,
+    };
+    if let Some(path) = &cache_path {
+        let model = embedding_model
+            .clone()
+            .context("--embedding-cache needs a model id; the embedding service doesn't report one, so pass --embedding-model")?;
+        indexer = indexer.with_embedding_cache(EmbeddingCache::open(path, &model)?);
+    }
+    let index_started = Instant::now();
+    install_interrupt_handler()?;
+    let outcome = indexer.index(&roots, &options)?;
+    if let Some(path) = &args.json_summary {
+        indexer.run_summary(&outcome, index_started.elapsed()).write(path)?;
+    }
+    if outcome.interrupted {
+        return Ok(ExitCode::from(EXIT_INTERRUPTED));
+    }
 
-        Ok(count)
+    if let Some(Command::Watch { watch_debounce_ms }) = args.command {
+        indexer.watch(&roots[0], &options, Duration::from_millis(watch_debounce_ms))?;
     }
 
-    pub fn count(&self) -> usize {
-        let Some(collection_id) = &self.collection_id else { return 0 };
-        let url = format!("{}/collections/{}/count", self.base_url, collection_id);
-        self.client.get(&url).send().ok()
-            .and_then(|r| r.json().ok())
-            .unwrap_or(0)
+    if outcome.is_partial() {
+        println!("Completed with failures: {} files errored, {} chunks skipped", outcome.errored_files, outcome.skipped_chunks);
+        return Ok(ExitCode::from(EXIT_PARTIAL));
     }
+    Ok(ExitCode::SUCCESS)
 }
 
-// ============================================================================
-// Indexer
-// ============================================================================
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub struct CodebaseIndexer {
-    chroma: ChromaClient,
-    embedding_client: EmbeddingClient,
-    chunker: CodeChunker,
-    git_commit: String,
-    git_branch: String,
-}
+    fn chunker(commit: &str, id_mode: IdMode) -> CodeChunker {
+        CodeChunker::new(commit.to_string(), "main".to_string(), id_mode)
+    }
 
-impl CodebaseIndexer {
-    pub fn new(chroma_host: &str, chroma_port: &str, collection: &str, embed_url: &str, git_commit: String, git_branch: String) -> Result<Self> {
-        println!("Connecting to ChromaDB at {}:{}...", chroma_host, chroma_port);
-        let chroma = ChromaClient::new(chroma_host, chroma_port, collection)?;
+    /// `n` distinct lines of about 40 bytes each.
+    fn numbered_lines(n: usize) -> String {
+        (1..=n).map(|i| format!("let value_{:04} = compute({}) + offset;\n", i, i)).collect()
+    }
 
-        println!("Connecting to embedding service at {}...", embed_url);
-        let embedding_client = EmbeddingClient::new(embed_url)?;
-        println!("  Ready!");
+    #[test]
+    fn stable_ids_survive_a_new_commit() {
+        let content = numbered_lines(200);
+        let before: Vec<String> = chunker("aaaaaaaa11", IdMode::Stable).chunk_code(&content, "src/lib.rs").into_iter().map(|c| c.id).collect();
+        let after: Vec<String> = chunker("bbbbbbbb22", IdMode::Stable).chunk_code(&content, "src/lib.rs").into_iter().map(|c| c.id).collect();
+        assert!(before.len() > 1);
+        assert_eq!(before, after);
+        assert!(before.iter().all(|id| !id.contains("aaaaaaaa")));
+    }
 
-        let chunker = CodeChunker::new(git_commit.clone(), git_branch.clone());
+    #[test]
+    fn line_ids_follow_the_line_range_and_commit() {
+        let content = numbered_lines(200);
+        let chunks = chunker("aaaaaaaa11", IdMode::Lines).chunk_code(&content, "src/lib.rs");
+        for chunk in &chunks {
+            let expected = format!("main_aaaaaaaa_src_lib_rs_{}_{}", chunk.metadata.start_line, chunk.metadata.end_line);
+            assert_eq!(chunk.id, expected);
+        }
+        let other = chunker("bbbbbbbb22", IdMode::Lines).chunk_code(&content, "src/lib.rs");
+        assert_ne!(chunks[0].id, other[0].id);
+    }
 
-        Ok(Self { chroma, embedding_client, chunker, git_commit, git_branch })
+    #[test]
+    fn chunks_rebuild_from_their_line_ranges() {
+        // Short and long lines mixed, so overlaps of varying length are carried
+        let content: String = (1..=120)
+            .map(|i| match i % 7 {
+                0 => format!("// {}\n", "long comment line ".repeat(8)),
+                3 => "}\n".to_string(),
+                _ => format!("let v{} = f({});\n", i, i),
+            })
+            .collect();
+        let lines: Vec<&str> = content.lines().collect();
+        let chunks = chunker("aaaaaaaa11", IdMode::Lines).with_sizes(300, 100).unwrap().chunk_code(&content, "a.rs");
+        assert!(chunks.len() > 5);
+        for chunk in &chunks {
+            let rebuilt = lines[chunk.metadata.start_line - 1..chunk.metadata.end_line].join("\n");
+            assert_eq!(chunk.text, rebuilt, "chunk {}-{}", chunk.metadata.start_line, chunk.metadata.end_line);
+        }
+        // Each chunk starts after the previous one and they cover the file
+        for pair in chunks.windows(2) {
+            assert!(pair[1].metadata.start_line > pair[0].metadata.start_line);
+            assert!(pair[1].metadata.start_line <= pair[0].metadata.end_line + 1);
+        }
+        assert_eq!(chunks[0].metadata.start_line, 1);
+        assert_eq!(chunks.last().unwrap().metadata.end_line, lines.len());
     }
 
-    pub fn index(&self, directory: &Path, batch_size: usize) -> Result<()> {
-        println!("Indexing {}...", directory.display());
+    #[test]
+    fn missing_grammar_falls_back_to_line_chunks() {
+        // Go is only used here, so marking its grammar as failed can't affect other tests
+        let content: String = (1..=60).map(|i| format!("func f{}() int {{\n\treturn {}\n}}\n", i, i)).collect();
+        let lines = chunker("aaaaaaaa11", IdMode::Lines).with_sizes(400, 100).unwrap();
+        let syntactic = chunker("aaaaaaaa11", IdMode::Lines).with_sizes(400, 100).unwrap().with_strategy(ChunkStrategy::Syntactic);
+        let expected: Vec<(usize, usize)> = lines.chunk_code(&content, "main.go").iter().map(|c| (c.metadata.start_line, c.metadata.end_line)).collect();
+        assert_ne!(syntactic.chunk_code(&content, "main.go").iter().map(|c| (c.metadata.start_line, c.metadata.end_line)).collect::<Vec<_>>(), expected);
 
-        // Check if already indexed
-        if !self.git_commit.is_empty() && !self.git_branch.is_empty() {
-            if self.chroma.is_commit_indexed(&self.git_branch, &self.git_commit) {
-                println!("Branch {} at commit {} already indexed.", self.git_branch, &self.git_commit[..8.min(self.git_commit.len())]);
-                println!("Total chunks: {}", self.chroma.count());
-                return Ok(());
-            }
+        assert!(grammar_failed("go", &"simulated missing grammar"));
+        assert!(!grammar_failed("go", &"simulated missing grammar"));
+        let chunks = syntactic.chunk_code(&content, "main.go");
+        assert_eq!(chunks.iter().map(|c| (c.metadata.start_line, c.metadata.end_line)).collect::<Vec<_>>(), expected);
+    }
 
-            // Clean up old commits for this branch
-            let deleted = self.chroma.delete_old_commits(&self.git_branch, &self.git_commit)?;
-            if deleted > 0 {
-                println!("Cleaned up {} old chunks", deleted);
+    /// Answers one HTTP request per entry of `statuses`, in order, and counts
+    /// the requests it saw. Returns the base URL.
+    fn serve_statuses(statuses: Vec<u16>) -> (String, Arc<AtomicUsize>) {
+        use std::io::{BufRead, BufReader, Read};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&requests);
+        thread::spawn(move || {
+            for status in statuses {
+                let Ok((stream, _)) = listener.accept() else { return };
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                seen.fetch_add(1, Ordering::SeqCst);
+                let payload = "[[0.5,0.5]]";
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    payload.len(),
+                    payload
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
             }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn embedding_requests_retry_on_overload() {
+        let (url, requests) = serve_statuses(vec![503, 503, 200]);
+        let client = http_client(Duration::from_secs(5)).unwrap();
+        let started = Instant::now();
+        let response = post_with_retries(&client, &format!("{}/embed", url), &serde_json::json!({"inputs": ["x"]}), 3).unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        // Two backoffs of at least 500ms and 1000ms
+        assert!(started.elapsed() >= Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn embedding_requests_give_up_after_max_retries() {
+        let (url, requests) = serve_statuses(vec![503, 503, 503]);
+        let client = http_client(Duration::from_secs(5)).unwrap();
+        let err = post_with_retries(&client, &format!("{}/embed", url), &serde_json::json!({"inputs": ["x"]}), 1).unwrap_err();
+        assert!(format!("{:#}", err).contains("after 1 retries"));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn embedding_backoff_doubles_with_jitter_and_caps() {
+        for (attempt, base) in [(1, 500), (2, 1000), (3, 2000)] {
+            let delay = embed_backoff(attempt).as_millis() as u64;
+            assert!((base..=base + base / 2).contains(&delay), "attempt {}: {}ms", attempt, delay);
         }
+        assert!(embed_backoff(40).as_millis() as u64 <= EMBED_BACKOFF_MAX_MS * 3 / 2);
+    }
 
-        // Scan files
-        println!("Scanning...");
-        let files = self.scan_directory(directory)?;
-        println!("Found {} files", files.len());
+    /// A fresh directory under the system temp dir, unique to this test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("indexer-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-        if files.is_empty() {
-            return Ok(());
+    #[test]
+    fn overlapping_roots_are_rejected() {
+        let dir = temp_dir("overlap");
+        let (app, lib, nested) = (dir.join("app"), dir.join("lib"), dir.join("app/vendor/inner"));
+        for path in [&app, &lib, &nested] {
+            fs::create_dir_all(path).unwrap();
         }
+        let overlap = |paths: &[&Path]| {
+            let roots: Vec<SourceRoot> = paths
+                .iter()
+                .map(|path| SourceRoot { path: path.to_path_buf(), label: None, git_commit: String::new(), git_branch: String::new(), filter: None })
+                .collect();
+            SourceRoot::check_overlap(&roots).map_err(|err| err.to_string())
+        };
 
-        // Process files in parallel to generate chunks
-        let processed = Arc::new(Mutex::new(0usize));
-        let total = files.len();
+        assert_eq!(overlap(&[&app, &lib]), Ok(()));
+        assert!(overlap(&[&nested, &app]).unwrap_err().contains("is inside"));
+        assert!(overlap(&[&app, &nested]).unwrap_err().contains("is inside"));
+        assert!(overlap(&[&app, &app.join(".")]).unwrap_err().contains("same directory"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 
-        let chunks: Vec<Chunk> = files
-            .par_iter()
-            .filter_map(|path| {
-                let content = fs::read_to_string(path).ok()?;
-                if content.is_empty() { return None; }
+    #[test]
+    fn cleanup_keeps_every_live_commit() {
+        let store = InMemoryStore::new(&serde_json::Map::new());
+        let content = numbered_lines(10);
+        for (commit, path) in [("aaaaaaaa11", "app/a.rs"), ("bbbbbbbb22", "lib/b.rs"), ("cccccccc33", "lib/b.rs")] {
+            let chunks = chunker(commit, IdMode::Lines).chunk_code(&content, path);
+            let embeddings = vec![vec![0.5, 0.5]; chunks.len()];
+            store.add_chunks(&chunks, embeddings).unwrap();
+        }
+        // Two repositories on one branch name, each at its own commit
+        let deleted = store.delete_old_commits("main", None, &["aaaaaaaa11", "cccccccc33"]).unwrap();
+        assert_eq!(deleted, 1);
+        assert!(store.is_commit_indexed("main", None, "aaaaaaaa11"));
+        assert!(store.is_commit_indexed("main", None, "cccccccc33"));
+        assert!(!store.is_commit_indexed("main", None, "bbbbbbbb22"));
+    }
 
-                let relative = path.strip_prefix(directory).unwrap_or(path).to_string_lossy().to_string();
-                let file_chunks = self.chunker.chunk_code(&content, &relative);
+    #[test]
+    fn roots_group_by_commit_and_own_their_labels() {
+        let root = |label: &str, commit: &str| SourceRoot {
+            path: PathBuf::from(format!("/src/{}", label)),
+            label: Some(label.to_string()),
+            git_commit: commit.to_string(),
+            git_branch: "main".to_string(),
+            filter: None,
+        };
+        let roots = vec![root("web", "aaaa"), root("api", "bbbb"), root("docs", "aaaa")];
+        let groups = RootGroup::of(&roots);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].name(), "web, docs");
+        assert!(groups[0].owns("docs/index.md"));
+        assert!(!groups[0].owns("api/main.rs"));
+        assert!(!groups[0].owns("webapp/main.rs"));
+        assert_eq!(SourceRoot::owning(&roots, "api/main.rs").map(|root| root.git_commit.as_str()), Some("bbbb"));
+    }
 
-                let mut count = processed.lock().unwrap();
-                *count += 1;
-                if *count % 100 == 0 {
-                    println!("Processed {}/{} files", *count, total);
-                }
+    #[test]
+    fn whitespace_chunks_are_never_embedded() {
+        let chunks = chunker("aaaaaaaa11", IdMode::Lines).chunk_code("   \n\t\n    \n", "a.rs");
+        assert!(chunks.iter().all(|chunk| !is_embeddable(&chunk.text)));
+        assert!(!is_embeddable("{ }"));
+        assert!(is_embeddable("fn a"));
 
-                Some(file_chunks)
-            })
-            .flatten()
-            .collect();
+        // A degenerate vector has a zero norm, not NaN, and is rejected before storage
+        let zeros = vec![0.0f32; 8];
+        assert_eq!(l2_norm(&zeros), 0.0);
+        assert!(embedding_problem(&zeros, None).is_some());
+        assert!(!l2_norm(&[0.6, 0.8]).is_nan());
+    }
 
-        println!("Generated {} chunks", chunks.len());
+    #[test]
+    fn allowlisted_extensionless_files_are_indexed() {
+        let default = FileFilter::new(Vec::new(), &[]).unwrap();
+        let allowed = FileFilter::new(vec!["Justfile".to_string()], &[]).unwrap();
+        assert!(!default.should_index_file(Path::new("tools/Justfile")));
+        assert!(allowed.should_index_file(Path::new("tools/Justfile")));
+        assert!(allowed.should_index_file(Path::new("Makefile")));
+        assert!(!allowed.should_index_file(Path::new("justfile")));
+        assert!(!allowed.should_index_file(Path::new("LICENSE")));
+    }
 
-        // Upload with pipelining
-        let total_batches = (chunks.len() + batch_size - 1) / batch_size;
-        let batches: Vec<_> = chunks.chunks(batch_size).collect();
+    #[test]
+    fn crlf_files_join_per_line_ending_mode() {
+        let content = "fn main() {\r\n    run();\r\n}\r\n";
+        let lf = chunker("aaaaaaaa11", IdMode::Content).chunk_code(content, "main.rs");
+        let preserve = chunker("aaaaaaaa11", IdMode::Content).with_line_ending(LineEnding::Preserve).chunk_code(content, "main.rs");
+        assert_eq!(lf.len(), 1);
+        assert_eq!(lf[0].text, "fn main() {\n    run();\n}");
+        assert_eq!(preserve.len(), 1);
+        assert_eq!(preserve[0].text, "fn main() {\r\n    run();\r\n}");
+        assert_ne!(lf[0].id, preserve[0].id);
 
-        let (tx, rx) = mpsc::channel::<(Vec<Chunk>, Vec<Vec<f32>>)>();
-        let chroma = self.chroma.clone();
+        // Mostly-LF files keep LF even when preserving
+        let mixed = "a = 1\nb = 2\nc = 3\r\n";
+        assert_eq!(LineEnding::Preserve.separator(mixed), "\n");
+    }
 
-        let upload_thread = thread::spawn(move || -> Result<()> {
-            while let Ok((chunks, embeddings)) = rx.recv() {
-                chroma.add_chunks(&chunks, embeddings)?;
-            }
-            Ok(())
-        });
+    #[test]
+    fn generated_markers_are_found_in_the_header() {
+        let markers: Vec<String> = GENERATED_MARKERS.iter().map(|m| m.to_string()).collect();
+        assert_eq!(generated_marker("// Code generated by protoc-gen-go. DO NOT EDIT.\npackage api\n", &markers), Some("do not edit"));
+        assert_eq!(generated_marker("/* @generated */\nexport const a = 1;\n", &markers), Some("@generated"));
+        assert_eq!(generated_marker("fn main() {\n    println!(\"hi\");\n}\n", &markers), None);
 
-        for (i, batch) in batches.iter().enumerate() {
-            println!("Batch {}/{}", i + 1, total_batches);
-            let texts: Vec<&str> = batch.iter().map(|c| c.text.as_str()).collect();
-            let embeddings = self.embedding_client.encode(&texts)?;
-            tx.send((batch.to_vec(), embeddings)).ok();
-        }
+        // Only the first lines count, and no markers means no check
+        let late = format!("{}// @generated\n", "let x = 1;\n".repeat(GENERATED_MARKER_LINES));
+        assert_eq!(generated_marker(&late, &markers), None);
+        assert_eq!(generated_marker("// @generated\n", &[]), None);
+    }
 
-        drop(tx);
-        upload_thread.join().map_err(|_| anyhow::anyhow!("Upload thread panicked"))??;
+    #[test]
+    fn repetitive_chunks_fall_below_min_distinct_tokens() {
+        let repetitive = "assert!(ok);\n".repeat(40);
+        let varied = numbered_lines(10);
+        assert_eq!(distinct_tokens(&repetitive), 1);
+        assert!(distinct_tokens(&varied) > 20);
 
-        println!("Done! Total chunks: {}", self.chroma.count());
-        Ok(())
+        let chunker = chunker("aaaaaaaa11", IdMode::Lines);
+        let mut chunks = chunker.chunk_code(&repetitive, "a.rs");
+        chunks.extend(chunker.chunk_code(&varied, "b.rs"));
+        let total = chunks.len();
+        let stats = RunStats::default();
+
+        // Disabled by default
+        drop_low_information(&mut chunks, None, &stats);
+        assert_eq!(chunks.len(), total);
+
+        drop_low_information(&mut chunks, Some(5), &stats);
+        assert!(!chunks.is_empty());
+        assert!(chunks.iter().all(|chunk| chunk.metadata.file_path == "b.rs"));
+        assert_eq!(stats.low_information_chunks.load(Ordering::Relaxed), total - chunks.len());
+        assert_eq!(stats.skipped_files().get("low_information_chunks"), Some(&(total - chunks.len())));
     }
 
-    fn scan_directory(&self, directory: &Path) -> Result<Vec<PathBuf>> {
-        let gitignore = load_gitignore(directory);
-        let ignore_dirs: HashSet<&str> = ALWAYS_IGNORE_DIRS.iter().cloned().collect();
-        let mut files = Vec::new();
+    #[test]
+    fn small_chunk_size_sets_the_boundaries() {
+        // Ten lines of ten bytes each, newline included
+        let content: String = (1..=10).map(|i| format!("line {:04}\n", i)).collect();
+        let ranges = |chunk_size, overlap| {
+            let chunker = chunker("aaaaaaaa11", IdMode::Lines).with_sizes(chunk_size, overlap).unwrap();
+            chunker.chunk_code(&content, "a.txt").iter().map(|c| (c.metadata.start_line, c.metadata.end_line)).collect::<Vec<_>>()
+        };
+        assert_eq!(ranges(35, 0), [(1, 3), (4, 6), (7, 9), (10, 10)]);
+        assert_eq!(ranges(35, 10), [(1, 3), (3, 5), (5, 7), (7, 9), (9, 10)]);
 
-        for entry in walkdir::WalkDir::new(directory)
-            .follow_links(false)
-            .into_iter()
-            .filter_entry(|e| {
-                let path = e.path();
-                let is_dir = e.file_type().is_dir();
-
-                if is_dir {
-                    let name = e.file_name().to_str().unwrap_or("");
-                    if ignore_dirs.contains(name) {
-                        return false;
-                    }
-                }
+        assert!(chunker("aaaaaaaa11", IdMode::Lines).with_sizes(0, 0).is_err());
+        assert!(chunker("aaaaaaaa11", IdMode::Lines).with_sizes(100, 100).is_err());
+    }
 
-                if let Some(ref gi) = gitignore {
-                    if gi.matched(path, is_dir).is_ignore() {
-                        return false;
-                    }
-                }
+    #[test]
+    fn exclude_wins_over_include() {
+        let include = ["packages/api/**".to_string()];
+        let exclude = ["**/generated/**".to_string()];
+        let filter = FileFilter::new(Vec::new(), &[]).unwrap().with_scope(&include, &exclude).unwrap();
+        let reason = |path: &str| filter.scope_skip_reason(Path::new(path));
 
-                true
-            })
-        {
-            let entry = match entry { Ok(e) => e, Err(_) => continue };
-            if !entry.file_type().is_file() { continue; }
+        assert!(reason("packages/api/src/main.rs").is_none());
+        assert!(matches!(reason("packages/web/src/main.rs"), Some(SkipReason::NotIncluded)));
+        assert!(matches!(reason("packages/api/generated/types.rs"), Some(SkipReason::Excluded(pattern)) if pattern == "**/generated/**"));
 
-            let path = entry.path();
-            if !should_index_file(path) { continue; }
+        // Without --include everything not excluded is in scope
+        let filter = FileFilter::new(Vec::new(), &[]).unwrap().with_scope(&[], &exclude).unwrap();
+        assert!(filter.scope_skip_reason(Path::new("packages/web/src/main.rs")).is_none());
+        assert!(filter.scope_skip_reason(Path::new("generated/types.rs")).is_some());
+        assert!(FileFilter::new(Vec::new(), &[]).unwrap().with_scope(&["[".to_string()], &[]).is_err());
+    }
 
-            // Skip large files (>10MB)
-            if let Ok(meta) = path.metadata() {
-                if meta.len() > 10 * 1024 * 1024 { continue; }
-            }
+    #[test]
+    fn re_adding_a_chunk_id_updates_the_stored_record() {
+        let store = InMemoryStore::new(&serde_json::Map::new());
+        let chunker = chunker("aaaaaaaa11", IdMode::Lines);
+        let before = chunker.chunk_code("fn a() {\n    old();\n}\n", "a.rs");
+        let after = chunker.chunk_code("fn a() {\n    new();\n}\n", "a.rs");
+        assert_eq!(before[0].id, after[0].id);
 
-            files.push(path.to_path_buf());
-        }
+        store.add_chunks(&before, vec![vec![1.0, 0.0]]).unwrap();
+        store.add_chunks(&after, vec![vec![0.0, 1.0]]).unwrap();
+        assert_eq!(store.count_with_retries(0), Some(1));
+        let records = store.records.lock().unwrap();
+        let record = &records[&after[0].id];
+        assert_eq!(record.document, "fn a() {\n    new();\n}");
+        assert_eq!(record.embedding, [0.0, 1.0]);
+    }
 
-        Ok(files)
+    #[test]
+    fn dedup_keeps_one_shared_header_and_lists_its_files() {
+        let header = "// Copyright 2024 Example Corp.\n// Licensed under the MIT license.\n// See LICENSE for details.\n";
+        let chunker = chunker("aaaaaaaa11", IdMode::Lines).with_sizes(100, 0).unwrap();
+        let mut chunks = chunker.chunk_code(&format!("{}fn alpha() -> u32 {{ 1 }}\n", header), "src/a.rs");
+        chunks.extend(chunker.chunk_code(&format!("{}fn beta() -> u32 {{ 2 }}\n", header), "src/b.rs"));
+        assert_eq!(chunks.len(), 4);
+
+        assert_eq!(dedup_chunks(&mut chunks), 1);
+        assert_eq!(chunks.len(), 3);
+        let shared: Vec<&Chunk> = chunks.iter().filter(|chunk| chunk.metadata.source_files.is_some()).collect();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].metadata.file_path, "src/a.rs");
+        assert_eq!(shared[0].metadata.source_files.as_deref(), Some(r#"["src/a.rs","src/b.rs"]"#));
     }
-}
 
-// ============================================================================
-// CLI
-// ============================================================================
+    #[test]
+    fn test_files_are_skipped_unless_included() {
+        let default = FileFilter::new(Vec::new(), &[]).unwrap();
+        let included = FileFilter::new(Vec::new(), &[]).unwrap().with_include_tests(true);
+        for path in ["src/foo.test.ts", "src/foo.spec.js", "src/__tests__/foo.ts"] {
+            assert!(!default.should_index_file(Path::new(path)), "{}", path);
+            assert!(included.should_index_file(Path::new(path)), "{}", path);
+        }
+        assert!(default.should_index_file(Path::new("src/foo.ts")));
+    }
 
-#[derive(Parser)]
-#[command(name = "indexer", about = "Index codebase for vector search")]
-struct Args {
-    #[arg(long)]
-    directory: String,
-    #[arg(long, default_value = "chromadb")]
-    host: String,
-    #[arg(long, default_value = "8000")]
-    port: String,
-    #[arg(long, default_value = "codebase")]
-    collection: String,
-    #[arg(long, default_value_t = 128)]
-    batch_size: usize,
-}
+    #[test]
+    fn mismatched_embedding_lengths_follow_the_policy() {
+        let chunker = chunker("aaaaaaaa11", IdMode::Lines);
+        let chunks: Vec<Chunk> = ["a.rs", "b.rs", "c.rs"].iter().flat_map(|path| chunker.chunk_code("fn main() {}\n", path)).collect();
+        let embeddings = || vec![vec![1.0, 0.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0, 0.0]];
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let directory = PathBuf::from(&args.directory);
+        // The first good vector fixes the dimension; the short one is dropped
+        let mut dim = None;
+        let (kept, vectors, skipped) = validate_embeddings(&chunks, embeddings(), &mut dim, BadEmbeddingPolicy::Skip).unwrap();
+        assert_eq!((kept.len(), vectors.len(), skipped), (2, 2, 1));
+        assert_eq!(kept[1].metadata.file_path, "c.rs");
+        assert_eq!(dim, Some(3));
+
+        let mut dim = None;
+        let err = validate_embeddings(&chunks, embeddings(), &mut dim, BadEmbeddingPolicy::Fail).unwrap_err().to_string();
+        assert!(err.contains("b.rs") && err.contains("dimension 2 (expected 3)"), "{}", err);
 
-    if !directory.is_dir() {
-        anyhow::bail!("{} is not a directory", args.directory);
+        // A dimension carried over from the collection rejects every vector
+        let mut dim = Some(4);
+        let (kept, _, skipped) = validate_embeddings(&chunks, embeddings(), &mut dim, BadEmbeddingPolicy::Skip).unwrap();
+        assert_eq!((kept.len(), skipped), (0, 3));
+        assert!(validate_embeddings(&chunks, embeddings(), &mut Some(4), BadEmbeddingPolicy::Fail).is_err());
+
+        assert!(validate_embeddings(&chunks, vec![vec![1.0]], &mut None, BadEmbeddingPolicy::Skip).is_err());
     }
 
-    let git_commit = env::var("GIT_HASH").unwrap_or_default();
-    let git_branch = env::var("GIT_BRANCH").unwrap_or_default();
-    let embed_url = env::var("TEI_URL").unwrap_or_else(|_| "http://localhost:8081".to_string());
+    #[test]
+    fn detect_language_by_name_extension_content_and_shebang() {
+        let cases: &[(&str, &str, Option<&str>)] = &[
+            ("src/main.rs", "fn main() {}", Some("rust")),
+            ("app/View.TSX", "export const View = () => null;", Some("typescript")),
+            ("lib/util.py", "", Some("python")),
+            ("Makefile", "all:\n\tcargo build", Some("make")),
+            ("docker/Dockerfile", "FROM rust", Some("dockerfile")),
+            ("include/vec.h", "namespace geo { class Vec; }", Some("cpp")),
+            ("include/list.h", "struct list { int n; };", Some("c")),
+            ("ios/View.h", "@interface View : NSObject\n@end", Some("objective-c")),
+            ("bin/deploy", "#!/usr/bin/env -S python3 -u\nprint()", Some("python")),
+            ("bin/run", "#!/bin/bash\nset -e", Some("shell")),
+            ("scripts/build", "#!/usr/bin/env node\n", Some("javascript")),
+            ("analysis.r", "x <- 1", None),
+            ("notes/todo", "buy milk", None),
+            ("bin/tool", "#!/usr/local/bin/unknown", None),
+        ];
+        for &(path, content, expected) in cases {
+            assert_eq!(detect_language(Path::new(path), content), expected, "{}", path);
+        }
+        // Files without a language are tagged with their extension
+        assert_eq!(language_tag(Path::new("data/table.CSV"), ""), Some("csv".to_string()));
+        assert_eq!(language_tag(Path::new("notes/todo"), ""), None);
+    }
 
-    println!("=== Rust Codebase Indexer ===");
-    println!("Directory: {}", args.directory);
-    println!("TEI: {}", embed_url);
-    println!("Collection: {}", args.collection);
-    if !git_branch.is_empty() { println!("Git branch: {}", git_branch); }
-    if !git_commit.is_empty() { println!("Git commit: {}", &git_commit[..8.min(git_commit.len())]); }
+    #[test]
+    fn delete_file_except_keeps_the_new_ids() {
+        let store = InMemoryStore::new(&serde_json::Map::new());
+        let chunker = chunker("aaaaaaaa11", IdMode::Lines).with_sizes(400, 0).unwrap();
+        let old = chunker.chunk_code(&numbered_lines(20), "src/a.rs");
+        let new = chunker.chunk_code(&numbered_lines(8), "src/a.rs");
+        let other = chunker.chunk_code(&numbered_lines(20), "src/b.rs");
+        assert!(old.len() > new.len());
+        for chunks in [&old, &new, &other] {
+            store.add_chunks(chunks, vec![vec![1.0, 0.0]; chunks.len()]).unwrap();
+        }
 
-    let indexer = CodebaseIndexer::new(&args.host, &args.port, &args.collection, &embed_url, git_commit, git_branch)?;
-    indexer.index(&directory, args.batch_size)?;
+        let keep: HashSet<String> = new.iter().map(|chunk| chunk.id.clone()).collect();
+        store.delete_file_except("main", None, "src/a.rs", &keep).unwrap();
+        let records = store.records.lock().unwrap();
+        assert_eq!(records.len(), new.len() + other.len());
+        assert!(new.iter().chain(&other).all(|chunk| records.contains_key(&chunk.id)));
+    }
 
-    Ok(())
+    #[test]
+    fn huge_max_file_size_saturates() {
+        let filter = FileFilter::new(Vec::new(), &[]).unwrap().with_max_file_size(Some(u64::MAX));
+        assert_eq!(filter.max_file_size, u64::MAX);
+        assert_eq!(FileFilter::new(Vec::new(), &[]).unwrap().with_max_file_size(Some(2)).max_file_size, 2 * 1024 * 1024);
+    }
 }
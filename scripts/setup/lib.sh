@@ -55,7 +55,9 @@ wait_for_indexing() {
     done
     local exit_code=$(docker inspect vector-mcp-indexer --format='{{.State.ExitCode}}' 2>/dev/null || echo "1")
     echo ""
-    [ "$exit_code" = "0" ] && { print_success "Indexing complete!"; return 0; } || { print_error "Indexer failed with exit code: $exit_code"; return 1; }
+    [ "$exit_code" = "0" ] && { print_success "Indexing complete!"; return 0; }
+    [ "$exit_code" = "2" ] && { print_warning "Indexing completed, but some files or chunks failed"; return 0; }
+    print_error "Indexer failed with exit code: $exit_code"; return 1
 }
 
 cleanup_indexing_services() {